@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+/// Size of the Topic Alias property in a PUBLISH (identifier byte + u16)
+pub const ALIAS_PROPERTY_BYTES: u64 = 3;
+
+/// How a publish should use a topic alias
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasUse {
+    /// No alias available, send the full topic
+    None,
+    /// Send the full topic and bind it to this alias
+    Assign(u16),
+    /// The alias is already bound, send an empty topic
+    Reuse(u16),
+}
+
+/// Topic alias assignments of one connection, keyed by topic index
+///
+/// Aliases are only valid for the connection they were set on, so call
+/// [`TopicAliases::reset`] after every reconnect. Without churn the first
+/// `max` topics keep their alias for the whole connection and the rest are
+/// always sent in full. With churn, topics beyond `max` take over aliases in
+/// FIFO order, so every topic is aliased but aliases are rebound constantly
+/// when there are more topics than aliases.
+#[allow(dead_code)]
+pub struct TopicAliases {
+    max: u16,
+    churn: bool,
+    by_topic: HashMap<usize, u16>,
+    // Index = alias - 1, the topic currently bound to each alias
+    owners: Vec<usize>,
+    next_evict: usize,
+}
+
+#[allow(dead_code)]
+impl TopicAliases {
+    pub fn new(max: u16, churn: bool) -> Self {
+        TopicAliases {
+            max,
+            churn,
+            by_topic: HashMap::new(),
+            owners: Vec::with_capacity(max as usize),
+            next_evict: 0,
+        }
+    }
+
+    /// Forget all bindings and use at most `max` aliases (the broker's limit may differ per connection)
+    pub fn reset(&mut self, max: u16) {
+        self.max = max;
+        self.by_topic.clear();
+        self.owners.clear();
+        self.next_evict = 0;
+    }
+
+    pub fn lookup(&mut self, topic_index: usize) -> AliasUse {
+        if let Some(&alias) = self.by_topic.get(&topic_index) {
+            return AliasUse::Reuse(alias);
+        }
+        if self.owners.len() < self.max as usize {
+            self.owners.push(topic_index);
+            let alias = self.owners.len() as u16;
+            self.by_topic.insert(topic_index, alias);
+            return AliasUse::Assign(alias);
+        }
+        if !self.churn || self.max == 0 {
+            return AliasUse::None;
+        }
+
+        let slot = self.next_evict;
+        self.next_evict = (self.next_evict + 1) % self.owners.len();
+        self.by_topic.remove(&self.owners[slot]);
+        self.owners[slot] = topic_index;
+        let alias = slot as u16 + 1;
+        self.by_topic.insert(topic_index, alias);
+        AliasUse::Assign(alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_aliases_without_churn() {
+        let mut aliases = TopicAliases::new(2, false);
+        assert_eq!(aliases.lookup(0), AliasUse::Assign(1));
+        assert_eq!(aliases.lookup(1), AliasUse::Assign(2));
+        assert_eq!(aliases.lookup(2), AliasUse::None);
+        assert_eq!(aliases.lookup(0), AliasUse::Reuse(1));
+        assert_eq!(aliases.lookup(2), AliasUse::None);
+    }
+
+    #[test]
+    fn test_churn_rebinds_in_fifo_order() {
+        let mut aliases = TopicAliases::new(2, true);
+        aliases.lookup(0);
+        aliases.lookup(1);
+        assert_eq!(aliases.lookup(2), AliasUse::Assign(1));
+        assert_eq!(aliases.lookup(1), AliasUse::Reuse(2));
+        assert_eq!(aliases.lookup(0), AliasUse::Assign(2));
+        assert_eq!(aliases.lookup(2), AliasUse::Reuse(1));
+    }
+
+    #[test]
+    fn test_reset_forgets_bindings() {
+        let mut aliases = TopicAliases::new(2, false);
+        aliases.lookup(0);
+        aliases.reset(1);
+        assert_eq!(aliases.lookup(1), AliasUse::Assign(1));
+        assert_eq!(aliases.lookup(0), AliasUse::None);
+    }
+}
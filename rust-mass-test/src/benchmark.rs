@@ -0,0 +1,792 @@
+use crate::config::Config;
+use crate::metrics::{ClientMetrics, GlobalMetrics, LatencySnapshot};
+use crate::phases::PhaseReport;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Why a headless run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    Duration,
+    Messages,
+    Interrupted,
+    ClientExited,
+    /// A saturation search step violated the SLO
+    #[allow(dead_code)]
+    SloViolated,
+    /// A saturation search passed its maximum rate
+    #[allow(dead_code)]
+    MaxRate,
+    /// A connect storm got all clients connected
+    #[allow(dead_code)]
+    AllConnected,
+    /// A connect storm timed out before all clients were connected
+    #[allow(dead_code)]
+    ConnectTimeout,
+    /// A retained benchmark went through all of its steps
+    #[allow(dead_code)]
+    Completed,
+}
+
+/// Block until the configured duration or message count is reached, or Ctrl+C is pressed.
+///
+/// `count` picks the metric the message limit applies to (published for the
+/// publisher, received for the subscriber).
+pub async fn wait_for_stop(
+    config: &Config,
+    metrics: &Arc<Mutex<GlobalMetrics>>,
+    count: fn(&GlobalMetrics) -> u64,
+    all_exited: impl Fn() -> bool,
+) -> StopReason {
+    let deadline = config
+        .duration_secs
+        .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
+    let mut check_timer = tokio::time::interval(Duration::from_millis(100));
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return StopReason::Interrupted,
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => return StopReason::Duration,
+            _ = check_timer.tick() => {
+                if let Some(limit) = config.max_messages {
+                    if count(&metrics.lock().unwrap()) >= limit {
+                        return StopReason::Messages;
+                    }
+                }
+                if all_exited() {
+                    return StopReason::ClientExited;
+                }
+            }
+        }
+    }
+}
+
+/// Wait until every client is connected, returns how long that took or, on
+/// timeout, how many clients were connected
+pub async fn wait_for_connected(metrics: &Arc<Mutex<GlobalMetrics>>, timeout: Duration) -> Result<Duration, usize> {
+    let started = tokio::time::Instant::now();
+    loop {
+        let (connected, total) = {
+            let metrics = metrics.lock().unwrap();
+            (metrics.get_connected_count(), metrics.clients.len())
+        };
+        if connected >= total {
+            return Ok(started.elapsed());
+        }
+        if started.elapsed() >= timeout {
+            return Err(connected);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Track in the background how long it takes until every client is connected
+pub fn spawn_connect_watch(metrics: Arc<Mutex<GlobalMetrics>>) -> tokio::task::JoinHandle<Option<f64>> {
+    tokio::spawn(async move { wait_for_connected(&metrics, Duration::MAX).await.ok().map(|d| d.as_secs_f64()) })
+}
+
+/// Result of [`spawn_connect_watch`], `None` if not every client got connected
+pub async fn all_connected_secs(watch: tokio::task::JoinHandle<Option<f64>>) -> Option<f64> {
+    if !watch.is_finished() {
+        watch.abort();
+        return None;
+    }
+    watch.await.ok().flatten()
+}
+
+/// One line with connection progress and CONNACK latency for the final summary
+pub fn connect_summary(metrics: &GlobalMetrics, all_connected_secs: Option<f64>) -> String {
+    let connected = match all_connected_secs {
+        Some(secs) => format!("all {} clients after {:.2}s", metrics.clients.len(), secs),
+        None => format!("{}/{} clients", metrics.get_connected_count(), metrics.clients.len()),
+    };
+    format!("Connected: {} | CONNACK latency: {}", connected, metrics.get_connect_latency().format_ms())
+}
+
+#[derive(Debug, Serialize)]
+pub struct Totals {
+    pub published: u64,
+    pub received: u64,
+    pub publish_rate: f64,
+    pub receive_rate: f64,
+    /// Payload bytes, without MQTT framing and topics
+    pub published_bytes: u64,
+    pub received_bytes: u64,
+    pub lost: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub connection_errors: u64,
+    pub reconnects: u64,
+    pub publish_errors: u64,
+    /// Non-success reason codes received from the broker, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub reason_codes: BTreeMap<String, u64>,
+    /// Only present when topic aliases are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic_aliases: Option<AliasTotals>,
+    /// Only present when QoS 1 or 2 messages were exchanged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acks: Option<AckTotals>,
+}
+
+/// QoS 1 and 2 acknowledgements, received by producers and sent by subscribers
+#[derive(Debug, Serialize)]
+pub struct AckTotals {
+    pub pubacks: u64,
+    pub pubrecs: u64,
+    pub pubcomps: u64,
+    /// Most QoS 1/2 flows a single client had open at once
+    pub max_inflight: u64,
+}
+
+#[allow(dead_code)]
+impl AckTotals {
+    pub fn new(metrics: &GlobalMetrics) -> Option<Self> {
+        let acks = AckTotals {
+            pubacks: metrics.get_total_pubacks(),
+            pubrecs: metrics.get_total_pubrecs(),
+            pubcomps: metrics.get_total_pubcomps(),
+            max_inflight: metrics.get_max_inflight(),
+        };
+        (acks.pubacks + acks.pubrecs + acks.pubcomps > 0).then_some(acks)
+    }
+
+    pub fn summary_line(&self) -> String {
+        format!(
+            "PUBACK: {} | PUBREC: {} | PUBCOMP: {} | Max in-flight: {}",
+            self.pubacks, self.pubrecs, self.pubcomps, self.max_inflight
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AliasTotals {
+    /// Publishes sent by alias only
+    pub reuses: u64,
+    /// Publishes that bound a topic to an alias
+    pub assignments: u64,
+    /// Net PUBLISH bytes saved (omitted topics minus alias properties)
+    pub bytes_saved: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientReport {
+    pub id: usize,
+    pub published: u64,
+    pub received: u64,
+    pub publish_rate: f64,
+    pub receive_rate: f64,
+    pub published_bytes: u64,
+    pub received_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_us: Option<LatencySnapshot>,
+    pub lost: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub connection_errors: u64,
+    pub reconnects: u64,
+    pub publish_errors: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub reason_codes: BTreeMap<String, u64>,
+}
+
+impl ClientReport {
+    fn new(c: &ClientMetrics, elapsed: f64) -> Self {
+        let rate = |count: u64| if elapsed > 0.0 { count as f64 / elapsed } else { 0.0 };
+        ClientReport {
+            id: c.id + 1,
+            published: c.get_total_published(),
+            received: c.get_total_received(),
+            publish_rate: rate(c.get_total_published()),
+            receive_rate: rate(c.get_total_received()),
+            published_bytes: c.get_published_bytes(),
+            received_bytes: c.get_received_bytes(),
+            latency_us: non_empty(c.get_latency()),
+            lost: c.get_lost(),
+            duplicates: c.get_duplicates(),
+            out_of_order: c.get_out_of_order(),
+            connection_errors: c.get_connection_errors(),
+            reconnects: c.get_reconnects(),
+            publish_errors: c.get_publish_errors(),
+            reason_codes: c.get_reason_codes(),
+        }
+    }
+}
+
+/// Machine-readable summary of a headless run, rates are averages over the whole run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub tool: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub stop_reason: StopReason,
+    pub config: Config,
+    pub totals: Totals,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_us: Option<LatencySnapshot>,
+    /// Time from opening a connection to its CONNACK, reconnects included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_latency_us: Option<LatencySnapshot>,
+    /// Seconds until every client was connected, unset if that never happened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all_connected_secs: Option<f64>,
+    /// Keep-alive round trips, PINGREQ to PINGRESP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_rtt_us: Option<LatencySnapshot>,
+    /// Latency from the intended send time, only with a target rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corrected_latency_us: Option<LatencySnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate: Option<RateReport>,
+    /// One entry per load profile stage, filled in by the publisher
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub phases: Vec<PhaseReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search: Option<SearchReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle: Option<IdleReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery: Option<DeliveryReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retained: Option<RetainedReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared: Option<SharedReport>,
+    /// Agents of a distributed run, client ids are the global producer numbers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub agents: Vec<AgentReport>,
+    pub clients: Vec<ClientReport>,
+}
+
+/// One agent of a distributed run
+#[derive(Debug, Serialize)]
+pub struct AgentReport {
+    pub name: String,
+    pub first_producer: usize,
+    pub producers: usize,
+    /// Whether its final metrics, with histograms, arrived
+    pub finished: bool,
+}
+
+/// End-to-end delivery of a run with publishers and subscribers in one process
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryReport {
+    pub published: u64,
+    pub received: u64,
+    /// Subscribers expected to receive each published message
+    pub expected_fan_out: f64,
+    /// Messages received per message published
+    pub fan_out: f64,
+    /// Received messages against published × expected fan-out
+    pub delivery_ratio: f64,
+}
+
+#[allow(dead_code)]
+impl DeliveryReport {
+    pub fn new(published: u64, received: u64, expected_fan_out: f64) -> Self {
+        let expected = published as f64 * expected_fan_out;
+        DeliveryReport {
+            published,
+            received,
+            expected_fan_out,
+            fan_out: if published > 0 { received as f64 / published as f64 } else { 0.0 },
+            delivery_ratio: if expected > 0.0 { received as f64 / expected } else { 0.0 },
+        }
+    }
+
+    pub fn summary_line(&self) -> String {
+        format!(
+            "Delivery: {:.2}% | Fan-out: {:.2} (expected {:.2})",
+            self.delivery_ratio * 100.0,
+            self.fan_out,
+            self.expected_fan_out
+        )
+    }
+}
+
+/// Messages the broker queued for subscribers with a persistent session
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_secs: Option<u64>,
+    /// Delivered from the queue after resuming a session
+    pub queued_delivered: u64,
+    /// Missing from the queue, seen as sequence gaps
+    pub queued_lost: u64,
+    /// Queued messages the subscribers expected, delivered plus lost
+    pub expected_queued: u64,
+    pub queue_delivery_ratio: f64,
+    /// Reconnects where the broker had not kept the session
+    pub sessions_lost: u64,
+}
+
+#[allow(dead_code)]
+impl SessionReport {
+    /// Only for subscribers that keep their session
+    pub fn new(config: &Config, subscribers: &GlobalMetrics) -> Option<Self> {
+        if config.clean_session {
+            return None;
+        }
+        let queued_delivered = subscribers.get_total_queued_delivered();
+        let queued_lost = subscribers.get_total_queued_lost();
+        let expected_queued = queued_delivered + queued_lost;
+        Some(SessionReport {
+            offline_secs: config.offline_secs,
+            queued_delivered,
+            queued_lost,
+            expected_queued,
+            queue_delivery_ratio: if expected_queued > 0 { queued_delivered as f64 / expected_queued as f64 } else { 0.0 },
+            sessions_lost: subscribers.get_total_sessions_lost(),
+        })
+    }
+
+    pub fn summary_line(&self) -> String {
+        format!(
+            "Queued: {}/{} delivered ({:.2}%) | Sessions lost: {}",
+            self.queued_delivered,
+            self.expected_queued,
+            self.queue_delivery_ratio * 100.0,
+            self.sessions_lost
+        )
+    }
+}
+
+/// How the broker spread the messages over the members of each share group
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SharedReport {
+    pub filter: String,
+    /// Messages received by all groups, each group should get every matching message once
+    pub received: u64,
+    pub groups: Vec<ShareGroupReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShareGroupReport {
+    pub name: String,
+    pub received: u64,
+    pub members: Vec<ShareMemberReport>,
+    /// Busiest member against the group average, 1.0 is an even spread
+    pub imbalance_ratio: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShareMemberReport {
+    /// Subscriber number
+    pub id: usize,
+    pub received: u64,
+    /// Percent of the group's messages
+    pub share_pct: f64,
+}
+
+#[allow(dead_code)]
+impl SharedReport {
+    /// Only with shared subscriptions
+    pub fn new(config: &Config, subscribers: &GlobalMetrics) -> Option<Self> {
+        let shared = config.shared.as_ref()?;
+        let groups: Vec<ShareGroupReport> = (0..shared.groups.max(1))
+            .map(|group| {
+                let received: Vec<(usize, u64)> = subscribers
+                    .clients
+                    .iter()
+                    .filter(|c| shared.group(c.id) == group)
+                    .map(|c| (c.id + 1, c.get_total_received()))
+                    .collect();
+                let total: u64 = received.iter().map(|&(_, count)| count).sum();
+                let max = received.iter().map(|&(_, count)| count).max().unwrap_or(0);
+                let average = total as f64 / received.len().max(1) as f64;
+                ShareGroupReport {
+                    name: shared.group_name(group),
+                    received: total,
+                    members: received
+                        .into_iter()
+                        .map(|(id, count)| ShareMemberReport {
+                            id,
+                            received: count,
+                            share_pct: if total > 0 { count as f64 * 100.0 / total as f64 } else { 0.0 },
+                        })
+                        .collect(),
+                    imbalance_ratio: if average > 0.0 { max as f64 / average } else { 0.0 },
+                }
+            })
+            .collect();
+        Some(SharedReport {
+            filter: shared.filter.clone(),
+            received: groups.iter().map(|g| g.received).sum(),
+            groups,
+        })
+    }
+
+    /// Share of subscriber `id` (1-based) within its group
+    pub fn member(&self, id: usize) -> Option<(&ShareGroupReport, &ShareMemberReport)> {
+        self.groups
+            .iter()
+            .find_map(|group| group.members.iter().find(|m| m.id == id).map(|member| (group, member)))
+    }
+
+    pub fn summary_line(&self) -> String {
+        let worst = self.groups.iter().max_by(|a, b| a.imbalance_ratio.total_cmp(&b.imbalance_ratio));
+        format!(
+            "Shared: {} groups on {} | Received: {} | Worst imbalance: {}",
+            self.groups.len(),
+            self.filter,
+            self.received,
+            worst.map_or("n/a".to_string(), |g| format!("{:.2} ({})", g.imbalance_ratio, g.name))
+        )
+    }
+
+    /// One line per group with the members' shares
+    pub fn group_lines(&self) -> Vec<String> {
+        self.groups
+            .iter()
+            .map(|group| {
+                let shares: Vec<String> = group.members.iter().map(|m| format!("{}={:.1}%", m.id, m.share_pct)).collect();
+                format!(
+                    "  {}: {} received | imbalance {:.2} | {}",
+                    group.name,
+                    group.received,
+                    group.imbalance_ratio,
+                    shares.join(" ")
+                )
+            })
+            .collect()
+    }
+}
+
+/// Steps of a retained-message benchmark that ran
+#[derive(Debug, Default, Serialize)]
+pub struct RetainedReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load: Option<RetainedLoad>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reads: Vec<RetainedRead>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clear: Option<RetainedLoad>,
+}
+
+/// Loading (or clearing) the retained trees, done once every message is
+/// acknowledged, with QoS 0 once the client has written it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetainedLoad {
+    pub topics: u64,
+    pub secs: f64,
+    pub rate: f64,
+}
+
+#[allow(dead_code)]
+impl RetainedLoad {
+    pub fn new(topics: u64, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        RetainedLoad { topics, secs, rate: if secs > 0.0 { topics as f64 / secs } else { 0.0 } }
+    }
+}
+
+/// One wildcard read of retained messages, times are from sending the SUBSCRIBE
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetainedRead {
+    pub filter: String,
+    /// Loaded topics the filter matches
+    pub expected: u64,
+    pub received: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suback_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_ms: Option<f64>,
+}
+
+#[allow(dead_code)]
+impl RetainedRead {
+    pub fn complete(&self) -> bool {
+        self.received >= self.expected
+    }
+
+    pub fn summary_line(&self) -> String {
+        let ms = |value: Option<f64>| value.map_or("n/a".to_string(), |ms| format!("{:.1} ms", ms));
+        format!(
+            "{} {}: {}/{} messages | SUBACK {} | first {} | last {}",
+            if self.complete() { "✅" } else { "❌" },
+            self.filter,
+            self.received,
+            self.expected,
+            ms(self.suback_ms),
+            ms(self.first_ms),
+            ms(self.last_ms)
+        )
+    }
+}
+
+/// Memory use of the idle clients and, if given, the broker
+#[derive(Debug, Serialize)]
+pub struct IdleReport {
+    pub clients: usize,
+    pub connected: usize,
+    pub subscribed: bool,
+    pub keep_alive_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_bytes_per_connection: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broker_resident_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broker_bytes_per_connection: Option<f64>,
+}
+
+/// Outcome of a saturation search
+#[derive(Debug, Serialize)]
+pub struct SearchReport {
+    /// Highest step rate that met the SLO
+    pub max_sustainable_rate: Option<f64>,
+    pub steps: Vec<SearchStep>,
+}
+
+/// One step of a saturation search, as seen by the in-process subscribers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchStep {
+    /// Intended total rate
+    pub rate: f64,
+    pub published_rate: f64,
+    pub received_rate: f64,
+    /// p99 of the CO-corrected latency, if anything was received
+    pub p99_ms: Option<f64>,
+    pub loss_pct: f64,
+    /// Broken SLO conditions, empty when the step passed
+    pub violations: Vec<String>,
+}
+
+#[allow(dead_code)]
+impl SearchStep {
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    pub fn summary_line(&self) -> String {
+        format!(
+            "{} {:>10.2} msg/s: published {:>10.2} | received {:>10.2} | p99 {} | loss {:.3}%{}",
+            if self.passed() { "✅" } else { "❌" },
+            self.rate,
+            self.published_rate,
+            self.received_rate,
+            self.p99_ms.map_or("n/a".to_string(), |p99| format!("{:.2} ms", p99)),
+            self.loss_pct,
+            if self.passed() { String::new() } else { format!(" ({})", self.violations.join(", ")) }
+        )
+    }
+}
+
+/// Intended vs achieved publish rate of a run with a target rate
+#[derive(Debug, Serialize)]
+pub struct RateReport {
+    pub intended: f64,
+    pub achieved: f64,
+    pub schedule_lag_us: LatencySnapshot,
+}
+
+fn non_empty(latency: LatencySnapshot) -> Option<LatencySnapshot> {
+    if latency.count > 0 {
+        Some(latency)
+    } else {
+        None
+    }
+}
+
+impl Report {
+    pub fn new(
+        tool: &str,
+        config: &Config,
+        metrics: &GlobalMetrics,
+        started_at: DateTime<Utc>,
+        duration: Duration,
+        stop_reason: StopReason,
+    ) -> Self {
+        let elapsed = duration.as_secs_f64();
+        let rate = |count: u64| if elapsed > 0.0 { count as f64 / elapsed } else { 0.0 };
+
+        let clients = metrics.clients.iter().map(|c| ClientReport::new(c, elapsed)).collect();
+
+        Report {
+            tool: tool.to_string(),
+            started_at,
+            finished_at: started_at + chrono::Duration::from_std(duration).unwrap_or_default(),
+            duration_secs: elapsed,
+            stop_reason,
+            config: config.redacted(),
+            totals: Totals {
+                published: metrics.get_total_published(),
+                received: metrics.get_total_received(),
+                publish_rate: rate(metrics.get_total_published()),
+                receive_rate: rate(metrics.get_total_received()),
+                published_bytes: metrics.get_total_published_bytes(),
+                received_bytes: metrics.get_total_received_bytes(),
+                lost: metrics.get_total_lost(),
+                duplicates: metrics.get_total_duplicates(),
+                out_of_order: metrics.get_total_out_of_order(),
+                connection_errors: metrics.get_total_connection_errors(),
+                reconnects: metrics.get_total_reconnects(),
+                publish_errors: metrics.clients.iter().map(|c| c.get_publish_errors()).sum(),
+                reason_codes: metrics.get_reason_codes(),
+                topic_aliases: (config.topic_alias_max > 0).then(|| AliasTotals {
+                    reuses: metrics.get_total_alias_reuses(),
+                    assignments: metrics.get_total_alias_assignments(),
+                    bytes_saved: metrics.get_total_alias_bytes_saved(),
+                }),
+                acks: AckTotals::new(metrics),
+            },
+            latency_us: non_empty(metrics.get_latency()),
+            connect_latency_us: non_empty(metrics.get_connect_latency()),
+            all_connected_secs: None,
+            ping_rtt_us: non_empty(metrics.get_ping_rtt()),
+            corrected_latency_us: non_empty(metrics.get_corrected_latency()),
+            rate: config
+                .total_target_rate()
+                .filter(|_| metrics.get_schedule_lag().count > 0)
+                .map(|intended| RateReport {
+                    intended,
+                    achieved: rate(metrics.get_total_published()),
+                    schedule_lag_us: metrics.get_schedule_lag(),
+                }),
+            phases: Vec::new(),
+            search: None,
+            idle: None,
+            delivery: None,
+            session: None,
+            retained: None,
+            shared: None,
+            agents: Vec::new(),
+            clients,
+        }
+    }
+
+    /// Take the publishing side from `publishers` when both sides ran in one
+    /// process and the report was built from the subscribers
+    #[allow(dead_code)]
+    pub fn add_publishers(&mut self, config: &Config, publishers: &GlobalMetrics) {
+        let elapsed = self.duration_secs;
+        let rate = |count: u64| if elapsed > 0.0 { count as f64 / elapsed } else { 0.0 };
+
+        let totals = &mut self.totals;
+        totals.published = publishers.get_total_published();
+        totals.publish_rate = rate(totals.published);
+        totals.published_bytes = publishers.get_total_published_bytes();
+        totals.publish_errors = publishers.clients.iter().map(|c| c.get_publish_errors()).sum();
+        totals.connection_errors += publishers.get_total_connection_errors();
+        totals.reconnects += publishers.get_total_reconnects();
+        for (code, count) in publishers.get_reason_codes() {
+            *totals.reason_codes.entry(code).or_default() += count;
+        }
+        if let Some(publisher_acks) = AckTotals::new(publishers) {
+            let acks = totals.acks.get_or_insert(AckTotals { pubacks: 0, pubrecs: 0, pubcomps: 0, max_inflight: 0 });
+            acks.pubacks += publisher_acks.pubacks;
+            acks.pubrecs += publisher_acks.pubrecs;
+            acks.pubcomps += publisher_acks.pubcomps;
+            acks.max_inflight = acks.max_inflight.max(publisher_acks.max_inflight);
+        }
+
+        for (index, publisher) in publishers.clients.iter().enumerate() {
+            // Fan-in has more publishers than subscribers
+            let Some(client) = self.clients.get_mut(index) else {
+                self.clients.push(ClientReport::new(publisher, elapsed));
+                continue;
+            };
+            client.published = publisher.get_total_published();
+            client.publish_rate = rate(client.published);
+            client.published_bytes = publisher.get_published_bytes();
+            client.publish_errors = publisher.get_publish_errors();
+            client.connection_errors += publisher.get_connection_errors();
+            client.reconnects += publisher.get_reconnects();
+        }
+
+        self.rate = config
+            .total_target_rate()
+            .filter(|_| publishers.get_schedule_lag().count > 0)
+            .map(|intended| RateReport {
+                intended,
+                achieved: rate(publishers.get_total_published()),
+                schedule_lag_us: publishers.get_schedule_lag(),
+            });
+    }
+
+    /// Write the report as pretty JSON to `path`, or to stdout when `path` is "-"
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+        if path == "-" {
+            println!("{}", json);
+        } else {
+            fs::write(path, json)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SharedConfig;
+    use crate::sequence::QueueOutcome;
+
+    #[test]
+    fn test_delivery_report() {
+        let delivery = DeliveryReport::new(1000, 990, 1.0);
+        assert_eq!(delivery.fan_out, 0.99);
+        assert_eq!(delivery.delivery_ratio, 0.99);
+
+        // Subscribers on half of the topics should see half of the messages
+        let delivery = DeliveryReport::new(1000, 500, 0.5);
+        assert_eq!(delivery.fan_out, 0.5);
+        assert_eq!(delivery.delivery_ratio, 1.0);
+
+        let delivery = DeliveryReport::new(0, 0, 1.0);
+        assert_eq!((delivery.fan_out, delivery.delivery_ratio), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_session_report() {
+        let metrics = GlobalMetrics::new(2);
+        metrics.clients[0].record_queue(QueueOutcome { queued: true, lost: 0 });
+        metrics.clients[0].record_queue(QueueOutcome { queued: true, lost: 0 });
+        metrics.clients[1].record_queue(QueueOutcome { queued: true, lost: 0 });
+        metrics.clients[1].record_queue(QueueOutcome { queued: false, lost: 1 });
+        metrics.clients[1].increment_sessions_lost();
+
+        assert_eq!(SessionReport::new(&Config::default(), &metrics), None);
+        let config = Config { clean_session: false, offline_secs: Some(30), ..Config::default() };
+        let session = SessionReport::new(&config, &metrics).unwrap();
+        assert_eq!((session.queued_delivered, session.queued_lost, session.expected_queued), (3, 1, 4));
+        assert_eq!(session.queue_delivery_ratio, 0.75);
+        assert_eq!(session.sessions_lost, 1);
+    }
+
+    #[test]
+    fn test_shared_report() {
+        let metrics = GlobalMetrics::new(5);
+        for (client, received) in metrics.clients.iter().zip([300, 100, 100, 100, 0]) {
+            for _ in 0..received {
+                client.increment_received();
+            }
+        }
+
+        assert_eq!(SharedReport::new(&Config::default(), &metrics), None);
+        let config = Config { shared: Some(SharedConfig { groups: 2, ..SharedConfig::default() }), ..Config::default() };
+        let shared = SharedReport::new(&config, &metrics).unwrap();
+        assert_eq!(shared.received, 600);
+        // Subscribers 1, 3 and 5 in group1, 2 and 4 in group2
+        let group1 = &shared.groups[0];
+        assert_eq!(group1.received, 400);
+        assert_eq!(group1.members.iter().map(|m| m.id).collect::<Vec<_>>(), vec![1, 3, 5]);
+        assert_eq!(group1.members[0].share_pct, 75.0);
+        assert_eq!(group1.imbalance_ratio, 2.25);
+        assert_eq!(shared.groups[1].imbalance_ratio, 1.0);
+        assert_eq!(shared.member(4).map(|(group, member)| (group.name.as_str(), member.share_pct)), Some(("group2", 50.0)));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::config::Config;
+use crate::connection::{self, BoxError, ClientEvent};
+use rumqttc::QoS;
+use std::collections::BTreeSet;
+use std::fs;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// How long to wait for the broker to close the connection after DISCONNECT
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Subscribe to `filter` on the configured broker for `secs` seconds and write
+/// the distinct topics seen to `path`, sorted and one per line, ready for a
+/// `file` topic shape. Retained messages count, so a quiet broker still gives
+/// the topics that hold one.
+pub async fn run(config: &Config, path: &str, filter: &str, secs: u64) -> Result<(), BoxError> {
+    // A leftover session could hold subscriptions of an earlier capture
+    let config = Config { clean_session: true, ..config.clone() };
+    let mut options = connection::mqtt_options(&config, connection::client_id(&config, "capture", 1), 1)?;
+    options.set_max_packet_size(connection::MAX_PACKET_SIZE);
+    let (client, mut eventloop) = options.connect(10);
+
+    eprintln!("🎧 Capturing topics matching {} for {}s...", filter, secs);
+    let mut topics = BTreeSet::new();
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    loop {
+        match time::timeout_at(deadline, eventloop.poll()).await {
+            Err(_) => break,
+            Ok(Ok(ClientEvent::ConnAck { .. })) => client.subscribe(filter, QoS::AtMostOnce).await?,
+            Ok(Ok(ClientEvent::SubAck { rejected })) => {
+                if !rejected.is_empty() {
+                    return Err(format!("Subscription to {} rejected: {}", filter, rejected.join(", ")).into());
+                }
+            }
+            Ok(Ok(ClientEvent::Publish { topic, .. })) => {
+                topics.insert(topic);
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(format!("Connection lost while capturing {}: {}", filter, e).into()),
+        }
+    }
+
+    client.disconnect().await?;
+    let _ = time::timeout(DISCONNECT_TIMEOUT, async { while eventloop.poll().await.is_ok() {} }).await;
+
+    if topics.is_empty() {
+        return Err(format!("No messages on {} within {}s", filter, secs).into());
+    }
+    let lines: String = topics.iter().map(|topic| format!("{}\n", topic)).collect();
+    fs::write(path, lines)?;
+    eprintln!("✅ Wrote {} topics to {}", topics.len(), path);
+    Ok(())
+}
@@ -1,12 +1,24 @@
+use crate::payload::PayloadConfig;
+use crate::phases::Phase;
+use crate::selection::TopicSelection;
+use crate::topic::TopicShape;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
 
+/// Stands in for a password or credential path that must not be passed on
+const REDACTED: &str = "***";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub broker_host: String,
     pub broker_port: u16,
+    /// mqtt://, mqtts://, ws:// or wss:// URL, overrides broker_host/broker_port/use_tls
+    #[serde(default)]
+    pub broker_url: Option<String>,
     pub num_producers: usize,
+    /// Cap on the topics per producer, 0 (default) for the whole tree
+    #[serde(default)]
     pub num_topics: usize,
     pub topics_per_node: usize,
     pub max_depth: usize,
@@ -19,6 +31,338 @@ pub struct Config {
     pub use_leafs: bool,
     #[serde(default)]
     pub use_wildcard: bool,
+    /// Stop an auto-start run after this many seconds
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    /// Stop an auto-start run after this many messages (published or received)
+    #[serde(default)]
+    pub max_messages: Option<u64>,
+    /// Write a JSON report at the end of an auto-start run, "-" for stdout
+    #[serde(default)]
+    pub report_file: Option<String>,
+    /// Serve Prometheus metrics on this address, e.g. "0.0.0.0:9100"
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    #[serde(default)]
+    pub use_tls: bool,
+    /// PEM file with CA certificates, the platform trust store is used if unset
+    #[serde(default)]
+    pub tls_ca_file: Option<String>,
+    /// PEM client certificate and key for mutual TLS
+    #[serde(default)]
+    pub tls_client_cert: Option<String>,
+    #[serde(default)]
+    pub tls_client_key: Option<String>,
+    #[serde(default)]
+    pub tls_alpn: Vec<String>,
+    /// Sent as SNI and used to verify the server certificate instead of the wss:// URL's host
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+    /// Accept any server certificate (self-signed test brokers)
+    #[serde(default)]
+    pub tls_insecure: bool,
+    /// Username sent in CONNECT, "{id}" is replaced by the client number (e.g. "user-{id}")
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password sent in CONNECT, "{id}" is replaced by the client number
+    #[serde(default)]
+    pub password: Option<String>,
+    /// File with one password per line, client N uses line N (wrapping around)
+    #[serde(default)]
+    pub password_file: Option<String>,
+    /// MQTT protocol level: 4 (3.1.1) or 5
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u8,
+    /// MQTT 5 session expiry interval sent in CONNECT
+    #[serde(default)]
+    pub session_expiry_secs: Option<u32>,
+    /// QoS of the subscriptions, 0 if unset (1 with a persistent session)
+    #[serde(default)]
+    pub subscribe_qos: Option<i32>,
+    /// Percentages of subscriptions at QoS 0, 1 and 2, e.g. [50, 30, 20], replaces subscribe_qos
+    #[serde(default)]
+    pub subscribe_qos_mix: Option<[u8; 3]>,
+    /// Stable client ids, "{role}" becomes pub/sub/idle/storm and "{id}" the client number
+    #[serde(default)]
+    pub client_id_template: Option<String>,
+    /// Start with a clean session; false keeps subscriptions and queued messages across reconnects
+    #[serde(default = "default_clean_session")]
+    pub clean_session: bool,
+    /// Offline scenario: subscribers disconnect for this many seconds, then resume their session
+    #[serde(default)]
+    pub offline_secs: Option<u64>,
+    /// How long subscribers receive before going offline
+    #[serde(default = "default_offline_after_secs")]
+    pub offline_after_secs: u64,
+    /// MQTT 5 message expiry interval set on every publish
+    #[serde(default)]
+    pub message_expiry_secs: Option<u32>,
+    /// MQTT 5 user properties set on every publish, as [["key", "value"], ...]
+    #[serde(default)]
+    pub user_properties: Vec<(String, String)>,
+    /// MQTT 5 content type set on every publish
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// MQTT 5 topic aliases per producer, capped by the broker's maximum, 0 disables
+    #[serde(default)]
+    pub topic_alias_max: u16,
+    /// Rebind aliases when a producer has more topics than aliases
+    #[serde(default)]
+    pub topic_alias_churn: bool,
+    /// Payload generator, e.g. {"kind": "random", "size": {"type": "fixed", "bytes": 4096}}
+    #[serde(default)]
+    pub payload: PayloadConfig,
+    /// Messages per second across all producers (or per producer, see below), replaces sleep_ms
+    #[serde(default)]
+    pub target_rate: Option<f64>,
+    /// Apply target_rate to every producer instead of splitting it between them
+    #[serde(default)]
+    pub target_rate_per_producer: bool,
+    /// Load profile, e.g. [{"type": "ramp", "from": 0, "to": 5000, "secs": 60}], replaces target_rate
+    #[serde(default)]
+    pub phases: Vec<Phase>,
+    /// MQTT keep-alive interval, 0 disables PINGREQs (MQTT 3.1.1 only)
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// Idle mode: connect this many clients that only send keep-alives
+    #[serde(default)]
+    pub idle_clients: Option<usize>,
+    /// Let every idle client subscribe to its own topic
+    #[serde(default)]
+    pub idle_subscribe: bool,
+    /// PID of a broker on this host, its memory is reported per connection in idle mode
+    #[serde(default)]
+    pub broker_pid: Option<u32>,
+    /// Clients started per second, all at once if unset
+    #[serde(default)]
+    pub connect_rate: Option<f64>,
+    /// Connect-storm mode: connect this many clients, report CONNACK latency and exit
+    #[serde(default)]
+    pub connect_storm: Option<usize>,
+    /// How long to wait for all clients to connect
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Saturation search, replaces target_rate and phases
+    #[serde(default)]
+    pub search: Option<SearchConfig>,
+    /// Retained-message benchmark: load, read back and clear retained trees
+    #[serde(default)]
+    pub retained_bench: Option<RetainedConfig>,
+    /// Shared subscriptions: subscribers join `$share/<group>/<filter>` instead of their own tree
+    #[serde(default)]
+    pub shared: Option<SharedConfig>,
+    /// How many publishers and subscribers run and which trees the subscribers listen to
+    #[serde(default)]
+    pub topology: Topology,
+    /// Shape of the producers' topic trees, `num_topics` caps the topics per producer
+    #[serde(default)]
+    pub topic_shape: TopicShape,
+    /// How producers pick the topic of each message, round-robin by default
+    #[serde(default)]
+    pub topic_selection: TopicSelection,
+}
+
+/// Publisher/subscriber topology, `num_producers` is the size of the larger side
+///
+/// Client N always publishes to tree N, only the number of publishers and the
+/// trees a subscriber listens to change.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Topology {
+    /// Subscriber N on the tree of producer N
+    #[default]
+    OneToOne,
+    /// One producer, every subscriber on its tree
+    FanOut,
+    /// Every producer, one subscriber on the single wildcard of every tree
+    FanIn,
+    /// Subscriber N on the trees of producers N to N + overlap - 1, wrapping around,
+    /// so every message reaches `overlap` subscribers
+    ManyToMany { overlap: usize },
+}
+
+#[allow(dead_code)]
+impl Topology {
+    /// Trees (1-based producer numbers) subscriber `subscriber` (0-based) listens to
+    pub fn subscribed_trees(&self, subscriber: usize, num_producers: usize) -> Vec<usize> {
+        match self {
+            Topology::OneToOne => vec![subscriber + 1],
+            Topology::FanOut => vec![1],
+            Topology::FanIn => (1..=num_producers).collect(),
+            Topology::ManyToMany { overlap } => {
+                let producers = num_producers.max(1);
+                (0..(*overlap).clamp(1, producers)).map(|k| (subscriber + k) % producers + 1).collect()
+            }
+        }
+    }
+
+    /// Subscribers on the tree of each producer
+    pub fn subscribers_per_tree(&self, num_producers: usize) -> usize {
+        match self {
+            Topology::OneToOne | Topology::FanIn => 1,
+            Topology::FanOut => num_producers,
+            Topology::ManyToMany { overlap } => (*overlap).clamp(1, num_producers.max(1)),
+        }
+    }
+}
+
+/// Parse "one-to-one", "fan-out", "fan-in" or "many-to-many[:overlap]" from the command line
+#[allow(dead_code)]
+pub fn parse_topology(s: &str) -> Result<Topology, String> {
+    match s.split_once(':').unwrap_or((s, "")) {
+        ("one-to-one", "") => Ok(Topology::OneToOne),
+        ("fan-out", "") => Ok(Topology::FanOut),
+        ("fan-in", "") => Ok(Topology::FanIn),
+        ("many-to-many", overlap) => {
+            let overlap = if overlap.is_empty() { 2 } else { overlap.parse::<usize>().map_err(|e| format!("'{}': {}", overlap, e))? };
+            if overlap == 0 {
+                return Err("the many-to-many overlap must be at least 1".to_string());
+            }
+            Ok(Topology::ManyToMany { overlap })
+        }
+        _ => Err(format!("expected one-to-one, fan-out, fan-in or many-to-many[:overlap], got '{}'", s)),
+    }
+}
+
+/// Step the total publish rate up until the SLO is violated, see `search.rs`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchConfig {
+    /// Total rate of the first step, in messages per second
+    pub start_rate: f64,
+    /// Added to the rate after every passing step
+    pub step_rate: f64,
+    pub step_secs: u64,
+    /// Stop after this rate passes
+    pub max_rate: Option<f64>,
+    /// SLO: p99 of the CO-corrected latency seen by the subscribers
+    pub max_p99_ms: f64,
+    /// SLO: lost messages as a percentage of expected ones
+    pub max_loss_pct: f64,
+    /// SLO: achieved publish rate as a fraction of the intended rate
+    pub min_rate_ratio: f64,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            start_rate: 1000.0,
+            step_rate: 1000.0,
+            step_secs: 10,
+            max_rate: None,
+            max_p99_ms: 100.0,
+            max_loss_pct: 0.1,
+            min_rate_ratio: 0.95,
+        }
+    }
+}
+
+/// Load a retained message onto every topic of the producers' trees, time
+/// wildcard reads of them and clear them again, see `retained.rs`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetainedConfig {
+    /// Publish a retained message to every topic, one client per producer tree
+    pub load: bool,
+    /// Filters whose retained messages are read back and timed, e.g. "test00001/+/02/#"
+    pub read: Vec<String>,
+    /// Publish empty retained payloads to every topic, after loading and reading
+    pub clear: bool,
+    /// A read gives up after this long without a message
+    pub read_timeout_secs: u64,
+}
+
+impl Default for RetainedConfig {
+    fn default() -> Self {
+        RetainedConfig {
+            load: false,
+            read: Vec::new(),
+            clear: false,
+            read_timeout_secs: 10,
+        }
+    }
+}
+
+/// Subscribers split into share groups that all subscribe to the same filter,
+/// the broker hands every matching message to one member of each group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharedConfig {
+    /// Subscriber N joins group N modulo groups
+    pub groups: usize,
+    /// Groups are named "<group_prefix>1", "<group_prefix>2", ...
+    pub group_prefix: String,
+    /// Filter every group subscribes to, "+/#" covers all producer trees
+    pub filter: String,
+}
+
+impl Default for SharedConfig {
+    fn default() -> Self {
+        SharedConfig {
+            groups: 1,
+            group_prefix: "group".to_string(),
+            filter: "+/#".to_string(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl SharedConfig {
+    /// Group (0-based) of subscriber `member` (0-based)
+    pub fn group(&self, member: usize) -> usize {
+        member % self.groups.max(1)
+    }
+
+    pub fn group_name(&self, group: usize) -> String {
+        format!("{}{}", self.group_prefix, group + 1)
+    }
+
+    /// The `$share/<group>/<filter>` subscription of subscriber `member`
+    pub fn subscription(&self, member: usize) -> String {
+        format!("$share/{}/{}", self.group_name(self.group(member)), self.filter)
+    }
+}
+
+fn default_protocol_version() -> u8 {
+    4
+}
+
+fn default_clean_session() -> bool {
+    true
+}
+
+fn default_offline_after_secs() -> u64 {
+    10
+}
+
+fn default_keep_alive_secs() -> u64 {
+    120
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    30
+}
+
+/// Parse a "key=value" user property from the command line
+#[allow(dead_code)]
+pub fn parse_user_property(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got '{}'", s))
+}
+
+/// Parse a QoS mix like "50,30,20" (percent at QoS 0, 1 and 2) from the command line
+#[allow(dead_code)]
+pub fn parse_qos_mix(s: &str) -> Result<[u8; 3], String> {
+    let parts: Vec<u8> = s
+        .split(',')
+        .map(|part| part.trim().parse::<u8>().map_err(|e| format!("'{}': {}", part, e)))
+        .collect::<Result<_, _>>()?;
+    let mix: [u8; 3] = parts.try_into().map_err(|_| format!("expected three percentages for QoS 0,1,2, got '{}'", s))?;
+    if mix.iter().map(|&p| p as u32).sum::<u32>() != 100 {
+        return Err(format!("the QoS percentages must add up to 100, got '{}'", s));
+    }
+    Ok(mix)
 }
 
 impl Default for Config {
@@ -26,8 +370,9 @@ impl Default for Config {
         Config {
             broker_host: "localhost".to_string(),
             broker_port: 1883,
+            broker_url: None,
             num_producers: 10,
-            num_topics: 100,
+            num_topics: 0,
             topics_per_node: 10,
             max_depth: 3,
             sleep_ms: 100,
@@ -37,11 +382,56 @@ impl Default for Config {
             subscribe_percentage: 100,
             use_leafs: false,
             use_wildcard: false,
+            duration_secs: None,
+            max_messages: None,
+            report_file: None,
+            metrics_listen: None,
+            use_tls: false,
+            tls_ca_file: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            tls_alpn: Vec::new(),
+            tls_server_name: None,
+            tls_insecure: false,
+            username: None,
+            password: None,
+            password_file: None,
+            protocol_version: default_protocol_version(),
+            session_expiry_secs: None,
+            subscribe_qos: None,
+            subscribe_qos_mix: None,
+            client_id_template: None,
+            clean_session: default_clean_session(),
+            offline_secs: None,
+            offline_after_secs: default_offline_after_secs(),
+            message_expiry_secs: None,
+            user_properties: Vec::new(),
+            content_type: None,
+            topic_alias_max: 0,
+            topic_alias_churn: false,
+            payload: PayloadConfig::default(),
+            target_rate: None,
+            target_rate_per_producer: false,
+            phases: Vec::new(),
+            keep_alive_secs: default_keep_alive_secs(),
+            idle_clients: None,
+            idle_subscribe: false,
+            broker_pid: None,
+            connect_rate: None,
+            connect_storm: None,
+            connect_timeout_secs: default_connect_timeout_secs(),
+            search: None,
+            retained_bench: None,
+            shared: None,
+            topology: Topology::default(),
+            topic_shape: TopicShape::default(),
+            topic_selection: TopicSelection::default(),
         }
     }
 }
 
 impl Config {
+    #[allow(dead_code)]
     pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
         let json = serde_json::to_string_pretty(self)?;
         fs::write(path, json)?;
@@ -57,6 +447,83 @@ impl Config {
         Ok(config)
     }
 
+    /// Copy of the config that is safe to write into reports
+    #[allow(dead_code)]
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        if config.password.is_some() {
+            config.password = Some(REDACTED.to_string());
+        }
+        config
+    }
+
+    /// Copy of the config for distributed agents: the password, the password
+    /// file and the TLS client certificate and key are masked, agents fill them
+    /// in from their own configuration with [`Config::with_local_credentials`]
+    #[allow(dead_code)]
+    pub fn without_credentials(&self) -> Config {
+        let mut config = self.redacted();
+        for value in [&mut config.password_file, &mut config.tls_client_cert, &mut config.tls_client_key] {
+            if value.is_some() {
+                *value = Some(REDACTED.to_string());
+            }
+        }
+        config
+    }
+
+    /// Replace the credentials masked by [`Config::without_credentials`] with
+    /// the ones from `local`, which has to set every one that is masked
+    #[allow(dead_code)]
+    pub fn with_local_credentials(mut self, local: &Config) -> Result<Config, String> {
+        let credentials = [
+            ("password", &mut self.password, &local.password),
+            ("password_file", &mut self.password_file, &local.password_file),
+            ("tls_client_cert", &mut self.tls_client_cert, &local.tls_client_cert),
+            ("tls_client_key", &mut self.tls_client_key, &local.tls_client_key),
+        ];
+        for (name, value, local) in credentials {
+            if value.as_deref() == Some(REDACTED) {
+                let local = local.clone().ok_or_else(|| format!("The coordinator's configuration sets {}, the agent needs its own", name))?;
+                *value = Some(local);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Target rate summed over all producers
+    #[allow(dead_code)]
+    pub fn total_target_rate(&self) -> Option<f64> {
+        self.target_rate.map(|rate| rate * self.rate_scale())
+    }
+
+    /// Factor from configured rates (target_rate, phases) to the total rate
+    #[allow(dead_code)]
+    pub fn rate_scale(&self) -> f64 {
+        if self.target_rate_per_producer {
+            self.publishers() as f64
+        } else {
+            1.0
+        }
+    }
+
+    /// Producers to run, one for fan-out
+    #[allow(dead_code)]
+    pub fn publishers(&self) -> usize {
+        match self.topology {
+            Topology::FanOut => self.num_producers.min(1),
+            _ => self.num_producers,
+        }
+    }
+
+    /// Subscribers to run, one for fan-in
+    #[allow(dead_code)]
+    pub fn subscribers(&self) -> usize {
+        match self.topology {
+            Topology::FanIn => self.num_producers.min(1),
+            _ => self.num_producers,
+        }
+    }
+
     pub fn load_or_default(path: Option<&str>) -> Self {
         match path {
             Some(p) => Self::load(p).unwrap_or_default(),
@@ -64,3 +531,79 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_qos_mix() {
+        assert_eq!(parse_qos_mix("50,30,20"), Ok([50, 30, 20]));
+        assert_eq!(parse_qos_mix("0, 0, 100"), Ok([0, 0, 100]));
+        assert!(parse_qos_mix("50,50").is_err());
+        assert!(parse_qos_mix("50,30,30").is_err());
+        assert!(parse_qos_mix("a,b,c").is_err());
+    }
+
+    #[test]
+    fn test_parse_topology() {
+        assert_eq!(parse_topology("fan-out"), Ok(Topology::FanOut));
+        assert_eq!(parse_topology("many-to-many"), Ok(Topology::ManyToMany { overlap: 2 }));
+        assert_eq!(parse_topology("many-to-many:3"), Ok(Topology::ManyToMany { overlap: 3 }));
+        assert!(parse_topology("many-to-many:0").is_err());
+        assert!(parse_topology("fan-in:2").is_err());
+        assert!(parse_topology("star").is_err());
+    }
+
+    #[test]
+    fn test_topology_trees() {
+        assert_eq!(Topology::OneToOne.subscribed_trees(2, 4), vec![3]);
+        assert_eq!(Topology::FanOut.subscribed_trees(2, 4), vec![1]);
+        assert_eq!(Topology::FanIn.subscribed_trees(0, 3), vec![1, 2, 3]);
+        assert_eq!(Topology::ManyToMany { overlap: 2 }.subscribed_trees(3, 4), vec![4, 1]);
+        // More overlap than trees subscribes to each tree once
+        assert_eq!(Topology::ManyToMany { overlap: 9 }.subscribed_trees(1, 3), vec![2, 3, 1]);
+        assert_eq!(Topology::ManyToMany { overlap: 9 }.subscribers_per_tree(3), 3);
+
+        let config = Config { topology: Topology::FanOut, num_producers: 8, ..Config::default() };
+        assert_eq!((config.publishers(), config.subscribers()), (1, 8));
+        let config = Config { topology: Topology::FanIn, num_producers: 8, ..Config::default() };
+        assert_eq!((config.publishers(), config.subscribers()), (8, 1));
+    }
+
+    #[test]
+    fn test_agent_credentials() {
+        let coordinator = Config {
+            password: Some("secret".to_string()),
+            tls_client_key: Some("/etc/coordinator/client.key".to_string()),
+            tls_ca_file: Some("ca.pem".to_string()),
+            ..Config::default()
+        };
+        let sent = coordinator.without_credentials();
+        assert_eq!(sent.password.as_deref(), Some("***"));
+        assert_eq!(sent.tls_client_key.as_deref(), Some("***"));
+        assert_eq!(sent.tls_ca_file.as_deref(), Some("ca.pem"));
+        assert_eq!(sent.password_file, None);
+
+        let local = Config {
+            password: Some("agent-secret".to_string()),
+            tls_client_key: Some("agent.key".to_string()),
+            ..Config::default()
+        };
+        let config = sent.clone().with_local_credentials(&local).unwrap();
+        assert_eq!(config.password.as_deref(), Some("agent-secret"));
+        assert_eq!(config.tls_client_key.as_deref(), Some("agent.key"));
+        assert!(sent.with_local_credentials(&Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_shared_subscription() {
+        let shared = SharedConfig { groups: 2, ..SharedConfig::default() };
+        assert_eq!(shared.subscription(0), "$share/group1/+/#");
+        assert_eq!(shared.subscription(1), "$share/group2/+/#");
+        assert_eq!(shared.subscription(2), "$share/group1/+/#");
+
+        let shared = SharedConfig { groups: 0, filter: "test00001/#".to_string(), ..SharedConfig::default() };
+        assert_eq!(shared.subscription(3), "$share/group1/test00001/#");
+    }
+}
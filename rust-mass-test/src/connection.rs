@@ -0,0 +1,845 @@
+use crate::config::Config;
+use rumqttc::tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rumqttc::tokio_rustls::rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+};
+use rumqttc::tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rumqttc::tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore, SignatureScheme,
+};
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::{PubAckReason, PubRecReason, PublishProperties};
+use rumqttc::v5::{self, StateError};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS, SubscribeReasonCode, TlsConfiguration, Transport};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Largest packet the MQTT remaining-length encoding allows (256 MB)
+#[allow(dead_code)]
+pub const MAX_PACKET_SIZE: usize = 268_435_455;
+
+/// TLS client configurations by [`tls_key`], built once and shared by every client
+static TLS_CONFIGS: OnceLock<Mutex<HashMap<String, Arc<ClientConfig>>>> = OnceLock::new();
+
+/// Non-empty lines of each password file, read on first use
+static PASSWORD_FILES: OnceLock<Mutex<HashMap<String, Arc<Vec<String>>>>> = OnceLock::new();
+
+/// Connection options for either protocol version, see [`mqtt_options`]
+#[derive(Clone)]
+pub enum ConnectOptions {
+    V3(MqttOptions),
+    V5 {
+        options: Box<v5::MqttOptions>,
+        /// Attached to every publish (message expiry, user properties, content type)
+        publish_properties: PublishProperties,
+    },
+}
+
+impl ConnectOptions {
+    /// Largest packet size in bytes, for both directions
+    pub fn set_max_packet_size(&mut self, size: usize) {
+        match self {
+            ConnectOptions::V3(options) => {
+                options.set_max_packet_size(size, size);
+            }
+            ConnectOptions::V5 { options, .. } => {
+                options.set_max_packet_size(Some(size as u32));
+            }
+        }
+    }
+
+    /// Maximum number of outgoing QoS 1/2 publishes awaiting acknowledgement
+    #[allow(dead_code)]
+    pub fn set_inflight(&mut self, inflight: u16) {
+        match self {
+            ConnectOptions::V3(options) => {
+                options.set_inflight(inflight);
+            }
+            ConnectOptions::V5 { options, .. } => {
+                options.set_outgoing_inflight_upper_limit(inflight);
+            }
+        }
+    }
+
+    /// Create a client and its event loop, nothing is sent until the event loop is polled
+    pub fn connect(&self, cap: usize) -> (MqttClient, MqttEventLoop) {
+        match self {
+            ConnectOptions::V3(options) => {
+                let (client, eventloop) = AsyncClient::new(options.clone(), cap);
+                (MqttClient::V3(client), MqttEventLoop::V3(Box::new(eventloop)))
+            }
+            ConnectOptions::V5 { options, publish_properties } => {
+                let (client, eventloop) = v5::AsyncClient::new(options.as_ref().clone(), cap);
+                let client = MqttClient::V5 { client, publish_properties: publish_properties.clone() };
+                (client, MqttEventLoop::V5(Box::new(eventloop)))
+            }
+        }
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// Client handle for either protocol version
+#[allow(dead_code)]
+pub enum MqttClient {
+    V3(AsyncClient),
+    V5 {
+        client: v5::AsyncClient,
+        publish_properties: PublishProperties,
+    },
+}
+
+impl MqttClient {
+    #[allow(dead_code)]
+    /// Publish a message, `topic_alias` is only sent with MQTT 5 (pass an empty
+    /// topic to publish by alias only)
+    pub async fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Bytes,
+        topic_alias: Option<u16>,
+    ) -> Result<(), BoxError> {
+        match self {
+            MqttClient::V3(client) => client.publish(topic, qos, retain, payload).await?,
+            MqttClient::V5 { client, publish_properties } => {
+                let mut properties = publish_properties.clone();
+                properties.topic_alias = topic_alias;
+                client
+                    .publish_with_properties(topic, v5_qos(qos), retain, payload, properties)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), BoxError> {
+        match self {
+            MqttClient::V3(client) => client.subscribe(filter, qos).await?,
+            MqttClient::V5 { client, .. } => client.subscribe(filter, v5_qos(qos)).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<(), BoxError> {
+        match self {
+            MqttClient::V3(client) => client.disconnect().await?,
+            MqttClient::V5 { client, .. } => client.disconnect().await?,
+        }
+        Ok(())
+    }
+}
+
+/// The parts of an event loop notification producers and subscribers act on
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// `topic_alias_max` is the broker's limit for our publishes, 0 with MQTT 3.1.1
+    ConnAck { session_present: bool, topic_alias_max: u16 },
+    /// Reason codes of the rejected filters, empty when all were granted
+    SubAck { rejected: Vec<String> },
+    Publish { topic: String, payload: Bytes },
+    /// `reason` is set for MQTT 5 acknowledgements with a reason code other than Success
+    PubAck { reason: Option<String> },
+    PubRec { reason: Option<String> },
+    PubComp,
+    /// A QoS 1 or 2 PUBLISH was written to the socket, retransmissions included
+    PublishSent,
+    /// Acknowledgements we wrote for received QoS 1 and 2 messages
+    PubAckSent,
+    PubRecSent,
+    PubCompSent,
+    /// A keep-alive PINGREQ was written to the socket
+    PingSent,
+    PingResp,
+    Disconnect,
+    Other,
+}
+
+fn reason_name<T: fmt::Debug + PartialEq>(reason: T, success: T) -> Option<String> {
+    if reason == success {
+        None
+    } else {
+        Some(format!("{:?}", reason))
+    }
+}
+
+impl From<Event> for ClientEvent {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::Incoming(Packet::ConnAck(ack)) => ClientEvent::ConnAck { session_present: ack.session_present, topic_alias_max: 0 },
+            Event::Incoming(Packet::SubAck(ack)) => ClientEvent::SubAck {
+                rejected: ack
+                    .return_codes
+                    .iter()
+                    .filter(|code| matches!(code, SubscribeReasonCode::Failure))
+                    .map(|code| format!("{:?}", code))
+                    .collect(),
+            },
+            Event::Incoming(Packet::Publish(p)) => ClientEvent::Publish { topic: p.topic, payload: p.payload },
+            Event::Incoming(Packet::PubAck(_)) => ClientEvent::PubAck { reason: None },
+            Event::Incoming(Packet::PubRec(_)) => ClientEvent::PubRec { reason: None },
+            Event::Incoming(Packet::PubComp(_)) => ClientEvent::PubComp,
+            Event::Outgoing(outgoing) if is_ack_flow(&outgoing) => ClientEvent::from(outgoing),
+            Event::Incoming(Packet::PingResp) => ClientEvent::PingResp,
+            Event::Outgoing(Outgoing::PingReq) => ClientEvent::PingSent,
+            Event::Incoming(Packet::Disconnect) => ClientEvent::Disconnect,
+            _ => ClientEvent::Other,
+        }
+    }
+}
+
+/// Outgoing packets of the QoS 1 and 2 flows, QoS 0 publishes have packet id 0
+fn is_ack_flow(outgoing: &Outgoing) -> bool {
+    matches!(outgoing, Outgoing::Publish(pkid) if *pkid != 0)
+        || matches!(outgoing, Outgoing::PubAck(_) | Outgoing::PubRec(_) | Outgoing::PubComp(_))
+}
+
+impl From<Outgoing> for ClientEvent {
+    fn from(outgoing: Outgoing) -> Self {
+        match outgoing {
+            Outgoing::Publish(_) => ClientEvent::PublishSent,
+            Outgoing::PubAck(_) => ClientEvent::PubAckSent,
+            Outgoing::PubRec(_) => ClientEvent::PubRecSent,
+            Outgoing::PubComp(_) => ClientEvent::PubCompSent,
+            _ => ClientEvent::Other,
+        }
+    }
+}
+
+impl From<v5::Event> for ClientEvent {
+    fn from(event: v5::Event) -> Self {
+        use v5::mqttbytes::v5::{Packet as V5Packet, SubscribeReasonCode as V5SubscribeReasonCode};
+
+        match event {
+            v5::Event::Incoming(V5Packet::ConnAck(ack)) => ClientEvent::ConnAck {
+                session_present: ack.session_present,
+                topic_alias_max: ack.properties.and_then(|p| p.topic_alias_max).unwrap_or(0),
+            },
+            v5::Event::Incoming(V5Packet::SubAck(ack)) => ClientEvent::SubAck {
+                rejected: ack
+                    .return_codes
+                    .iter()
+                    .filter(|code| !matches!(code, V5SubscribeReasonCode::Success(_)))
+                    .map(|code| format!("{:?}", code))
+                    .collect(),
+            },
+            v5::Event::Incoming(V5Packet::Publish(p)) => ClientEvent::Publish {
+                topic: String::from_utf8_lossy(&p.topic).into_owned(),
+                payload: p.payload,
+            },
+            v5::Event::Incoming(V5Packet::PubAck(ack)) => ClientEvent::PubAck { reason: reason_name(ack.reason, PubAckReason::Success) },
+            v5::Event::Incoming(V5Packet::PubRec(rec)) => ClientEvent::PubRec { reason: reason_name(rec.reason, PubRecReason::Success) },
+            v5::Event::Incoming(V5Packet::PubComp(_)) => ClientEvent::PubComp,
+            v5::Event::Outgoing(outgoing) if is_ack_flow(&outgoing) => ClientEvent::from(outgoing),
+            v5::Event::Incoming(V5Packet::PingResp(_)) => ClientEvent::PingResp,
+            v5::Event::Outgoing(Outgoing::PingReq) => ClientEvent::PingSent,
+            v5::Event::Incoming(V5Packet::Disconnect(_)) => ClientEvent::Disconnect,
+            _ => ClientEvent::Other,
+        }
+    }
+}
+
+/// Event loop for either protocol version
+pub enum MqttEventLoop {
+    V3(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl MqttEventLoop {
+    pub async fn poll(&mut self) -> Result<ClientEvent, ConnectionError> {
+        match self {
+            MqttEventLoop::V3(eventloop) => eventloop.poll().await.map(ClientEvent::from).map_err(ConnectionError::V3),
+            MqttEventLoop::V5(eventloop) => eventloop.poll().await.map(ClientEvent::from).map_err(ConnectionError::V5),
+        }
+    }
+}
+
+/// Event loop error for either protocol version
+pub enum ConnectionError {
+    V3(rumqttc::ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+impl ConnectionError {
+    /// Name of the reason code the broker sent, if this error was caused by one
+    ///
+    /// MQTT 5 reports refused connections, rejected subscriptions, failed
+    /// acknowledgements and server DISCONNECTs this way, MQTT 3.1.1 only
+    /// refused connections.
+    pub fn reason_code(&self) -> Option<String> {
+        match self {
+            ConnectionError::V3(rumqttc::ConnectionError::ConnectionRefused(code)) => Some(format!("{:?}", code)),
+            ConnectionError::V3(_) => None,
+            ConnectionError::V5(v5::ConnectionError::ConnectionRefused(code)) => Some(format!("{:?}", code)),
+            ConnectionError::V5(v5::ConnectionError::MqttState(state)) => match state {
+                StateError::ServerDisconnect { reason_code, .. } => Some(format!("{:?}", reason_code)),
+                StateError::SubFail { reason } => Some(format!("{:?}", reason)),
+                StateError::UnsubFail { reason } => Some(format!("{:?}", reason)),
+                StateError::PubAckFail { reason } => Some(format!("{:?}", reason)),
+                StateError::PubRecFail { reason } => Some(format!("{:?}", reason)),
+                StateError::PubRelFail { reason } => Some(format!("{:?}", reason)),
+                StateError::PubCompFail { reason } => Some(format!("{:?}", reason)),
+                StateError::ConnFail { reason } => Some(format!("{:?}", reason)),
+                _ => None,
+            },
+            ConnectionError::V5(_) => None,
+        }
+    }
+}
+
+// Debug and Display forward to the wrapped error so logs read the same for both versions
+impl fmt::Debug for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::V3(e) => fmt::Debug::fmt(e, f),
+            ConnectionError::V5(e) => fmt::Debug::fmt(e, f),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::V3(e) => fmt::Display::fmt(e, f),
+            ConnectionError::V5(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+/// Broker address parsed from a `mqtt://`, `mqtts://`, `ws://` or `wss://` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerUrl {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    /// Only used by WebSocket URLs, "/" if the URL has none
+    pub path: String,
+}
+
+impl BrokerUrl {
+    pub fn parse(url: &str) -> Result<BrokerUrl, BoxError> {
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| format!("Missing scheme in broker URL {}", url))?;
+        let scheme = scheme.to_ascii_lowercase();
+        let default_port = match scheme.as_str() {
+            "mqtt" | "tcp" => 1883,
+            "mqtts" | "ssl" => 8883,
+            "ws" => 80,
+            "wss" => 443,
+            other => return Err(format!("Unsupported broker URL scheme {}, use mqtt, mqtts, ws or wss", other).into()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // IPv6 literals are bracketed, the port follows the closing bracket
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| format!("Invalid port in broker URL {}", url))?)
+            }
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("Missing host in broker URL {}", url).into());
+        }
+        Ok(BrokerUrl { scheme, host: host.to_string(), port, path: path.to_string() })
+    }
+
+    fn uses_tls(&self) -> bool {
+        matches!(self.scheme.as_str(), "mqtts" | "ssl" | "wss")
+    }
+
+    fn is_websocket(&self) -> bool {
+        matches!(self.scheme.as_str(), "ws" | "wss")
+    }
+
+    /// `host:port` with `host` in place of the URL's, IPv6 addresses bracketed
+    fn authority(&self, host: &str) -> String {
+        if host.contains(':') {
+            format!("[{}]:{}", host, self.port)
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+
+    /// The WebSocket URL rumqttc connects to, with `host` in place of the URL's
+    fn websocket_address(&self, host: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.authority(host), self.path)
+    }
+}
+
+/// Where to connect: `broker_url` if set, otherwise `broker_host`/`broker_port`/`use_tls`
+pub fn broker_url(config: &Config) -> Result<BrokerUrl, BoxError> {
+    match &config.broker_url {
+        Some(url) => BrokerUrl::parse(url),
+        None => Ok(BrokerUrl {
+            scheme: if config.use_tls { "mqtts" } else { "mqtt" }.to_string(),
+            host: config.broker_host.clone(),
+            port: config.broker_port,
+            path: "/".to_string(),
+        }),
+    }
+}
+
+/// How long client `index` (0-based) waits before its first connect so that
+/// clients start at `connect_rate` per second
+pub fn connect_delay(config: &Config, index: usize) -> Duration {
+    match config.connect_rate {
+        Some(rate) if rate > 0.0 => Duration::from_secs_f64(index as f64 / rate),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Replace `{id}` in a per-client template with the 1-based client number
+pub fn expand_template(template: &str, client_number: usize) -> String {
+    template.replace("{id}", &client_number.to_string())
+}
+
+/// Client id for the 1-based `client_number` in `role` (pub, sub, ...): the
+/// expanded `client_id_template` when set, otherwise random per process
+pub fn client_id(config: &Config, role: &str, client_number: usize) -> String {
+    match &config.client_id_template {
+        Some(template) => expand_template(&template.replace("{role}", role), client_number),
+        None => format!("{}-{}", role, Uuid::new_v4()),
+    }
+}
+
+/// Password for one client: the password file wins over the plain password.
+/// The file is read once, later clients index into the cached lines.
+fn client_password(config: &Config, client_number: usize) -> Result<Option<String>, BoxError> {
+    if let Some(path) = &config.password_file {
+        let lines = cached(&PASSWORD_FILES, path.clone(), || {
+            let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            let lines: Vec<String> =
+                contents.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string).collect();
+            if lines.is_empty() {
+                return Err(format!("No passwords found in {}", path).into());
+            }
+            Ok(Arc::new(lines))
+        })?;
+        let line = &lines[(client_number.max(1) - 1) % lines.len()];
+        return Ok(Some(expand_template(line, client_number)));
+    }
+    Ok(config.password.as_deref().map(|p| expand_template(p, client_number)))
+}
+
+/// Build the connection options shared by producers and subscribers.
+///
+/// The transport follows [`broker_url`], TLS settings apply to `mqtts://` and
+/// `wss://`, `tls_server_name` only to `wss://`.
+///
+/// `client_number` is the 1-based client number used in credential templates.
+/// Certificate and password files are read once per process. Call it once
+/// per client and clone the result for reconnects.
+pub fn mqtt_options(config: &Config, client_id: String, client_number: usize) -> Result<ConnectOptions, BoxError> {
+    let keep_alive = Duration::from_secs(config.keep_alive_secs);
+    let password = client_password(config, client_number)?;
+    let credentials = match &config.username {
+        Some(username) => Some((expand_template(username, client_number), password.unwrap_or_default())),
+        None if password.is_some() => return Err("A password requires a username".into()),
+        None => None,
+    };
+    let url = broker_url(config)?;
+    // Over WebSockets rumqttc dials the host of the URL but takes the TLS server
+    // name from the upgrade request, which `ws_request_host` rewrites
+    let mut ws_request_host = None;
+    let (host, port, transport) = match (url.is_websocket(), url.uses_tls()) {
+        (true, false) => (url.websocket_address(&url.host), url.port, Some(Transport::Ws)),
+        (true, true) => {
+            let tls = rustls_config(config)?;
+            if let Some(name) = &config.tls_server_name {
+                ServerName::try_from(name.clone()).map_err(|e| format!("Invalid tls_server_name {}: {}", name, e))?;
+                ws_request_host = Some((url.websocket_address(name), url.authority(name)));
+            }
+            (url.websocket_address(&url.host), url.port, Some(Transport::Wss(TlsConfiguration::Rustls(tls))))
+        }
+        (false, true) => {
+            let tls = rustls_config(config)?;
+            // rumqttc sends the host it dials as SNI and has no way to dial another one
+            if config.tls_server_name.is_some() {
+                return Err(format!(
+                    "tls_server_name needs a wss:// broker URL, over {}:// connect to the server name itself",
+                    url.scheme
+                )
+                .into());
+            }
+            (url.host.clone(), url.port, Some(Transport::Tls(TlsConfiguration::Rustls(tls))))
+        }
+        (false, false) => (url.host.clone(), url.port, None),
+    };
+
+    // rumqttc panics on keep-alives it does not support
+    match (config.protocol_version, config.keep_alive_secs) {
+        (5, secs) if secs < 5 => return Err("MQTT 5 keep_alive_secs must be at least 5".into()),
+        (_, secs) if secs > u16::MAX as u64 => return Err(format!("keep_alive_secs must be at most {}", u16::MAX).into()),
+        _ => {}
+    }
+
+    if client_id.is_empty() && !config.clean_session {
+        return Err("A persistent session needs a non-empty client id".into());
+    }
+    if config.protocol_version == 5 && !config.clean_session && config.session_expiry_secs.unwrap_or(0) == 0 {
+        return Err("MQTT 5 brokers drop the session on disconnect unless session_expiry_secs is set".into());
+    }
+
+    match config.protocol_version {
+        4 => {
+            let mut options = MqttOptions::new(client_id, host.clone(), port);
+            options.set_keep_alive(keep_alive);
+            options.set_clean_session(config.clean_session);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            if let Some((uri, authority)) = ws_request_host.clone() {
+                options.set_request_modifier(move |mut request| {
+                    if let (Ok(uri), Ok(authority)) = (uri.parse(), authority.parse()) {
+                        *request.uri_mut() = uri;
+                        request.headers_mut().insert("Host", authority);
+                    }
+                    async move { request }
+                });
+            }
+            Ok(ConnectOptions::V3(options))
+        }
+        5 => {
+            let mut options = v5::MqttOptions::new(client_id, host.clone(), port);
+            options.set_keep_alive(keep_alive);
+            options.set_clean_start(config.clean_session);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            if let Some((uri, authority)) = ws_request_host.clone() {
+                options.set_request_modifier(move |mut request| {
+                    if let (Ok(uri), Ok(authority)) = (uri.parse(), authority.parse()) {
+                        *request.uri_mut() = uri;
+                        request.headers_mut().insert("Host", authority);
+                    }
+                    async move { request }
+                });
+            }
+            if let Some(expiry) = config.session_expiry_secs {
+                let mut properties = options.connect_properties().unwrap_or_default();
+                properties.session_expiry_interval = Some(expiry);
+                options.set_connect_properties(properties);
+            }
+            let publish_properties = PublishProperties {
+                message_expiry_interval: config.message_expiry_secs,
+                user_properties: config.user_properties.clone(),
+                content_type: config.content_type.clone(),
+                ..PublishProperties::default()
+            };
+            Ok(ConnectOptions::V5 { options: Box::new(options), publish_properties })
+        }
+        other => Err(format!("Unsupported protocol_version {}, use 4 (MQTT 3.1.1) or 5", other).into()),
+    }
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, BoxError> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("{}: {}", path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path).into());
+    }
+    Ok(certs)
+}
+
+/// Looks `key` up in a process-wide cache and only runs `load` on a miss
+fn cached<V: Clone>(
+    cache: &OnceLock<Mutex<HashMap<String, V>>>,
+    key: String,
+    load: impl FnOnce() -> Result<V, BoxError>,
+) -> Result<V, BoxError> {
+    let mut cache = cache.get_or_init(Default::default).lock().unwrap();
+    if let Some(value) = cache.get(&key) {
+        return Ok(value.clone());
+    }
+    let value = load()?;
+    cache.insert(key, value.clone());
+    Ok(value)
+}
+
+/// The settings a TLS client configuration is built from
+fn tls_key(config: &Config) -> String {
+    format!(
+        "{:?}",
+        (&config.tls_ca_file, &config.tls_client_cert, &config.tls_client_key, &config.tls_alpn, config.tls_insecure)
+    )
+}
+
+/// The TLS client configuration, read from the certificate files and the
+/// platform trust store on first use and shared by all clients afterwards
+fn rustls_config(config: &Config) -> Result<Arc<ClientConfig>, BoxError> {
+    cached(&TLS_CONFIGS, tls_key(config), || Ok(Arc::new(build_rustls_config(config)?)))
+}
+
+fn build_rustls_config(config: &Config) -> Result<ClientConfig, BoxError> {
+    let mut roots = RootCertStore::empty();
+    match &config.tls_ca_file {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                roots.add(cert)?;
+            }
+        }
+        None => {
+            // Unparsable platform certificates are skipped, like rumqttc does
+            for cert in rustls_native_certs::load_native_certs()? {
+                let _ = roots.add(cert);
+            }
+        }
+    }
+
+    let algorithms = ring::default_provider().signature_verification_algorithms;
+    let builder = if config.tls_insecure {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(InsecureVerifier { algorithms }))
+    } else {
+        ClientConfig::builder().with_root_certificates(roots)
+    };
+
+    let mut tls = match (&config.tls_client_cert, &config.tls_client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = read_certs(cert_path)?;
+            let mut reader = BufReader::new(File::open(key_path).map_err(|e| format!("{}: {}", key_path, e))?);
+            let key = rustls_pemfile::private_key(&mut reader)?
+                .ok_or_else(|| format!("No private key found in {}", key_path))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("tls_client_cert and tls_client_key must be set together".into()),
+    };
+
+    tls.alpn_protocols = config.tls_alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(tls)
+}
+
+/// Accepts any server certificate. Handshake signatures are still checked so
+/// the cost of the handshake stays comparable to a verified one.
+#[derive(Debug)]
+struct InsecureVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_delay() {
+        let mut config = Config::default();
+        assert_eq!(connect_delay(&config, 5), Duration::ZERO);
+        config.connect_rate = Some(100.0);
+        assert_eq!(connect_delay(&config, 0), Duration::ZERO);
+        assert_eq!(connect_delay(&config, 50), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_expand_template() {
+        assert_eq!(expand_template("user-{id}", 7), "user-7");
+        assert_eq!(expand_template("shared", 7), "shared");
+    }
+
+    #[test]
+    fn test_client_id_template() {
+        let mut config = Config::default();
+        assert!(client_id(&config, "sub", 3).starts_with("sub-"));
+        assert_ne!(client_id(&config, "sub", 3), client_id(&config, "sub", 3));
+        config.client_id_template = Some("load-{role}-{id}".to_string());
+        assert_eq!(client_id(&config, "sub", 3), "load-sub-3");
+    }
+
+    #[test]
+    fn test_persistent_session() {
+        let config = Config { clean_session: false, ..Config::default() };
+        match mqtt_options(&config, "sub-1".to_string(), 1).unwrap() {
+            ConnectOptions::V3(options) => assert!(!options.clean_session()),
+            ConnectOptions::V5 { .. } => panic!("expected MQTT 3.1.1 options"),
+        }
+        assert!(mqtt_options(&config, String::new(), 1).is_err());
+
+        // Without an expiry an MQTT 5 session ends with the connection
+        let v5 = Config { protocol_version: 5, ..config };
+        assert!(mqtt_options(&v5, "sub-1".to_string(), 1).is_err());
+        let v5 = Config { session_expiry_secs: Some(600), ..v5 };
+        match mqtt_options(&v5, "sub-1".to_string(), 1).unwrap() {
+            ConnectOptions::V5 { options, .. } => assert!(!options.clean_start()),
+            ConnectOptions::V3(_) => panic!("expected MQTT 5 options"),
+        }
+    }
+
+    #[test]
+    fn test_parse_broker_url() {
+        let url = BrokerUrl::parse("mqtts://broker.example.com").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.uses_tls()), ("broker.example.com", 8883, true));
+
+        let url = BrokerUrl::parse("ws://proxy:8080/mqtt").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("proxy", 8080, "/mqtt"));
+        assert!(url.is_websocket());
+
+        let url = BrokerUrl::parse("mqtt://[::1]:1884").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 1884));
+
+        assert!(BrokerUrl::parse("broker:1883").is_err());
+        assert!(BrokerUrl::parse("http://broker").is_err());
+        assert!(BrokerUrl::parse("mqtt://broker:port").is_err());
+    }
+
+    #[test]
+    fn test_credentials_from_template() {
+        let config = Config {
+            username: Some("user-{id}".to_string()),
+            password: Some("secret-{id}".to_string()),
+            ..Config::default()
+        };
+        let options = match mqtt_options(&config, "pub-3".to_string(), 3).unwrap() {
+            ConnectOptions::V3(options) => options,
+            ConnectOptions::V5 { .. } => panic!("expected MQTT 3.1.1 options"),
+        };
+        assert_eq!(options.credentials(), Some(("user-3".to_string(), "secret-3".to_string())));
+    }
+
+    #[test]
+    fn test_v5_properties() {
+        let config = Config {
+            protocol_version: 5,
+            session_expiry_secs: Some(300),
+            message_expiry_secs: Some(60),
+            user_properties: vec![("team".to_string(), "load".to_string())],
+            content_type: Some("application/json".to_string()),
+            ..Config::default()
+        };
+        let (options, publish_properties) = match mqtt_options(&config, "pub-1".to_string(), 1).unwrap() {
+            ConnectOptions::V5 { options, publish_properties } => (options, publish_properties),
+            ConnectOptions::V3(_) => panic!("expected MQTT 5 options"),
+        };
+        assert_eq!(options.connect_properties().unwrap().session_expiry_interval, Some(300));
+        assert_eq!(publish_properties.message_expiry_interval, Some(60));
+        assert_eq!(publish_properties.user_properties, config.user_properties);
+        assert_eq!(publish_properties.content_type.as_deref(), Some("application/json"));
+
+        let unsupported = Config { protocol_version: 6, ..Config::default() };
+        assert!(mqtt_options(&unsupported, "pub-1".to_string(), 1).is_err());
+        let short_keep_alive = Config { protocol_version: 5, keep_alive_secs: 2, ..Config::default() };
+        assert!(mqtt_options(&short_keep_alive, "pub-1".to_string(), 1).is_err());
+    }
+
+    #[test]
+    fn test_password_file_lines_per_client() {
+        let path = std::env::temp_dir().join(format!("mqtt-test-passwords-{}", std::process::id()));
+        fs::write(&path, "first\nsecond\n").unwrap();
+        let config = Config {
+            username: Some("user-{id}".to_string()),
+            password_file: Some(path.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+        assert_eq!(client_password(&config, 1).unwrap().as_deref(), Some("first"));
+        assert_eq!(client_password(&config, 2).unwrap().as_deref(), Some("second"));
+        assert_eq!(client_password(&config, 3).unwrap().as_deref(), Some("first"));
+        // Later clients use the lines read by the first one
+        let _ = fs::remove_file(path);
+        assert_eq!(client_password(&config, 4).unwrap().as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn test_tls_config_is_shared() {
+        let config = Config { tls_insecure: true, tls_alpn: vec!["shared-test".to_string()], ..Config::default() };
+        let first = rustls_config(&config).unwrap();
+        assert!(Arc::ptr_eq(&first, &rustls_config(&config).unwrap()));
+        let other = Config { tls_alpn: vec!["other-test".to_string()], ..config };
+        assert!(!Arc::ptr_eq(&first, &rustls_config(&other).unwrap()));
+
+        let server_name = Config {
+            broker_url: Some("mqtts://192.0.2.1".to_string()),
+            tls_server_name: Some("broker.example.com".to_string()),
+            ..other
+        };
+        assert!(mqtt_options(&server_name, "pub-1".to_string(), 1).is_err());
+    }
+
+    #[test]
+    fn test_websocket_transport() {
+        let config = Config { broker_url: Some("ws://proxy:8080/mqtt".to_string()), ..Config::default() };
+        match mqtt_options(&config, "pub-1".to_string(), 1).unwrap() {
+            ConnectOptions::V3(options) => {
+                assert_eq!(options.broker_address().0, "ws://proxy:8080/mqtt");
+                assert!(matches!(options.transport(), Transport::Ws));
+            }
+            ConnectOptions::V5 { .. } => unreachable!(),
+        }
+
+        let config = Config {
+            broker_url: Some("wss://[::1]/mqtt".to_string()),
+            protocol_version: 5,
+            tls_insecure: true,
+            tls_server_name: Some("broker.example.com".to_string()),
+            ..Config::default()
+        };
+        match mqtt_options(&config, "pub-1".to_string(), 1).unwrap() {
+            ConnectOptions::V5 { options, .. } => {
+                assert_eq!(options.broker_address().0, "wss://[::1]:443/mqtt");
+                assert!(matches!(options.transport(), Transport::Wss(_)));
+                assert!(options.request_modifier().is_some());
+            }
+            ConnectOptions::V3(_) => unreachable!(),
+        }
+    }
+}
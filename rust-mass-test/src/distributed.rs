@@ -0,0 +1,475 @@
+use crate::benchmark::{self, AgentReport, Report};
+use crate::config::Config;
+use crate::connection::{self, BoxError};
+use crate::exporter;
+use crate::metrics::{ClientSnapshot, GlobalMetrics};
+use crate::ui::LogBuffer;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// How often agents send their metrics
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pause before an agent retries reaching the coordinator
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long the coordinator waits for a hello, a ready or the final metrics
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far ahead the common start time lies, enough for every agent to hear of it
+const START_LEAD: Duration = Duration::from_secs(2);
+
+/// Coordinator to agent, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CoordinatorMessage {
+    /// Run producers `first_producer..first_producer + producers` of `config.publishers()`
+    /// `config` comes without credentials, see [`Config::without_credentials`]
+    Assign { agent: usize, config: Box<Config>, first_producer: usize, producers: usize },
+    /// Every agent starts its producers at `start_at`
+    Start { start_at: DateTime<Utc> },
+    Stop,
+}
+
+/// Agent to coordinator, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentMessage {
+    /// `token` has to match the coordinator's `--cluster-token`
+    Hello { name: String, token: String },
+    Ready,
+    Failed { error: String },
+    Metrics { clients: Vec<ClientSnapshot> },
+    /// Last metrics after the producers stopped, with histograms
+    Finished { clients: Vec<ClientSnapshot> },
+}
+
+type MessageLines = Lines<BufReader<OwnedReadHalf>>;
+
+async fn send<T: Serialize>(writer: &mut OwnedWriteHalf, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+/// Next message, `None` when the peer closed the connection
+async fn receive<T: DeserializeOwned>(lines: &mut MessageLines) -> io::Result<Option<T>> {
+    match lines.next_line().await? {
+        Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+        None => Ok(None),
+    }
+}
+
+/// Split `total` producers into `agents` consecutive (first, count) ranges,
+/// the first agents take one more when it does not divide evenly
+pub fn split_producers(total: usize, agents: usize) -> Vec<(usize, usize)> {
+    let agents = agents.max(1);
+    let mut first = 0;
+    (0..agents)
+        .map(|agent| {
+            let count = total / agents + usize::from(agent < total % agents);
+            let range = (first, count);
+            first += count;
+            range
+        })
+        .collect()
+}
+
+/// Modes with their own runner, agents only run producers
+fn unsupported_mode(config: &Config) -> Option<&'static str> {
+    if config.retained_bench.is_some() {
+        Some("the retained message benchmark")
+    } else if config.idle_clients.is_some() {
+        Some("idle connections")
+    } else if config.connect_storm.is_some() {
+        Some("a connect storm")
+    } else if config.search.is_some() {
+        Some("the capacity search")
+    } else {
+        None
+    }
+}
+
+fn apply_snapshots(metrics: &Mutex<GlobalMetrics>, snapshots: &[ClientSnapshot]) {
+    let metrics = metrics.lock().unwrap();
+    for snapshot in snapshots {
+        if let Some(client) = metrics.clients.get(snapshot.id) {
+            client.restore(snapshot);
+        }
+    }
+}
+
+struct AgentConnection {
+    name: String,
+    lines: MessageLines,
+    writer: OwnedWriteHalf,
+}
+
+/// Wait for `agents` agents on `listen`, give each a share of the producers,
+/// start them together and aggregate the metrics they stream back until the
+/// duration or message limit is reached or Ctrl+C.
+///
+/// Only agents that present `token` are accepted. They get the configuration
+/// without credentials and bring their own.
+pub async fn run_coordinator(config: &Config, listen: &str, agents: usize, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if agents == 0 || agents > config.publishers() {
+        return Err(format!("Cannot split {} producers over {} agents", config.publishers(), agents).into());
+    }
+    if let Some(mode) = unsupported_mode(config) {
+        return Err(format!("Agents cannot run {}, only producers", mode).into());
+    }
+    let config = Arc::new(config.clone());
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.publishers())));
+
+    let listener = TcpListener::bind(listen).await?;
+    eprintln!("🛰️  Coordinator listening on {}, waiting for {} agents...", listen, agents);
+    let mut connections: Vec<AgentConnection> = Vec::new();
+    while connections.len() < agents {
+        let (stream, addr) = tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        let (reader, writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        match time::timeout(REPLY_TIMEOUT, receive(&mut lines)).await {
+            Ok(Ok(Some(AgentMessage::Hello { name, token: agent_token }))) if agent_token == token => {
+                eprintln!("Agent {}/{} joined: {} ({})", connections.len() + 1, agents, name, addr);
+                connections.push(AgentConnection { name, lines, writer });
+            }
+            _ => eprintln!("⚠️  Ignoring connection from {} without a hello and the cluster token", addr),
+        }
+    }
+
+    // Agents only run producers, the coordinator decides when to stop
+    let mut agent_config = config.without_credentials();
+    agent_config.duration_secs = None;
+    agent_config.max_messages = None;
+    agent_config.report_file = None;
+    agent_config.metrics_listen = None;
+    let ranges = split_producers(config.publishers(), agents);
+    for (index, (connection, &(first_producer, producers))) in connections.iter_mut().zip(&ranges).enumerate() {
+        let assign = CoordinatorMessage::Assign { agent: index, config: Box::new(agent_config.clone()), first_producer, producers };
+        send(&mut connection.writer, &assign).await?;
+    }
+    for connection in connections.iter_mut() {
+        match time::timeout(REPLY_TIMEOUT, receive(&mut connection.lines)).await {
+            Ok(Ok(Some(AgentMessage::Ready))) => {}
+            Ok(Ok(Some(AgentMessage::Failed { error }))) => return Err(format!("Agent {} failed: {}", connection.name, error).into()),
+            _ => return Err(format!("Agent {} did not get ready", connection.name).into()),
+        }
+    }
+
+    // Optional Prometheus endpoint with the aggregated metrics
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    let start_at = Utc::now() + START_LEAD;
+    let mut writers = Vec::new();
+    let mut readers: Vec<JoinHandle<bool>> = Vec::new();
+    let mut names = Vec::new();
+    for mut connection in connections {
+        send(&mut connection.writer, &CoordinatorMessage::Start { start_at }).await?;
+        writers.push(connection.writer);
+        names.push(connection.name);
+        let metrics = metrics.clone();
+        let mut lines = connection.lines;
+        readers.push(tokio::spawn(async move {
+            while let Ok(Some(message)) = receive::<AgentMessage>(&mut lines).await {
+                match message {
+                    AgentMessage::Metrics { clients } => apply_snapshots(&metrics, &clients),
+                    AgentMessage::Finished { clients } => {
+                        apply_snapshots(&metrics, &clients);
+                        return true;
+                    }
+                    _ => {}
+                }
+            }
+            false
+        }));
+    }
+    time::sleep(until(start_at)).await;
+    let started_at = Utc::now();
+    let started = Instant::now();
+    eprintln!("🚀 Started {} producers on {} agents", config.publishers(), agents);
+    let connect_watch = benchmark::spawn_connect_watch(metrics.clone());
+
+    let status = {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut timer = time::interval_at(Instant::now() + Duration::from_secs(1), Duration::from_secs(1));
+            loop {
+                timer.tick().await;
+                let metrics = metrics.lock().unwrap();
+                eprintln!(
+                    "📈 Connected: {}/{} clients | Published: {} | v/s: {:.2}",
+                    metrics.get_connected_count(),
+                    metrics.clients.len(),
+                    metrics.get_total_published(),
+                    metrics.get_total_vps()
+                );
+            }
+        })
+    };
+
+    let stop_reason = benchmark::wait_for_stop(
+        &config,
+        &metrics,
+        GlobalMetrics::get_total_published,
+        || readers.iter().all(|r| r.is_finished()),
+    )
+    .await;
+    let elapsed = started.elapsed();
+    status.abort();
+    for writer in writers.iter_mut() {
+        let _ = send(writer, &CoordinatorMessage::Stop).await;
+    }
+
+    let all_connected = benchmark::all_connected_secs(connect_watch).await;
+    let mut agent_reports = Vec::new();
+    for ((reader, name), (first_producer, producers)) in readers.into_iter().zip(names).zip(ranges) {
+        let finished = matches!(time::timeout(REPLY_TIMEOUT, reader).await, Ok(Ok(true)));
+        if !finished {
+            eprintln!("⚠️  Agent {} left without final metrics", name);
+        }
+        agent_reports.push(AgentReport { name, first_producer, producers, finished });
+    }
+
+    let final_metrics = metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    eprintln!("Total messages published: {}", final_metrics.get_total_published());
+    eprintln!("{}", benchmark::connect_summary(&final_metrics, all_connected));
+    eprintln!(
+        "Average throughput: {:.2} msg/s",
+        final_metrics.get_total_published() as f64 / elapsed.as_secs_f64().max(0.001)
+    );
+    if final_metrics.get_schedule_lag().count > 0 {
+        eprintln!("Schedule lag: {}", final_metrics.get_schedule_lag().format_ms());
+    }
+    let reason_codes = final_metrics.get_reason_codes();
+    if !reason_codes.is_empty() {
+        let codes: Vec<String> = reason_codes.iter().map(|(code, count)| format!("{}={}", code, count)).collect();
+        eprintln!("Reason codes: {}", codes.join(", "));
+    }
+    for agent in &agent_reports {
+        let published: u64 = final_metrics.clients[agent.first_producer..agent.first_producer + agent.producers]
+            .iter()
+            .map(|c| c.get_total_published())
+            .sum();
+        eprintln!(
+            "  Agent {}: producers {}-{} | Published: {}",
+            agent.name,
+            agent.first_producer + 1,
+            agent.first_producer + agent.producers,
+            published
+        );
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-publish", &config, &final_metrics, started_at, elapsed, stop_reason);
+        report.agents = agent_reports;
+        report.all_connected_secs = all_connected;
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
+    Ok(())
+}
+
+/// Time left until `at` on this host's clock, zero once it passed
+fn until(at: DateTime<Utc>) -> Duration {
+    (at - Utc::now()).to_std().unwrap_or(Duration::ZERO)
+}
+
+fn agent_name() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "agent".to_string());
+    format!("{}-{}", host, std::process::id())
+}
+
+/// Join the coordinator at `coordinator` with `token`, run the producers it
+/// assigns with the credentials from `local` and stream their metrics back
+/// until it says stop
+pub async fn run_agent(coordinator: &str, token: &str, local: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut waiting = false;
+    let stream = loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            connected = TcpStream::connect(coordinator) => match connected {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    if !waiting {
+                        eprintln!("Waiting for coordinator {} ({})...", coordinator, e);
+                        waiting = true;
+                    }
+                    time::sleep(RETRY_DELAY).await;
+                }
+            },
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    send(&mut writer, &AgentMessage::Hello { name: agent_name(), token: token.to_string() }).await?;
+
+    let assignment = tokio::select! {
+        _ = tokio::signal::ctrl_c() => return Ok(()),
+        message = receive(&mut lines) => message?,
+    };
+    let (agent, config, first_producer, producers) = match assignment {
+        Some(CoordinatorMessage::Assign { agent, config, first_producer, producers }) => (agent, config, first_producer, producers),
+        _ => return Err("Expected an assignment from the coordinator, check --cluster-token".into()),
+    };
+    // Catch configuration errors before the coordinator starts anyone
+    let checked = config.with_local_credentials(local).map_err(BoxError::from).and_then(|config| {
+        connection::mqtt_options(&config, "check".to_string(), first_producer + 1)?;
+        Ok(config)
+    });
+    let config = match checked {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            send(&mut writer, &AgentMessage::Failed { error: e.to_string() }).await?;
+            return Err(e.to_string().into());
+        }
+    };
+    send(&mut writer, &AgentMessage::Ready).await?;
+    eprintln!(
+        "Agent {}: producers {}-{} of {}, waiting for start...",
+        agent + 1,
+        first_producer + 1,
+        first_producer + producers,
+        config.publishers()
+    );
+    let start_at = tokio::select! {
+        _ = tokio::signal::ctrl_c() => return Ok(()),
+        message = receive(&mut lines) => match message? {
+            Some(CoordinatorMessage::Start { start_at }) => start_at,
+            _ => return Ok(()),
+        },
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => return Ok(()),
+        _ = time::sleep(until(start_at)) => {}
+    }
+
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(producers)));
+    let log_buffer = LogBuffer::new(100);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_pause_tx, pause_rx) = watch::channel(false);
+    // Every agent runs the whole profile, producers take their share of the total rate
+    let (rate_rx, profile_driver) = crate::phases::start(&config, metrics.clone(), shutdown_rx.clone(), |line| eprintln!("{}", line))?;
+
+    let handles: Vec<JoinHandle<Result<(), BoxError>>> = (0..producers)
+        .map(|index| {
+            let client_metrics = Arc::new(metrics.lock().unwrap().clients[index].clone());
+            tokio::spawn(crate::producer::run_producer(
+                first_producer + index,
+                config.clone(),
+                client_metrics,
+                shutdown_rx.clone(),
+                pause_rx.clone(),
+                rate_rx.clone(),
+                log_buffer.clone(),
+            ))
+        })
+        .collect();
+    eprintln!("🚀 Started {} producers", producers);
+
+    let snapshots = |with_histograms: bool| -> Vec<ClientSnapshot> {
+        let metrics = metrics.lock().unwrap();
+        metrics.clients.iter().map(|c| c.to_snapshot(first_producer + c.id, with_histograms)).collect()
+    };
+    let mut report_timer = time::interval(REPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = report_timer.tick() => {
+                if send(&mut writer, &AgentMessage::Metrics { clients: snapshots(false) }).await.is_err() {
+                    break;
+                }
+            }
+            message = receive::<CoordinatorMessage>(&mut lines) => match message {
+                Ok(Some(CoordinatorMessage::Stop)) | Ok(None) | Err(_) => break,
+                Ok(Some(_)) => {}
+            },
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    for handle in handles {
+        if let Ok(Ok(Err(e))) = time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Producer failed: {}", e);
+        }
+    }
+    if let Some(driver) = profile_driver {
+        let _ = driver.await;
+    }
+    let _ = send(&mut writer, &AgentMessage::Finished { clients: snapshots(true) }).await;
+    eprintln!("✅ Agent stopped, published {}", metrics.lock().unwrap().get_total_published());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_producers() {
+        assert_eq!(split_producers(10, 3), vec![(0, 4), (4, 3), (7, 3)]);
+        assert_eq!(split_producers(4, 4), vec![(0, 1), (1, 1), (2, 1), (3, 1)]);
+        assert_eq!(split_producers(5, 1), vec![(0, 5)]);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let metrics = GlobalMetrics::new(1);
+        let client = &metrics.clients[0];
+        client.increment_published();
+        client.record_reason_code("QuotaExceeded");
+        client.record_schedule_lag(1500);
+
+        let line = serde_json::to_string(&AgentMessage::Finished { clients: vec![client.to_snapshot(7, true)] }).unwrap();
+        let clients = match serde_json::from_str(&line).unwrap() {
+            AgentMessage::Finished { clients } => clients,
+            other => panic!("unexpected message {:?}", other),
+        };
+        assert_eq!(clients[0].id, 7);
+
+        let coordinator = GlobalMetrics::new(8);
+        coordinator.clients[7].restore(&clients[0]);
+        assert_eq!(coordinator.get_total_published(), 1);
+        assert_eq!(coordinator.get_reason_codes().get("QuotaExceeded"), Some(&1));
+        assert_eq!(coordinator.get_schedule_lag().count, 1);
+    }
+
+    #[test]
+    fn test_start_time_and_modes() {
+        let start_at = Utc::now() + START_LEAD;
+        let line = serde_json::to_string(&CoordinatorMessage::Start { start_at }).unwrap();
+        match serde_json::from_str(&line).unwrap() {
+            CoordinatorMessage::Start { start_at: received } => assert_eq!(received, start_at),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(until(start_at) > Duration::ZERO);
+        assert_eq!(until(Utc::now() - START_LEAD), Duration::ZERO);
+
+        assert_eq!(unsupported_mode(&Config::default()), None);
+        let storm = Config { connect_storm: Some(100), ..Config::default() };
+        assert_eq!(unsupported_mode(&storm), Some("a connect storm"));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::metrics::{ClientMetrics, GlobalMetrics, LatencySnapshot};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+
+/// Largest request we bother reading, scrapes are a single short GET
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How often the per-client rates behind the rate gauges are recalculated
+const RATE_REFRESH: Duration = Duration::from_secs(1);
+
+/// Metric name, type, help text and how to read it from one client
+type ClientSeries = (&'static str, &'static str, &'static str, fn(&ClientMetrics) -> f64);
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write a histogram as a summary in seconds, clients without samples are left out
+fn write_summary(
+    out: &mut String,
+    metrics: &GlobalMetrics,
+    role: &str,
+    name: &str,
+    help: &str,
+    snapshot: fn(&ClientMetrics) -> LatencySnapshot,
+) {
+    write_header(out, name, "summary", help);
+    for client in &metrics.clients {
+        let latency = snapshot(client);
+        if latency.count == 0 {
+            continue;
+        }
+        for (quantile, value) in [("0.5", latency.p50), ("0.9", latency.p90), ("0.99", latency.p99), ("0.999", latency.p999), ("1", latency.max)] {
+            let _ = writeln!(
+                out,
+                "{}{{role=\"{}\",client=\"{}\",quantile=\"{}\"}} {}",
+                name, role, client.id + 1, quantile, value as f64 / 1_000_000.0
+            );
+        }
+        let _ = writeln!(out, "{}_count{{role=\"{}\",client=\"{}\"}} {}", name, role, client.id + 1, latency.count);
+    }
+}
+
+/// Render all metrics in the Prometheus text exposition format (version 0.0.4)
+///
+/// `role` ends up as a label so publisher and subscriber scrapes of the same
+/// host can be told apart.
+pub fn render(metrics: &GlobalMetrics, role: &str) -> String {
+    let mut out = String::new();
+
+    write_header(&mut out, "mqtt_test_clients", "gauge", "Configured MQTT clients.");
+    let _ = writeln!(out, "mqtt_test_clients{{role=\"{}\"}} {}", role, metrics.clients.len());
+    write_header(&mut out, "mqtt_test_connected_clients", "gauge", "MQTT clients currently connected.");
+    let _ = writeln!(out, "mqtt_test_connected_clients{{role=\"{}\"}} {}", role, metrics.get_connected_count());
+
+    let per_client: [ClientSeries; 21] = [
+        ("mqtt_test_published_total", "counter", "Messages published.", |c| c.get_total_published() as f64),
+        ("mqtt_test_received_total", "counter", "Messages received.", |c| c.get_total_received() as f64),
+        ("mqtt_test_published_bytes_total", "counter", "Payload bytes published.", |c| c.get_published_bytes() as f64),
+        ("mqtt_test_received_bytes_total", "counter", "Payload bytes received.", |c| c.get_received_bytes() as f64),
+        ("mqtt_test_publish_rate", "gauge", "Messages published per second.", |c| c.cached_vps()),
+        ("mqtt_test_receive_rate", "gauge", "Messages received per second.", |c| c.cached_received_vps()),
+        ("mqtt_test_connected", "gauge", "1 if the client is connected.", |c| if c.is_connected() { 1.0 } else { 0.0 }),
+        ("mqtt_test_reconnects_total", "counter", "Successful reconnects after the first connection.", |c| c.get_reconnects() as f64),
+        ("mqtt_test_connection_errors_total", "counter", "Connection errors.", |c| c.get_connection_errors() as f64),
+        ("mqtt_test_publish_errors_total", "counter", "Failed publish requests.", |c| c.get_publish_errors() as f64),
+        ("mqtt_test_lost_total", "counter", "Messages detected as lost from sequence gaps.", |c| c.get_lost() as f64),
+        ("mqtt_test_duplicates_total", "counter", "Duplicate messages received.", |c| c.get_duplicates() as f64),
+        ("mqtt_test_queued_delivered_total", "counter", "Messages delivered from a resumed session queue.", |c| c.get_queued_delivered() as f64),
+        ("mqtt_test_queued_lost_total", "counter", "Messages missing from a resumed session queue.", |c| c.get_queued_lost() as f64),
+        ("mqtt_test_pubacks_total", "counter", "PUBACKs received (publisher) or sent (subscriber).", |c| c.get_pubacks() as f64),
+        ("mqtt_test_pubrecs_total", "counter", "PUBRECs received (publisher) or sent (subscriber).", |c| c.get_pubrecs() as f64),
+        ("mqtt_test_pubcomps_total", "counter", "PUBCOMPs received (publisher) or sent (subscriber).", |c| c.get_pubcomps() as f64),
+        ("mqtt_test_inflight", "gauge", "QoS 1 and 2 flows sent but not completed.", |c| c.get_inflight() as f64),
+        ("mqtt_test_topic_alias_reuses_total", "counter", "Publishes sent by topic alias only.", |c| c.get_alias_reuses() as f64),
+        ("mqtt_test_topic_alias_assignments_total", "counter", "Publishes that bound a topic alias.", |c| c.get_alias_assignments() as f64),
+        ("mqtt_test_topic_alias_bytes_saved", "gauge", "Net PUBLISH bytes saved by topic aliases.", |c| c.get_alias_bytes_saved() as f64),
+    ];
+
+    for (name, kind, help, value) in per_client.iter() {
+        write_header(&mut out, name, kind, help);
+        for client in &metrics.clients {
+            let _ = writeln!(out, "{}{{role=\"{}\",client=\"{}\"}} {}", name, role, client.id + 1, value(client));
+        }
+    }
+
+    write_header(&mut out, "mqtt_test_reason_codes_total", "counter", "Non-success reason codes received from the broker.");
+    for client in &metrics.clients {
+        for (code, count) in client.get_reason_codes() {
+            let _ = writeln!(
+                out,
+                "mqtt_test_reason_codes_total{{role=\"{}\",client=\"{}\",code=\"{}\"}} {}",
+                role, client.id + 1, code, count
+            );
+        }
+    }
+
+    write_summary(&mut out, metrics, role, "mqtt_test_latency_seconds", "Publish-to-receive latency.", ClientMetrics::get_latency);
+    write_summary(
+        &mut out,
+        metrics,
+        role,
+        "mqtt_test_corrected_latency_seconds",
+        "Latency from the intended send time (target rate only).",
+        ClientMetrics::get_corrected_latency,
+    );
+    write_summary(
+        &mut out,
+        metrics,
+        role,
+        "mqtt_test_connect_latency_seconds",
+        "Time from opening a connection to its CONNACK.",
+        ClientMetrics::get_connect_latency,
+    );
+    write_summary(
+        &mut out,
+        metrics,
+        role,
+        "mqtt_test_ping_rtt_seconds",
+        "Time from a keep-alive PINGREQ to its PINGRESP.",
+        ClientMetrics::get_ping_rtt,
+    );
+    write_summary(
+        &mut out,
+        metrics,
+        role,
+        "mqtt_test_schedule_lag_seconds",
+        "How late publishes were against the target rate schedule.",
+        ClientMetrics::get_schedule_lag,
+    );
+
+    out
+}
+
+async fn handle_connection(mut stream: TcpStream, metrics: Arc<Mutex<GlobalMetrics>>, role: &'static str) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    // Read until the end of the request headers, the body (if any) is ignored
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let response = if path == "/metrics" || path.starts_with("/metrics?") {
+        let body = render(&metrics.lock().unwrap(), role);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Serve `/metrics` on `addr` until the returned task is aborted
+pub async fn spawn(
+    addr: &str,
+    metrics: Arc<Mutex<GlobalMetrics>>,
+    role: &'static str,
+) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr).await?;
+    Ok(tokio::spawn(async move {
+        // Scrapes read the cached rates, refresh them here so they are current
+        // without the UI and without every scrape starting a new rate window
+        let mut refresh = time::interval(RATE_REFRESH);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    if let Ok((stream, _)) = accepted {
+                        tokio::spawn(handle_connection(stream, metrics.clone(), role));
+                    }
+                }
+                _ = refresh.tick() => {
+                    for client in &metrics.lock().unwrap().clients {
+                        client.calculate_vps();
+                        client.calculate_received_vps();
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_per_client_series() {
+        let metrics = GlobalMetrics::new(2);
+        metrics.clients[0].increment_published();
+        metrics.clients[1].record_latency(1500);
+        metrics.clients[1].record_reason_code("NotAuthorized");
+
+        let text = render(&metrics, "publisher");
+        assert!(text.contains("# TYPE mqtt_test_published_total counter"));
+        assert!(text.contains("mqtt_test_published_total{role=\"publisher\",client=\"1\"} 1"));
+        assert!(text.contains("mqtt_test_published_total{role=\"publisher\",client=\"2\"} 0"));
+        assert!(text.contains("mqtt_test_connected_clients{role=\"publisher\"} 0"));
+        assert!(text.contains("mqtt_test_latency_seconds_count{role=\"publisher\",client=\"2\"} 1"));
+        assert!(!text.contains("mqtt_test_latency_seconds_count{role=\"publisher\",client=\"1\"}"));
+        assert!(text.contains("mqtt_test_reason_codes_total{role=\"publisher\",client=\"2\",code=\"NotAuthorized\"} 1"));
+    }
+}
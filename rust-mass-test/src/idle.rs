@@ -0,0 +1,261 @@
+use crate::benchmark::{IdleReport, Report, StopReason};
+use crate::config::Config;
+use crate::connection::{self, ClientEvent};
+use crate::exporter;
+use crate::metrics::{ClientMetrics, GlobalMetrics};
+use chrono::Utc;
+use rumqttc::QoS;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// Pause before an idle client retries a failed connect
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the connection count and memory use are printed
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Resident set size in bytes from the contents of /proc/<pid>/status
+pub fn resident_bytes(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Resident set size of a process, `None` where /proc is unavailable
+fn process_resident_bytes(pid: Option<u32>) -> Option<u64> {
+    let path = match pid {
+        Some(pid) => format!("/proc/{}/status", pid),
+        None => "/proc/self/status".to_string(),
+    };
+    resident_bytes(&fs::read_to_string(path).ok()?)
+}
+
+/// Memory growth since `baseline` divided over the open connections
+pub fn bytes_per_connection(baseline: Option<u64>, now: Option<u64>, connected: usize) -> Option<f64> {
+    let (baseline, now) = (baseline?, now?);
+    (connected > 0).then(|| now.saturating_sub(baseline) as f64 / connected as f64)
+}
+
+fn format_bytes(bytes: Option<f64>) -> String {
+    match bytes {
+        Some(bytes) if bytes >= 1024.0 * 1024.0 => format!("{:.1} MiB", bytes / (1024.0 * 1024.0)),
+        Some(bytes) => format!("{:.1} KiB", bytes / 1024.0),
+        None => "n/a".to_string(),
+    }
+}
+
+/// Open `idle_clients` connections (at `connect_rate` per second, or all at
+/// once) that only send keep-alive PINGREQs, optionally after subscribing to
+/// one topic each, and hold them until the duration ends or Ctrl+C.
+///
+/// The clients share one set of metrics, so the tool's own memory per
+/// connection stays close to what an MQTT connection costs. Memory is measured
+/// against a baseline taken before the first connect.
+pub async fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let clients = config.idle_clients.unwrap_or(config.num_producers);
+    let config = Arc::new(config.clone());
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(1)));
+    let shared = metrics.lock().unwrap().clients[0].clone();
+    let connected = Arc::new(AtomicUsize::new(0));
+
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    let process_baseline = process_resident_bytes(None);
+    let broker_baseline = config.broker_pid.and_then(|pid| process_resident_bytes(Some(pid)));
+    if config.broker_pid.is_some() && broker_baseline.is_none() {
+        eprintln!("⚠️  Cannot read the memory of broker process {}", config.broker_pid.unwrap_or_default());
+    }
+
+    eprintln!(
+        "💤 Idle mode: {} clients, keep-alive {}s{}",
+        clients,
+        config.keep_alive_secs,
+        if config.idle_subscribe { ", one subscription each" } else { "" }
+    );
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let started_at = Utc::now();
+    let started = Instant::now();
+
+    let handles: Vec<JoinHandle<Result<(), connection::BoxError>>> = (0..clients)
+        .map(|index| {
+            let topic = config.idle_subscribe.then(|| format!("{}/idle/{}", config.topic_prefix, index + 1));
+            tokio::spawn(idle_client(index, config.clone(), topic, shared.clone(), connected.clone(), shutdown_rx.clone()))
+        })
+        .collect();
+
+    let deadline = config.duration_secs.map(|secs| started + Duration::from_secs(secs));
+    let mut status = time::interval_at(started + STATUS_INTERVAL, STATUS_INTERVAL);
+    let stop_reason = loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break StopReason::Interrupted,
+            _ = time::sleep_until(deadline.unwrap_or(started)), if deadline.is_some() => break StopReason::Duration,
+            _ = status.tick() => {
+                let open = connected.load(Ordering::Relaxed);
+                shared.set_connected(open > 0);
+                eprintln!(
+                    "[{:>5.0}s] Connected: {}/{} | Ping RTT: {} | Memory/connection: {}",
+                    started.elapsed().as_secs_f64(),
+                    open,
+                    clients,
+                    shared.get_ping_rtt().format_ms(),
+                    format_bytes(bytes_per_connection(process_baseline, process_resident_bytes(None), open)),
+                );
+            }
+        }
+    };
+
+    // Sample memory while the connections are still open
+    let open = connected.load(Ordering::Relaxed);
+    let broker_resident = config.broker_pid.and_then(|pid| process_resident_bytes(Some(pid)));
+    let idle = IdleReport {
+        clients,
+        connected: open,
+        subscribed: config.idle_subscribe,
+        keep_alive_secs: config.keep_alive_secs,
+        process_bytes_per_connection: bytes_per_connection(process_baseline, process_resident_bytes(None), open),
+        broker_resident_bytes: broker_resident,
+        broker_bytes_per_connection: bytes_per_connection(broker_baseline, broker_resident, open),
+    };
+
+    let elapsed = started.elapsed();
+    let _ = shutdown_tx.send(true);
+    for handle in handles {
+        if let Ok(Ok(Err(e))) = time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Client failed: {}", e);
+        }
+    }
+
+    let final_metrics = metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    eprintln!(
+        "Connected: {}/{} | CONNACK latency: {} | Ping RTT: {}",
+        idle.connected,
+        clients,
+        final_metrics.get_connect_latency().format_ms(),
+        final_metrics.get_ping_rtt().format_ms()
+    );
+    eprintln!(
+        "Failed connection attempts: {} | Reconnects: {}",
+        final_metrics.get_total_connection_errors(),
+        final_metrics.get_total_reconnects()
+    );
+    eprintln!("Memory/connection: {} (this process)", format_bytes(idle.process_bytes_per_connection));
+    if config.broker_pid.is_some() {
+        eprintln!("Memory/connection: {} (broker)", format_bytes(idle.broker_bytes_per_connection));
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-publish", &config, &final_metrics, started_at, elapsed, stop_reason);
+        report.idle = Some(idle);
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
+    Ok(())
+}
+
+/// Connect, subscribe to `topic` if given and answer keep-alives until
+/// shutdown, reconnecting when the connection drops
+async fn idle_client(
+    index: usize,
+    config: Arc<Config>,
+    topic: Option<String>,
+    metrics: ClientMetrics,
+    connected: Arc<AtomicUsize>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<(), connection::BoxError> {
+    let mqttoptions = connection::mqtt_options(&config, connection::client_id(&config, "idle", index + 1), index + 1)?;
+
+    tokio::select! {
+        _ = shutdown_rx.changed() => return Ok(()),
+        _ = time::sleep(connection::connect_delay(&config, index)) => {}
+    }
+
+    let mut has_connected = false;
+    loop {
+        let connect_started = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+        let mut is_connected = false;
+        // Only a SUBSCRIBE and the DISCONNECT go through the request channel
+        let (client, mut eventloop) = mqttoptions.connect(1);
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.changed() => {
+                    let _ = client.disconnect().await;
+                    if is_connected {
+                        connected.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    return Ok(());
+                }
+                event = eventloop.poll() => match event {
+                    Ok(ClientEvent::ConnAck { .. }) => {
+                        metrics.record_connect_latency(connect_started.elapsed().as_micros() as u64);
+                        if has_connected {
+                            metrics.increment_reconnects();
+                        }
+                        has_connected = true;
+                        is_connected = true;
+                        connected.fetch_add(1, Ordering::Relaxed);
+                        if let Some(topic) = &topic {
+                            let _ = client.subscribe(topic, QoS::AtMostOnce).await;
+                        }
+                    }
+                    Ok(ClientEvent::PingSent) => ping_sent = Some(Instant::now()),
+                    Ok(ClientEvent::PingResp) => {
+                        if let Some(sent) = ping_sent.take() {
+                            metrics.record_ping_rtt(sent.elapsed().as_micros() as u64);
+                        }
+                    }
+                    Ok(ClientEvent::Disconnect) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        if let Some(code) = e.reason_code() {
+                            metrics.record_reason_code(&code);
+                        }
+                        metrics.increment_connection_errors();
+                        break;
+                    }
+                },
+            }
+        }
+
+        if is_connected {
+            connected.fetch_sub(1, Ordering::Relaxed);
+        }
+        tokio::select! {
+            _ = shutdown_rx.changed() => return Ok(()),
+            _ = time::sleep(RETRY_DELAY) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resident_bytes() {
+        let status = "Name:\tmosquitto\nVmPeak:\t  20480 kB\nVmRSS:\t    8192 kB\nThreads:\t1\n";
+        assert_eq!(resident_bytes(status), Some(8 * 1024 * 1024));
+        assert_eq!(resident_bytes("Name:\tkernel\n"), None);
+
+        assert_eq!(bytes_per_connection(Some(1000), Some(5000), 4), Some(1000.0));
+        assert_eq!(bytes_per_connection(Some(1000), Some(5000), 0), None);
+        assert_eq!(bytes_per_connection(None, Some(5000), 4), None);
+    }
+}
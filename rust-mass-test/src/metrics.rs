@@ -1,7 +1,134 @@
+use crate::alias::ALIAS_PROPERTY_BYTES;
+use crate::connection::ClientEvent;
+use crate::sequence::{QueueOutcome, SequenceOutcome};
+use hdrhistogram::Histogram;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Highest latency the histograms can hold (1 hour in microseconds), larger values are clamped
+const MAX_LATENCY_US: u64 = 3_600_000_000;
+
+fn new_latency_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).expect("valid histogram bounds")
+}
+
+fn record(histogram: &Mutex<Histogram<u64>>, value_us: u64) {
+    if let Ok(mut hist) = histogram.lock() {
+        hist.saturating_record(value_us.min(MAX_LATENCY_US));
+    }
+}
+
+fn snapshot(histogram: &Mutex<Histogram<u64>>) -> LatencySnapshot {
+    match histogram.lock() {
+        Ok(hist) => LatencySnapshot::from_histogram(&hist),
+        Err(_) => LatencySnapshot::default(),
+    }
+}
+
+/// Point-in-time view of a latency histogram, all values in microseconds
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl LatencySnapshot {
+    fn from_histogram(hist: &Histogram<u64>) -> Self {
+        if hist.is_empty() {
+            return LatencySnapshot::default();
+        }
+        LatencySnapshot {
+            count: hist.len(),
+            p50: hist.value_at_quantile(0.50),
+            p90: hist.value_at_quantile(0.90),
+            p99: hist.value_at_quantile(0.99),
+            p999: hist.value_at_quantile(0.999),
+            max: hist.max(),
+        }
+    }
+
+    /// Format as "p50/p90/p99/p99.9/max" in milliseconds
+    pub fn format_ms(&self) -> String {
+        if self.count == 0 {
+            return "n/a".to_string();
+        }
+        format!(
+            "p50={:.2} p90={:.2} p99={:.2} p99.9={:.2} max={:.2} ms",
+            self.p50 as f64 / 1000.0,
+            self.p90 as f64 / 1000.0,
+            self.p99 as f64 / 1000.0,
+            self.p999 as f64 / 1000.0,
+            self.max as f64 / 1000.0,
+        )
+    }
+}
+
+/// Histogram as (value, count) pairs of its recorded buckets
+type HistogramBuckets = Vec<(u64, u64)>;
+
+fn histogram_buckets(histogram: &Mutex<Histogram<u64>>) -> HistogramBuckets {
+    match histogram.lock() {
+        Ok(hist) => hist.iter_recorded().map(|v| (v.value_iterated_to(), v.count_at_value())).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn restore_histogram(histogram: &Mutex<Histogram<u64>>, buckets: &HistogramBuckets) {
+    if let Ok(mut hist) = histogram.lock() {
+        hist.reset();
+        for &(value, count) in buckets {
+            let _ = hist.record_n(value.min(MAX_LATENCY_US), count);
+        }
+    }
+}
+
+/// Serializable copy of a client's metrics, how agents ship them to the coordinator
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientSnapshot {
+    pub id: usize,
+    pub connected: bool,
+    pub published: u64,
+    pub received: u64,
+    pub published_bytes: u64,
+    pub received_bytes: u64,
+    pub lost: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub queued_delivered: u64,
+    pub queued_lost: u64,
+    pub sessions_lost: u64,
+    pub pubacks: u64,
+    pub pubrecs: u64,
+    pub pubcomps: u64,
+    pub max_inflight: u64,
+    pub connection_errors: u64,
+    pub reconnects: u64,
+    pub publish_errors: u64,
+    pub reason_codes: BTreeMap<String, u64>,
+    pub alias_reuses: u64,
+    pub alias_assignments: u64,
+    pub alias_topic_bytes: u64,
+    /// Only in the final snapshot, histograms are too large to send every second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histograms: Option<HistogramSnapshot>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    pub latency: HistogramBuckets,
+    pub corrected_latency: HistogramBuckets,
+    pub schedule_lag: HistogramBuckets,
+    pub connect_latency: HistogramBuckets,
+    pub ping_rtt: HistogramBuckets,
+}
+
 #[derive(Clone)]
 pub struct ClientMetrics {
     pub id: usize,
@@ -20,6 +147,31 @@ pub struct ClientMetrics {
     #[allow(dead_code)]
     cached_recv_vps: Arc<AtomicU64>,
     connected: Arc<AtomicBool>,
+    latency: Arc<Mutex<Histogram<u64>>>,
+    corrected_latency: Arc<Mutex<Histogram<u64>>>,
+    schedule_lag: Arc<Mutex<Histogram<u64>>>,
+    connect_latency: Arc<Mutex<Histogram<u64>>>,
+    ping_rtt: Arc<Mutex<Histogram<u64>>>,
+    lost: Arc<AtomicU64>,
+    duplicates: Arc<AtomicU64>,
+    out_of_order: Arc<AtomicU64>,
+    queued_delivered: Arc<AtomicU64>,
+    queued_lost: Arc<AtomicU64>,
+    sessions_lost: Arc<AtomicU64>,
+    pubacks: Arc<AtomicU64>,
+    pubrecs: Arc<AtomicU64>,
+    pubcomps: Arc<AtomicU64>,
+    inflight: Arc<AtomicU64>,
+    max_inflight: Arc<AtomicU64>,
+    connection_errors: Arc<AtomicU64>,
+    reconnects: Arc<AtomicU64>,
+    publish_errors: Arc<AtomicU64>,
+    reason_codes: Arc<Mutex<HashMap<String, u64>>>,
+    alias_reuses: Arc<AtomicU64>,
+    alias_assignments: Arc<AtomicU64>,
+    alias_topic_bytes: Arc<AtomicU64>,
+    published_bytes: Arc<AtomicU64>,
+    received_bytes: Arc<AtomicU64>,
 }
 
 impl ClientMetrics {
@@ -40,6 +192,31 @@ impl ClientMetrics {
             last_recv_vps_count: Arc::new(AtomicU64::new(0)),
             cached_recv_vps: Arc::new(AtomicU64::new(0)),
             connected: Arc::new(AtomicBool::new(false)),
+            latency: Arc::new(Mutex::new(new_latency_histogram())),
+            corrected_latency: Arc::new(Mutex::new(new_latency_histogram())),
+            schedule_lag: Arc::new(Mutex::new(new_latency_histogram())),
+            connect_latency: Arc::new(Mutex::new(new_latency_histogram())),
+            ping_rtt: Arc::new(Mutex::new(new_latency_histogram())),
+            lost: Arc::new(AtomicU64::new(0)),
+            duplicates: Arc::new(AtomicU64::new(0)),
+            out_of_order: Arc::new(AtomicU64::new(0)),
+            queued_delivered: Arc::new(AtomicU64::new(0)),
+            queued_lost: Arc::new(AtomicU64::new(0)),
+            sessions_lost: Arc::new(AtomicU64::new(0)),
+            pubacks: Arc::new(AtomicU64::new(0)),
+            pubrecs: Arc::new(AtomicU64::new(0)),
+            pubcomps: Arc::new(AtomicU64::new(0)),
+            inflight: Arc::new(AtomicU64::new(0)),
+            max_inflight: Arc::new(AtomicU64::new(0)),
+            connection_errors: Arc::new(AtomicU64::new(0)),
+            reconnects: Arc::new(AtomicU64::new(0)),
+            publish_errors: Arc::new(AtomicU64::new(0)),
+            reason_codes: Arc::new(Mutex::new(HashMap::new())),
+            alias_reuses: Arc::new(AtomicU64::new(0)),
+            alias_assignments: Arc::new(AtomicU64::new(0)),
+            alias_topic_bytes: Arc::new(AtomicU64::new(0)),
+            published_bytes: Arc::new(AtomicU64::new(0)),
+            received_bytes: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -53,6 +230,26 @@ impl ClientMetrics {
         self.total_received.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Count payload bytes of a published message
+    #[allow(dead_code)]
+    pub fn add_published_bytes(&self, bytes: u64) {
+        self.published_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count payload bytes of a received message
+    #[allow(dead_code)]
+    pub fn add_received_bytes(&self, bytes: u64) {
+        self.received_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn get_published_bytes(&self) -> u64 {
+        self.published_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn get_received_bytes(&self) -> u64 {
+        self.received_bytes.load(Ordering::Relaxed)
+    }
+
     pub fn get_total_published(&self) -> u64 {
         self.total_published.load(Ordering::Relaxed)
     }
@@ -137,6 +334,18 @@ impl ClientMetrics {
         vps
     }
 
+    /// Publish rate from the last `calculate_vps`, without starting a new window
+    #[allow(dead_code)]
+    pub fn cached_vps(&self) -> f64 {
+        f64::from_bits(self.cached_pub_vps.load(Ordering::Relaxed))
+    }
+
+    /// Receive rate from the last `calculate_received_vps`, without starting a new window
+    #[allow(dead_code)]
+    pub fn cached_received_vps(&self) -> f64 {
+        f64::from_bits(self.cached_recv_vps.load(Ordering::Relaxed))
+    }
+
     #[allow(dead_code)]
     pub fn reset(&self) {
         let now = SystemTime::now()
@@ -152,6 +361,33 @@ impl ClientMetrics {
         self.last_recv_vps_time.store(now, Ordering::Relaxed);
         self.last_recv_vps_count.store(0, Ordering::Relaxed);
         self.cached_recv_vps.store(0, Ordering::Relaxed);
+        for histogram in [&self.latency, &self.corrected_latency, &self.schedule_lag, &self.connect_latency, &self.ping_rtt] {
+            if let Ok(mut hist) = histogram.lock() {
+                hist.reset();
+            }
+        }
+        self.lost.store(0, Ordering::Relaxed);
+        self.duplicates.store(0, Ordering::Relaxed);
+        self.out_of_order.store(0, Ordering::Relaxed);
+        self.queued_delivered.store(0, Ordering::Relaxed);
+        self.queued_lost.store(0, Ordering::Relaxed);
+        self.sessions_lost.store(0, Ordering::Relaxed);
+        self.pubacks.store(0, Ordering::Relaxed);
+        self.pubrecs.store(0, Ordering::Relaxed);
+        self.pubcomps.store(0, Ordering::Relaxed);
+        self.inflight.store(0, Ordering::Relaxed);
+        self.max_inflight.store(0, Ordering::Relaxed);
+        self.connection_errors.store(0, Ordering::Relaxed);
+        self.reconnects.store(0, Ordering::Relaxed);
+        self.publish_errors.store(0, Ordering::Relaxed);
+        if let Ok(mut codes) = self.reason_codes.lock() {
+            codes.clear();
+        }
+        self.alias_reuses.store(0, Ordering::Relaxed);
+        self.alias_assignments.store(0, Ordering::Relaxed);
+        self.alias_topic_bytes.store(0, Ordering::Relaxed);
+        self.published_bytes.store(0, Ordering::Relaxed);
+        self.received_bytes.store(0, Ordering::Relaxed);
     }
 
     pub fn set_connected(&self, connected: bool) {
@@ -161,6 +397,344 @@ impl ClientMetrics {
     pub fn is_connected(&self) -> bool {
         self.connected.load(Ordering::Relaxed)
     }
+
+    /// Record one publish-to-receive latency sample in microseconds
+    #[allow(dead_code)]
+    pub fn record_latency(&self, latency_us: u64) {
+        record(&self.latency, latency_us);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_latency(&self) -> LatencySnapshot {
+        snapshot(&self.latency)
+    }
+
+    /// Record one latency sample measured from the intended send time, in microseconds
+    #[allow(dead_code)]
+    pub fn record_corrected_latency(&self, latency_us: u64) {
+        record(&self.corrected_latency, latency_us);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_corrected_latency(&self) -> LatencySnapshot {
+        snapshot(&self.corrected_latency)
+    }
+
+    /// Record how far behind its rate schedule a publish was, in microseconds
+    #[allow(dead_code)]
+    pub fn record_schedule_lag(&self, lag_us: u64) {
+        record(&self.schedule_lag, lag_us);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_schedule_lag(&self) -> LatencySnapshot {
+        snapshot(&self.schedule_lag)
+    }
+
+    /// Record the time from opening a connection to its CONNACK, in microseconds
+    pub fn record_connect_latency(&self, latency_us: u64) {
+        record(&self.connect_latency, latency_us);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_connect_latency(&self) -> LatencySnapshot {
+        snapshot(&self.connect_latency)
+    }
+
+    /// Record the time from a PINGREQ to its PINGRESP, in microseconds
+    #[allow(dead_code)]
+    pub fn record_ping_rtt(&self, rtt_us: u64) {
+        record(&self.ping_rtt, rtt_us);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_ping_rtt(&self) -> LatencySnapshot {
+        snapshot(&self.ping_rtt)
+    }
+
+    /// Update loss/duplicate/reordering counters from a sequence check
+    #[allow(dead_code)]
+    pub fn record_sequence(&self, outcome: SequenceOutcome) {
+        match outcome {
+            SequenceOutcome::First | SequenceOutcome::InOrder => {}
+            SequenceOutcome::Gap(missing) => {
+                self.lost.fetch_add(missing, Ordering::Relaxed);
+            }
+            SequenceOutcome::OutOfOrder => {
+                // It was counted as lost when the gap was seen
+                let _ = self.lost.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(1)));
+                self.out_of_order.fetch_add(1, Ordering::Relaxed);
+            }
+            SequenceOutcome::Duplicate => {
+                self.duplicates.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_lost(&self) -> u64 {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_out_of_order(&self) -> u64 {
+        self.out_of_order.load(Ordering::Relaxed)
+    }
+
+    /// Count what a resumed session queue delivered and dropped for one message
+    #[allow(dead_code)]
+    pub fn record_queue(&self, outcome: QueueOutcome) {
+        if outcome.queued {
+            self.queued_delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        self.queued_lost.fetch_add(outcome.lost, Ordering::Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_queued_delivered(&self) -> u64 {
+        self.queued_delivered.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_queued_lost(&self) -> u64 {
+        self.queued_lost.load(Ordering::Relaxed)
+    }
+
+    /// A reconnect that asked to resume a session but the broker had none
+    #[allow(dead_code)]
+    pub fn increment_sessions_lost(&self) {
+        self.sessions_lost.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_sessions_lost(&self) -> u64 {
+        self.sessions_lost.load(Ordering::Relaxed)
+    }
+
+    /// Count a PUBACK, PUBREC or PUBCOMP (received by producers, sent by subscribers)
+    /// and keep track of the QoS 1 and 2 flows still open
+    #[allow(dead_code)]
+    pub fn record_ack_flow(&self, event: &ClientEvent) {
+        match event {
+            ClientEvent::PublishSent => self.inflight_started(),
+            ClientEvent::PubAck { .. } => {
+                self.pubacks.fetch_add(1, Ordering::Relaxed);
+                self.inflight_done();
+            }
+            ClientEvent::PubRec { .. } => {
+                self.pubrecs.fetch_add(1, Ordering::Relaxed);
+            }
+            ClientEvent::PubComp => {
+                self.pubcomps.fetch_add(1, Ordering::Relaxed);
+                self.inflight_done();
+            }
+            ClientEvent::PubAckSent => {
+                self.pubacks.fetch_add(1, Ordering::Relaxed);
+            }
+            ClientEvent::PubRecSent => {
+                self.pubrecs.fetch_add(1, Ordering::Relaxed);
+                self.inflight_started();
+            }
+            ClientEvent::PubCompSent => {
+                self.pubcomps.fetch_add(1, Ordering::Relaxed);
+                self.inflight_done();
+            }
+            _ => {}
+        }
+    }
+
+    fn inflight_started(&self) {
+        let inflight = self.inflight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_inflight.fetch_max(inflight, Ordering::Relaxed);
+    }
+
+    fn inflight_done(&self) {
+        let _ = self.inflight.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(1)));
+    }
+
+    /// Forget open flows when the connection is gone, rumqttc resends them after reconnecting
+    #[allow(dead_code)]
+    pub fn clear_inflight(&self) {
+        self.inflight.store(0, Ordering::Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_pubacks(&self) -> u64 {
+        self.pubacks.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_pubrecs(&self) -> u64 {
+        self.pubrecs.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_pubcomps(&self) -> u64 {
+        self.pubcomps.load(Ordering::Relaxed)
+    }
+
+    /// QoS 1 and 2 flows sent but not completed (PUBLISH without PUBACK/PUBCOMP,
+    /// or for subscribers PUBREC without PUBCOMP)
+    #[allow(dead_code)]
+    pub fn get_inflight(&self) -> u64 {
+        self.inflight.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_max_inflight(&self) -> u64 {
+        self.max_inflight.load(Ordering::Relaxed)
+    }
+
+    pub fn increment_connection_errors(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_connection_errors(&self) -> u64 {
+        self.connection_errors.load(Ordering::Relaxed)
+    }
+
+    /// Count a successful connection that followed an earlier one
+    pub fn increment_reconnects(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn increment_publish_errors(&self) {
+        self.publish_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_publish_errors(&self) -> u64 {
+        self.publish_errors.load(Ordering::Relaxed)
+    }
+
+    /// Count a non-success reason code received from the broker (e.g. "NotAuthorized")
+    pub fn record_reason_code(&self, code: &str) {
+        if let Ok(mut codes) = self.reason_codes.lock() {
+            match codes.get_mut(code) {
+                Some(count) => *count += 1,
+                None => {
+                    codes.insert(code.to_string(), 1);
+                }
+            }
+        }
+    }
+
+    pub fn get_reason_codes(&self) -> BTreeMap<String, u64> {
+        match self.reason_codes.lock() {
+            Ok(codes) => codes.iter().map(|(code, count)| (code.clone(), *count)).collect(),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    /// Count a publish sent by alias only, `topic_bytes` is the length of the topic left out
+    #[allow(dead_code)]
+    pub fn record_alias_reuse(&self, topic_bytes: u64) {
+        self.alias_reuses.fetch_add(1, Ordering::Relaxed);
+        self.alias_topic_bytes.fetch_add(topic_bytes, Ordering::Relaxed);
+    }
+
+    /// Count a publish that bound a topic to an alias
+    #[allow(dead_code)]
+    pub fn increment_alias_assignments(&self) {
+        self.alias_assignments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_alias_reuses(&self) -> u64 {
+        self.alias_reuses.load(Ordering::Relaxed)
+    }
+
+    pub fn get_alias_assignments(&self) -> u64 {
+        self.alias_assignments.load(Ordering::Relaxed)
+    }
+
+    /// Topic bytes not sent thanks to aliases, minus the alias properties that were sent.
+    /// Negative when churn rebinds aliases more often than they are reused.
+    pub fn get_alias_bytes_saved(&self) -> i64 {
+        let overhead = (self.get_alias_reuses() + self.get_alias_assignments()) * ALIAS_PROPERTY_BYTES;
+        self.alias_topic_bytes.load(Ordering::Relaxed) as i64 - overhead as i64
+    }
+
+    /// Copy the metrics under the id `id`, with the histograms if `with_histograms`
+    #[allow(dead_code)]
+    pub fn to_snapshot(&self, id: usize, with_histograms: bool) -> ClientSnapshot {
+        ClientSnapshot {
+            id,
+            connected: self.is_connected(),
+            published: self.get_total_published(),
+            received: self.get_total_received(),
+            published_bytes: self.get_published_bytes(),
+            received_bytes: self.get_received_bytes(),
+            lost: self.get_lost(),
+            duplicates: self.get_duplicates(),
+            out_of_order: self.get_out_of_order(),
+            queued_delivered: self.get_queued_delivered(),
+            queued_lost: self.get_queued_lost(),
+            sessions_lost: self.get_sessions_lost(),
+            pubacks: self.get_pubacks(),
+            pubrecs: self.get_pubrecs(),
+            pubcomps: self.get_pubcomps(),
+            max_inflight: self.get_max_inflight(),
+            connection_errors: self.get_connection_errors(),
+            reconnects: self.get_reconnects(),
+            publish_errors: self.get_publish_errors(),
+            reason_codes: self.get_reason_codes(),
+            alias_reuses: self.get_alias_reuses(),
+            alias_assignments: self.get_alias_assignments(),
+            alias_topic_bytes: self.alias_topic_bytes.load(Ordering::Relaxed),
+            histograms: with_histograms.then(|| HistogramSnapshot {
+                latency: histogram_buckets(&self.latency),
+                corrected_latency: histogram_buckets(&self.corrected_latency),
+                schedule_lag: histogram_buckets(&self.schedule_lag),
+                connect_latency: histogram_buckets(&self.connect_latency),
+                ping_rtt: histogram_buckets(&self.ping_rtt),
+            }),
+        }
+    }
+
+    /// Overwrite the metrics with a snapshot, histograms are kept if it has none
+    #[allow(dead_code)]
+    pub fn restore(&self, snapshot: &ClientSnapshot) {
+        self.set_connected(snapshot.connected);
+        self.total_published.store(snapshot.published, Ordering::Relaxed);
+        self.total_received.store(snapshot.received, Ordering::Relaxed);
+        self.published_bytes.store(snapshot.published_bytes, Ordering::Relaxed);
+        self.received_bytes.store(snapshot.received_bytes, Ordering::Relaxed);
+        self.lost.store(snapshot.lost, Ordering::Relaxed);
+        self.duplicates.store(snapshot.duplicates, Ordering::Relaxed);
+        self.out_of_order.store(snapshot.out_of_order, Ordering::Relaxed);
+        self.queued_delivered.store(snapshot.queued_delivered, Ordering::Relaxed);
+        self.queued_lost.store(snapshot.queued_lost, Ordering::Relaxed);
+        self.sessions_lost.store(snapshot.sessions_lost, Ordering::Relaxed);
+        self.pubacks.store(snapshot.pubacks, Ordering::Relaxed);
+        self.pubrecs.store(snapshot.pubrecs, Ordering::Relaxed);
+        self.pubcomps.store(snapshot.pubcomps, Ordering::Relaxed);
+        self.max_inflight.store(snapshot.max_inflight, Ordering::Relaxed);
+        self.connection_errors.store(snapshot.connection_errors, Ordering::Relaxed);
+        self.reconnects.store(snapshot.reconnects, Ordering::Relaxed);
+        self.publish_errors.store(snapshot.publish_errors, Ordering::Relaxed);
+        if let Ok(mut codes) = self.reason_codes.lock() {
+            *codes = snapshot.reason_codes.iter().map(|(code, count)| (code.clone(), *count)).collect();
+        }
+        self.alias_reuses.store(snapshot.alias_reuses, Ordering::Relaxed);
+        self.alias_assignments.store(snapshot.alias_assignments, Ordering::Relaxed);
+        self.alias_topic_bytes.store(snapshot.alias_topic_bytes, Ordering::Relaxed);
+        if let Some(histograms) = &snapshot.histograms {
+            restore_histogram(&self.latency, &histograms.latency);
+            restore_histogram(&self.corrected_latency, &histograms.corrected_latency);
+            restore_histogram(&self.schedule_lag, &histograms.schedule_lag);
+            restore_histogram(&self.connect_latency, &histograms.connect_latency);
+            restore_histogram(&self.ping_rtt, &histograms.ping_rtt);
+        }
+    }
 }
 
 pub struct GlobalMetrics {
@@ -170,7 +744,7 @@ pub struct GlobalMetrics {
 impl GlobalMetrics {
     pub fn new(num_clients: usize) -> Self {
         let clients = (0..num_clients)
-            .map(|i| ClientMetrics::new(i))
+            .map(ClientMetrics::new)
             .collect();
 
         GlobalMetrics { clients }
@@ -204,4 +778,155 @@ impl GlobalMetrics {
     pub fn get_connected_count(&self) -> usize {
         self.clients.iter().filter(|c| c.is_connected()).count()
     }
+
+    #[allow(dead_code)]
+    pub fn get_total_lost(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_lost()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_duplicates(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_duplicates()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_out_of_order(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_out_of_order()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_queued_delivered(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_queued_delivered()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_queued_lost(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_queued_lost()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_sessions_lost(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_sessions_lost()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_pubacks(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_pubacks()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_pubrecs(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_pubrecs()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_pubcomps(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_pubcomps()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_inflight(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_inflight()).sum()
+    }
+
+    /// Largest in-flight count any single client reached
+    #[allow(dead_code)]
+    pub fn get_max_inflight(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_max_inflight()).max().unwrap_or(0)
+    }
+
+    pub fn get_total_connection_errors(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_connection_errors()).sum()
+    }
+
+    pub fn get_total_reconnects(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_reconnects()).sum()
+    }
+
+    /// Reason code counts summed over all clients
+    pub fn get_reason_codes(&self) -> BTreeMap<String, u64> {
+        let mut total = BTreeMap::new();
+        for client in &self.clients {
+            for (code, count) in client.get_reason_codes() {
+                *total.entry(code).or_insert(0) += count;
+            }
+        }
+        total
+    }
+
+    pub fn get_total_published_bytes(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_published_bytes()).sum()
+    }
+
+    pub fn get_total_received_bytes(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_received_bytes()).sum()
+    }
+
+    pub fn get_total_alias_reuses(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_alias_reuses()).sum()
+    }
+
+    pub fn get_total_alias_assignments(&self) -> u64 {
+        self.clients.iter().map(|c| c.get_alias_assignments()).sum()
+    }
+
+    pub fn get_total_alias_bytes_saved(&self) -> i64 {
+        self.clients.iter().map(|c| c.get_alias_bytes_saved()).sum()
+    }
+
+    /// Latency percentiles across all clients (histograms are merged, not averaged)
+    #[allow(dead_code)]
+    pub fn get_latency(&self) -> LatencySnapshot {
+        self.merged(|c| &c.latency)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_corrected_latency(&self) -> LatencySnapshot {
+        self.merged(|c| &c.corrected_latency)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_schedule_lag(&self) -> LatencySnapshot {
+        self.merged(|c| &c.schedule_lag)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_connect_latency(&self) -> LatencySnapshot {
+        self.merged(|c| &c.connect_latency)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_ping_rtt(&self) -> LatencySnapshot {
+        self.merged(|c| &c.ping_rtt)
+    }
+
+    fn merged(&self, histogram: fn(&ClientMetrics) -> &Arc<Mutex<Histogram<u64>>>) -> LatencySnapshot {
+        let mut merged = new_latency_histogram();
+        for client in &self.clients {
+            if let Ok(hist) = histogram(client).lock() {
+                let _ = merged.add(&*hist);
+            }
+        }
+        LatencySnapshot::from_histogram(&merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscriber_qos2_flow() {
+        let metrics = ClientMetrics::new(0);
+        // Two QoS 2 messages in: a PUBREC goes out for each, a PUBCOMP once its PUBREL arrives
+        for event in [ClientEvent::PubRecSent, ClientEvent::PubRecSent] {
+            metrics.record_ack_flow(&event);
+        }
+        assert_eq!((metrics.get_pubrecs(), metrics.get_inflight(), metrics.get_max_inflight()), (2, 2, 2));
+        for event in [ClientEvent::PubCompSent, ClientEvent::PubCompSent] {
+            metrics.record_ack_flow(&event);
+        }
+        assert_eq!((metrics.get_pubrecs(), metrics.get_pubcomps(), metrics.get_inflight()), (2, 2, 0));
+        assert_eq!(metrics.get_pubacks(), 0);
+    }
 }
@@ -0,0 +1,477 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+
+#[allow(dead_code)]
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Marks a binary payload that starts with a [`PayloadHeader`]
+const BINARY_MAGIC: &[u8; 4] = b"MQTL";
+
+/// magic + timestamp + intended timestamp (µs, i64) + run id (8 ASCII bytes) + sequence (u64),
+/// all big endian
+pub const BINARY_HEADER_LEN: usize = 4 + 8 + 8 + 8 + 8;
+
+/// Random bytes/characters generated once and sliced per message
+#[allow(dead_code)]
+const POOL_LEN: usize = 64 * 1024;
+
+/// What the producers send
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PayloadKind {
+    /// The small `{"ts","counter","value","run","seq"}` object
+    Json,
+    /// The JSON object plus a `pad` string that brings it to the sampled size
+    PaddedJson,
+    /// Random bytes of the sampled size, starting with a binary header
+    Random,
+    /// The contents of a file, sent unchanged
+    File { path: String },
+    /// Text with `{topic}`, `{producer}`, `{seq}`, `{counter}`, `{run}` and `{ts}` placeholders
+    Template { template: String },
+}
+
+/// Payload size in bytes, sampled per message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SizeDistribution {
+    Fixed { bytes: usize },
+    /// Uniform between `min` and `max`, inclusive
+    Uniform { min: usize, max: usize },
+    /// Normal distribution, clamped to 0..=mean+6*std_dev
+    Normal { mean: f64, std_dev: f64 },
+    /// One of the listed sizes, picked uniformly
+    List { sizes: Vec<usize> },
+}
+
+#[allow(dead_code)]
+impl SizeDistribution {
+    /// Parse "1024", "1k-64k" (uniform), "normal:512:64" or "64,256,1k" (list)
+    pub fn parse(spec: &str) -> Result<SizeDistribution, String> {
+        if let Some(params) = spec.strip_prefix("normal:") {
+            let (mean, std_dev) = params
+                .split_once(':')
+                .ok_or_else(|| format!("expected normal:MEAN:STD_DEV, got '{}'", spec))?;
+            return Ok(SizeDistribution::Normal {
+                mean: parse_bytes(mean)? as f64,
+                std_dev: parse_bytes(std_dev)? as f64,
+            });
+        }
+        if spec.contains(',') {
+            let sizes = spec.split(',').map(parse_bytes).collect::<Result<Vec<_>, _>>()?;
+            return Ok(SizeDistribution::List { sizes });
+        }
+        if let Some((min, max)) = spec.split_once('-') {
+            let (min, max) = (parse_bytes(min)?, parse_bytes(max)?);
+            if min > max {
+                return Err(format!("minimum size is larger than maximum in '{}'", spec));
+            }
+            return Ok(SizeDistribution::Uniform { min, max });
+        }
+        Ok(SizeDistribution::Fixed { bytes: parse_bytes(spec)? })
+    }
+
+    pub fn sample(&self) -> usize {
+        match self {
+            SizeDistribution::Fixed { bytes } => *bytes,
+            SizeDistribution::Uniform { min, max } => fastrand::usize(*min..=*max),
+            SizeDistribution::Normal { mean, std_dev } => {
+                // Box-Muller, 1 - f64() keeps the log argument above zero
+                let u1 = 1.0 - fastrand::f64();
+                let u2 = fastrand::f64();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                (mean + z * std_dev).round().clamp(0.0, self.max() as f64) as usize
+            }
+            SizeDistribution::List { sizes } => sizes.get(fastrand::usize(..sizes.len().max(1))).copied().unwrap_or(0),
+        }
+    }
+
+    /// Largest size `sample` can return
+    pub fn max(&self) -> usize {
+        match self {
+            SizeDistribution::Fixed { bytes } => *bytes,
+            SizeDistribution::Uniform { max, .. } => *max,
+            SizeDistribution::Normal { mean, std_dev } => (mean + 6.0 * std_dev).max(0.0) as usize,
+            SizeDistribution::List { sizes } => sizes.iter().copied().max().unwrap_or(0),
+        }
+    }
+}
+
+/// Parse a byte count with an optional k/m suffix (powers of 1024)
+#[allow(dead_code)]
+fn parse_bytes(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let lower = s.to_ascii_lowercase();
+    let (number, multiplier) = if let Some(n) = lower.strip_suffix('k') {
+        (n, 1024)
+    } else if let Some(n) = lower.strip_suffix('m') {
+        (n, 1024 * 1024)
+    } else {
+        (lower.as_str(), 1)
+    };
+    number
+        .parse::<usize>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("invalid byte count '{}'", s))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayloadConfig {
+    #[serde(flatten)]
+    pub kind: PayloadKind,
+    /// Used by `padded_json` and `random`, other kinds have their own size
+    #[serde(default = "default_size")]
+    pub size: SizeDistribution,
+}
+
+fn default_size() -> SizeDistribution {
+    SizeDistribution::Fixed { bytes: 1024 }
+}
+
+impl Default for PayloadConfig {
+    fn default() -> Self {
+        PayloadConfig { kind: PayloadKind::Json, size: default_size() }
+    }
+}
+
+#[allow(dead_code)]
+impl PayloadConfig {
+    /// Upper bound of the payload size, used to size the packet limit
+    pub fn max_size(&self) -> Result<usize, BoxError> {
+        Ok(match &self.kind {
+            PayloadKind::Json => 256,
+            PayloadKind::PaddedJson | PayloadKind::Random => self.size.max(),
+            PayloadKind::File { path } => fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len() as usize,
+            // Placeholders expand to at most a topic (65535 bytes) each
+            PayloadKind::Template { template } => template.len() + template.matches('{').count() * 65_535,
+        })
+    }
+}
+
+/// Per-message values a payload can carry
+#[allow(dead_code)]
+pub struct MessageContext<'a> {
+    pub topic: &'a str,
+    pub producer: usize,
+    pub counter: u64,
+    pub run: &'a str,
+    pub seq: u64,
+    pub ts: DateTime<Utc>,
+    /// When the message was due under a target rate (open-loop schedule)
+    pub intended_ts: Option<DateTime<Utc>>,
+}
+
+#[allow(dead_code)]
+enum Segment {
+    Literal(String),
+    Topic,
+    Producer,
+    Seq,
+    Counter,
+    Run,
+    Ts,
+}
+
+#[allow(dead_code)]
+fn parse_template(template: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        literal.push_str(&rest[..start]);
+        let after = &rest[start..];
+        let placeholder = after.find('}').map(|end| (&after[1..end], end));
+        let segment = match placeholder.map(|(name, _)| name) {
+            Some("topic") => Some(Segment::Topic),
+            Some("producer") => Some(Segment::Producer),
+            Some("seq") => Some(Segment::Seq),
+            Some("counter") => Some(Segment::Counter),
+            Some("run") => Some(Segment::Run),
+            Some("ts") => Some(Segment::Ts),
+            _ => None,
+        };
+        match (segment, placeholder) {
+            (Some(segment), Some((_, end))) => {
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(segment);
+                rest = &after[end + 1..];
+            }
+            // Unknown placeholders and lone braces (e.g. JSON) are kept as they are
+            _ => {
+                literal.push('{');
+                rest = &after[1..];
+            }
+        }
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    segments
+}
+
+/// Builds message payloads for one producer
+#[allow(dead_code)]
+pub struct PayloadGenerator {
+    config: PayloadConfig,
+    pool: Bytes,
+    file: Bytes,
+    template: Vec<Segment>,
+}
+
+#[allow(dead_code)]
+impl PayloadGenerator {
+    /// Reads the payload file, if any, so call it once per producer
+    pub fn new(config: &PayloadConfig) -> Result<PayloadGenerator, BoxError> {
+        let pool = match config.kind {
+            PayloadKind::Random => (0..POOL_LEN).map(|_| fastrand::u8(..)).collect(),
+            PayloadKind::PaddedJson => (0..POOL_LEN).map(|_| fastrand::alphanumeric() as u8).collect(),
+            _ => Vec::new(),
+        };
+        let file = match &config.kind {
+            PayloadKind::File { path } => fs::read(path).map_err(|e| format!("{}: {}", path, e))?,
+            _ => Vec::new(),
+        };
+        let template = match &config.kind {
+            PayloadKind::Template { template } => parse_template(template),
+            _ => Vec::new(),
+        };
+        Ok(PayloadGenerator { config: config.clone(), pool: Bytes::from(pool), file: Bytes::from(file), template })
+    }
+
+    /// Append `len` bytes from the random pool, starting at a random offset
+    fn fill_from_pool(&self, out: &mut BytesMut, mut len: usize) {
+        let mut offset = fastrand::usize(..self.pool.len());
+        while len > 0 {
+            let chunk = len.min(self.pool.len() - offset);
+            out.put_slice(&self.pool[offset..offset + chunk]);
+            len -= chunk;
+            offset = 0;
+        }
+    }
+
+    fn json(&self, ctx: &MessageContext) -> serde_json::Value {
+        let mut object = json!({
+            "ts": ctx.ts.to_rfc3339(),
+            "counter": ctx.counter,
+            "value": fastrand::f64(),
+            "run": ctx.run,
+            "seq": ctx.seq,
+        });
+        if let Some(intended) = ctx.intended_ts {
+            object["its"] = json!(intended.to_rfc3339());
+        }
+        object
+    }
+
+    pub fn generate(&self, ctx: &MessageContext) -> Bytes {
+        match &self.config.kind {
+            PayloadKind::Json => Bytes::from(self.json(ctx).to_string()),
+            PayloadKind::PaddedJson => {
+                const PAD_FIELD: &[u8] = b",\"pad\":\"";
+                let base = self.json(ctx).to_string();
+                let target = self.config.size.sample();
+                // Closing quote and brace follow the padding
+                let overhead = PAD_FIELD.len() + 1;
+                if base.len() + overhead >= target {
+                    return Bytes::from(base);
+                }
+                let mut out = BytesMut::with_capacity(target);
+                out.put_slice(&base.as_bytes()[..base.len() - 1]);
+                out.put_slice(PAD_FIELD);
+                self.fill_from_pool(&mut out, target - base.len() - overhead);
+                out.put_slice(b"\"}");
+                out.freeze()
+            }
+            PayloadKind::Random => {
+                let size = self.config.size.sample();
+                let mut out = BytesMut::with_capacity(size);
+                if size >= BINARY_HEADER_LEN {
+                    out.put_slice(BINARY_MAGIC);
+                    out.put_i64(ctx.ts.timestamp_micros());
+                    out.put_i64(ctx.intended_ts.unwrap_or(ctx.ts).timestamp_micros());
+                    let mut run = [b'0'; 8];
+                    for (dst, src) in run.iter_mut().zip(ctx.run.bytes()) {
+                        *dst = src;
+                    }
+                    out.put_slice(&run);
+                    out.put_u64(ctx.seq);
+                }
+                let remaining = size - out.len();
+                self.fill_from_pool(&mut out, remaining);
+                out.freeze()
+            }
+            PayloadKind::File { .. } => self.file.clone(),
+            PayloadKind::Template { .. } => {
+                let mut out = String::new();
+                for segment in &self.template {
+                    match segment {
+                        Segment::Literal(text) => out.push_str(text),
+                        Segment::Topic => out.push_str(ctx.topic),
+                        Segment::Producer => out.push_str(&ctx.producer.to_string()),
+                        Segment::Seq => out.push_str(&ctx.seq.to_string()),
+                        Segment::Counter => out.push_str(&ctx.counter.to_string()),
+                        Segment::Run => out.push_str(ctx.run),
+                        Segment::Ts => out.push_str(&ctx.ts.to_rfc3339()),
+                    }
+                }
+                Bytes::from(out)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct JsonHeader {
+    ts: Option<String>,
+    its: Option<String>,
+    run: Option<String>,
+    seq: Option<u64>,
+}
+
+/// Fields of a received payload the subscriber looks at, everything else is ignored
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct PayloadHeader {
+    pub sent_at: Option<DateTime<Utc>>,
+    /// When the message was due, set when the producer ran with a target rate
+    pub intended_at: Option<DateTime<Utc>>,
+    pub run: Option<String>,
+    pub seq: Option<u64>,
+}
+
+impl PayloadHeader {
+    /// Reads the binary header of `random` payloads or the `ts`/`run`/`seq` fields of
+    /// JSON payloads. Returns an empty header for anything else.
+    #[allow(dead_code)]
+    pub fn parse(payload: &[u8]) -> Self {
+        if payload.len() >= BINARY_HEADER_LEN && payload.starts_with(BINARY_MAGIC) {
+            let micros = i64::from_be_bytes(payload[4..12].try_into().expect("8 bytes"));
+            let intended_micros = i64::from_be_bytes(payload[12..20].try_into().expect("8 bytes"));
+            let seq = u64::from_be_bytes(payload[28..36].try_into().expect("8 bytes"));
+            return PayloadHeader {
+                sent_at: Utc.timestamp_micros(micros).single(),
+                intended_at: Utc.timestamp_micros(intended_micros).single(),
+                run: Some(String::from_utf8_lossy(&payload[20..28]).into_owned()),
+                seq: Some(seq),
+            };
+        }
+        let header: JsonHeader = serde_json::from_slice(payload).unwrap_or_default();
+        let parse_ts = |ts: Option<String>| {
+            ts.and_then(|ts| DateTime::parse_from_rfc3339(&ts).ok())
+                .map(|ts| ts.with_timezone(&Utc))
+        };
+        PayloadHeader {
+            sent_at: parse_ts(header.ts),
+            intended_at: parse_ts(header.its),
+            run: header.run,
+            seq: header.seq,
+        }
+    }
+
+    /// Publish-to-receive latency in microseconds
+    #[allow(dead_code)]
+    pub fn latency_us(&self, received_at: DateTime<Utc>) -> Option<u64> {
+        Some(micros_between(self.sent_at?, received_at))
+    }
+
+    /// Latency measured from when the message was due instead of when it was sent, so
+    /// time a late producer spent catching up counts (coordinated omission correction)
+    #[allow(dead_code)]
+    pub fn corrected_latency_us(&self, received_at: DateTime<Utc>) -> Option<u64> {
+        Some(micros_between(self.intended_at?, received_at))
+    }
+}
+
+fn micros_between(from: DateTime<Utc>, to: DateTime<Utc>) -> u64 {
+    // Clock skew between hosts can make this negative, count it as zero
+    to.signed_duration_since(from).num_microseconds().unwrap_or(i64::MAX).max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(ts: DateTime<Utc>) -> MessageContext<'static> {
+        MessageContext { topic: "test/1/2", producer: 3, counter: 9, run: "ab12cd34", seq: 7, ts, intended_ts: None }
+    }
+
+    #[test]
+    fn test_parse_size_specs() {
+        assert_eq!(SizeDistribution::parse("1k"), Ok(SizeDistribution::Fixed { bytes: 1024 }));
+        assert_eq!(SizeDistribution::parse("10-20"), Ok(SizeDistribution::Uniform { min: 10, max: 20 }));
+        assert_eq!(SizeDistribution::parse("64,1m"), Ok(SizeDistribution::List { sizes: vec![64, 1024 * 1024] }));
+        assert_eq!(SizeDistribution::parse("normal:512:64"), Ok(SizeDistribution::Normal { mean: 512.0, std_dev: 64.0 }));
+        assert!(SizeDistribution::parse("20-10").is_err());
+        assert!(SizeDistribution::parse("big").is_err());
+    }
+
+    #[test]
+    fn test_sizes_stay_in_range() {
+        let normal = SizeDistribution::Normal { mean: 100.0, std_dev: 50.0 };
+        let uniform = SizeDistribution::Uniform { min: 10, max: 20 };
+        for _ in 0..1000 {
+            assert!(normal.sample() <= normal.max());
+            assert!((10..=20).contains(&uniform.sample()));
+        }
+    }
+
+    #[test]
+    fn test_header_from_json_payload() {
+        let received_at = DateTime::parse_from_rfc3339("2024-10-21T17:52:00.250Z").unwrap().with_timezone(&Utc);
+        let payload = br#"{"ts":"2024-10-21T17:52:00.000Z","counter":1,"value":0.5,"run":"ab12cd34","seq":7}"#;
+        let header = PayloadHeader::parse(payload);
+        assert_eq!(header.latency_us(received_at), Some(250_000));
+        assert_eq!(header.run.as_deref(), Some("ab12cd34"));
+        assert_eq!(header.seq, Some(7));
+    }
+
+    #[test]
+    fn test_header_ignores_foreign_payloads() {
+        let received_at = Utc::now();
+        assert_eq!(PayloadHeader::parse(b"not json").latency_us(received_at), None);
+        assert_eq!(PayloadHeader::parse(br#"{"value":1}"#).latency_us(received_at), None);
+        assert_eq!(PayloadHeader::parse(br#"{"value":1}"#).seq, None);
+    }
+
+    #[test]
+    fn test_random_payload_round_trip() {
+        let ts = Utc.timestamp_micros(1_700_000_000_123_456).unwrap();
+        let intended = Utc.timestamp_micros(1_700_000_000_023_456).unwrap();
+        let config = PayloadConfig { kind: PayloadKind::Random, size: SizeDistribution::Fixed { bytes: 200 } };
+        let ctx = MessageContext { intended_ts: Some(intended), ..context(ts) };
+        let payload = PayloadGenerator::new(&config).unwrap().generate(&ctx);
+        assert_eq!(payload.len(), 200);
+        let header = PayloadHeader::parse(&payload);
+        assert_eq!(header.sent_at, Some(ts));
+        assert_eq!(header.corrected_latency_us(ts), Some(100_000));
+        assert_eq!(header.run.as_deref(), Some("ab12cd34"));
+        assert_eq!(header.seq, Some(7));
+    }
+
+    #[test]
+    fn test_padded_json_has_target_size() {
+        let config = PayloadConfig { kind: PayloadKind::PaddedJson, size: SizeDistribution::Fixed { bytes: 100_000 } };
+        let payload = PayloadGenerator::new(&config).unwrap().generate(&context(Utc::now()));
+        assert_eq!(payload.len(), 100_000);
+        let header = PayloadHeader::parse(&payload);
+        assert_eq!(header.seq, Some(7));
+        assert!(header.sent_at.is_some());
+    }
+
+    #[test]
+    fn test_template_placeholders() {
+        let ts = DateTime::parse_from_rfc3339("2024-10-21T17:52:00Z").unwrap().with_timezone(&Utc);
+        let config = PayloadConfig {
+            kind: PayloadKind::Template { template: r#"{"t":"{topic}","p":{producer},"s":{seq},"x":"{unknown}"}"#.to_string() },
+            size: default_size(),
+        };
+        let payload = PayloadGenerator::new(&config).unwrap().generate(&context(ts));
+        assert_eq!(&payload[..], br#"{"t":"test/1/2","p":3,"s":7,"x":"{unknown}"}"#);
+    }
+}
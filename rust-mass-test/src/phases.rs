@@ -0,0 +1,344 @@
+use crate::config::Config;
+use crate::metrics::GlobalMetrics;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// How often the driver updates the target rate (and how smooth ramps are)
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One entry of `phases` in the config. Rates are messages per second and,
+/// like `target_rate`, split between producers unless `target_rate_per_producer` is set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Phase {
+    /// Change the rate linearly from `from` to `to`
+    Ramp { from: f64, to: f64, secs: u64 },
+    /// `steps` equal steps from `from` to `to`, each held for `step_secs`
+    Step { from: f64, to: f64, steps: u32, step_secs: u64 },
+    /// Constant rate, a long hold is a soak test
+    Hold { rate: f64, secs: u64 },
+    /// Constant rate, usually short and well above the surrounding phases
+    Spike { rate: f64, secs: u64 },
+    /// No publishing, connections stay open
+    Idle { secs: u64 },
+}
+
+#[allow(dead_code)]
+impl Phase {
+    /// Parse "ramp:FROM:TO:SECS", "step:FROM:TO:STEPS:STEP_SECS", "hold:RATE:SECS",
+    /// "spike:RATE:SECS" or "idle:SECS"
+    pub fn parse(spec: &str) -> Result<Phase, String> {
+        let mut parts = spec.split(':');
+        let kind = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let rate = |i: usize| -> Result<f64, String> {
+            args[i].parse::<f64>().map_err(|_| format!("invalid rate '{}' in '{}'", args[i], spec))
+        };
+        let number = |i: usize| -> Result<u64, String> {
+            args[i].parse::<u64>().map_err(|_| format!("invalid number '{}' in '{}'", args[i], spec))
+        };
+        let expect = |count: usize, usage: &str| -> Result<(), String> {
+            if args.len() == count {
+                Ok(())
+            } else {
+                Err(format!("expected {}, got '{}'", usage, spec))
+            }
+        };
+
+        let phase = match kind {
+            "ramp" => {
+                expect(3, "ramp:FROM:TO:SECS")?;
+                Phase::Ramp { from: rate(0)?, to: rate(1)?, secs: number(2)? }
+            }
+            "step" => {
+                expect(4, "step:FROM:TO:STEPS:STEP_SECS")?;
+                Phase::Step { from: rate(0)?, to: rate(1)?, steps: number(2)? as u32, step_secs: number(3)? }
+            }
+            "hold" => {
+                expect(2, "hold:RATE:SECS")?;
+                Phase::Hold { rate: rate(0)?, secs: number(1)? }
+            }
+            "spike" => {
+                expect(2, "spike:RATE:SECS")?;
+                Phase::Spike { rate: rate(0)?, secs: number(1)? }
+            }
+            "idle" => {
+                expect(1, "idle:SECS")?;
+                Phase::Idle { secs: number(0)? }
+            }
+            _ => return Err(format!("unknown phase '{}', expected ramp, step, hold, spike or idle", kind)),
+        };
+        phase.stages()?;
+        Ok(phase)
+    }
+
+    /// The phase as linear stages, a step phase has one stage per step
+    fn stages(&self) -> Result<Vec<Stage>, String> {
+        let stage = |kind: &'static str, label: String, from: f64, to: f64, secs: u64| Stage { kind, label, from, to, secs };
+        let stages = match *self {
+            Phase::Ramp { from, to, secs } => vec![stage("ramp", format!("ramp {} → {} msg/s", from, to), from, to, secs)],
+            Phase::Step { from, to, steps, step_secs } => {
+                if steps == 0 {
+                    return Err("a step phase needs at least one step".into());
+                }
+                (0..steps)
+                    .map(|i| {
+                        let rate = if steps == 1 { to } else { from + (to - from) * i as f64 / (steps - 1) as f64 };
+                        stage("step", format!("step {}/{} at {:.0} msg/s", i + 1, steps, rate), rate, rate, step_secs)
+                    })
+                    .collect()
+            }
+            Phase::Hold { rate, secs } => vec![stage("hold", format!("hold {} msg/s", rate), rate, rate, secs)],
+            Phase::Spike { rate, secs } => vec![stage("spike", format!("spike {} msg/s", rate), rate, rate, secs)],
+            Phase::Idle { secs } => vec![stage("idle", "idle".to_string(), 0.0, 0.0, secs)],
+        };
+        for stage in &stages {
+            if stage.secs == 0 {
+                return Err(format!("{} lasts 0 seconds", stage.label));
+            }
+            if !(stage.from >= 0.0 && stage.to >= 0.0) {
+                return Err(format!("{} has a negative rate", stage.label));
+            }
+        }
+        Ok(stages)
+    }
+}
+
+/// A stretch of the profile over which the rate changes linearly
+#[derive(Debug, Clone, PartialEq)]
+struct Stage {
+    kind: &'static str,
+    label: String,
+    from: f64,
+    to: f64,
+    secs: u64,
+}
+
+/// Where the profile is at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfilePoint {
+    pub stage: usize,
+    pub rate: f64,
+}
+
+/// The configured phases flattened into stages
+#[allow(dead_code)]
+pub struct LoadProfile {
+    stages: Vec<Stage>,
+    scale: f64,
+}
+
+#[allow(dead_code)]
+impl LoadProfile {
+    /// `scale` turns configured rates into total rates (the producer count with `target_rate_per_producer`)
+    pub fn new(phases: &[Phase], scale: f64) -> Result<Self, String> {
+        let mut stages = Vec::new();
+        for phase in phases {
+            stages.extend(phase.stages()?);
+        }
+        Ok(LoadProfile { stages, scale })
+    }
+
+    pub fn total_secs(&self) -> u64 {
+        self.stages.iter().map(|s| s.secs).sum()
+    }
+
+    /// Stage and total target rate `elapsed` into the profile, `None` once it is over
+    pub fn at(&self, elapsed: Duration) -> Option<ProfilePoint> {
+        let mut offset = elapsed.as_secs_f64();
+        for (index, stage) in self.stages.iter().enumerate() {
+            let secs = stage.secs as f64;
+            if offset < secs {
+                return Some(ProfilePoint {
+                    stage: index,
+                    rate: (stage.from + (stage.to - stage.from) * offset / secs) * self.scale,
+                });
+            }
+            offset -= secs;
+        }
+        None
+    }
+}
+
+/// How one stage of the profile went, stages cut short by the end of the run are included
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseReport {
+    pub index: usize,
+    pub kind: String,
+    pub label: String,
+    /// Seconds since the profile started
+    pub start_secs: f64,
+    pub duration_secs: f64,
+    /// Intended total rate at the start and end of the stage
+    pub from_rate: f64,
+    pub to_rate: f64,
+    pub published: u64,
+    pub achieved_rate: f64,
+}
+
+#[allow(dead_code)]
+impl PhaseReport {
+    pub fn summary_line(&self) -> String {
+        let intended = (self.from_rate + self.to_rate) / 2.0;
+        format!(
+            "  {:>2}. {:<32} {:>7.1}s  intended {:>10.2} msg/s  achieved {:>10.2} msg/s",
+            self.index, self.label, self.duration_secs, intended, self.achieved_rate
+        )
+    }
+}
+
+/// Hand `rate` to the producers, waking them only when it differs from the last one
+fn set_rate(rate_tx: &watch::Sender<f64>, rate: f64) {
+    rate_tx.send_if_modified(|current| {
+        let changed = *current != rate;
+        *current = rate;
+        changed
+    });
+}
+
+/// Feed the profile's total target rate to the producers until it ends or
+/// shutdown is signalled, announcing every stage as it starts.
+///
+/// The rate drops to 0 once the profile is over. Returns one report per stage reached.
+pub async fn drive(
+    profile: LoadProfile,
+    rate_tx: watch::Sender<f64>,
+    metrics: Arc<Mutex<GlobalMetrics>>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    announce: impl Fn(String) + Send,
+) -> Vec<PhaseReport> {
+    let started = Instant::now();
+    let mut reports: Vec<PhaseReport> = Vec::new();
+    let mut stage_published = 0;
+    let mut ticker = tokio::time::interval(UPDATE_INTERVAL);
+    let count = profile.stages.len();
+
+    // Close the running stage with what was published since it started
+    let finish = |reports: &mut Vec<PhaseReport>, stage_published: u64| {
+        if let Some(report) = reports.last_mut() {
+            report.duration_secs = started.elapsed().as_secs_f64() - report.start_secs;
+            report.published = metrics.lock().unwrap().get_total_published().saturating_sub(stage_published);
+            report.achieved_rate = report.published as f64 / report.duration_secs.max(0.001);
+        }
+    };
+
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.changed() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let point = profile.at(started.elapsed());
+        if point.map(|p| p.stage + 1) != reports.last().map(|r| r.index) {
+            finish(&mut reports, stage_published);
+            let Some(point) = point else {
+                announce("🏁 Load profile complete".to_string());
+                set_rate(&rate_tx, 0.0);
+                return reports;
+            };
+            let stage = &profile.stages[point.stage];
+            announce(format!("▶️  Phase {}/{}: {} for {}s", point.stage + 1, count, stage.label, stage.secs));
+            stage_published = metrics.lock().unwrap().get_total_published();
+            reports.push(PhaseReport {
+                index: point.stage + 1,
+                kind: stage.kind.to_string(),
+                label: stage.label.clone(),
+                start_secs: started.elapsed().as_secs_f64(),
+                duration_secs: 0.0,
+                from_rate: stage.from * profile.scale,
+                to_rate: stage.to * profile.scale,
+                published: 0,
+                achieved_rate: 0.0,
+            });
+        }
+        set_rate(&rate_tx, point.map_or(0.0, |p| p.rate));
+    }
+
+    finish(&mut reports, stage_published);
+    set_rate(&rate_tx, 0.0);
+    reports
+}
+
+/// Task feeding the load profile to the producers, see [`drive`]
+pub type ProfileDriver = JoinHandle<Vec<PhaseReport>>;
+
+/// Rate source for the producers: the load profile when phases are configured,
+/// otherwise the constant target rate, or `None` to keep sleep_ms pacing.
+///
+/// The driver task returns the phase reports once the profile ends or shutdown is signalled.
+#[allow(dead_code)]
+pub fn start(
+    config: &Config,
+    metrics: Arc<Mutex<GlobalMetrics>>,
+    shutdown_rx: watch::Receiver<bool>,
+    announce: impl Fn(String) + Send + 'static,
+) -> Result<(Option<watch::Receiver<f64>>, Option<ProfileDriver>), String> {
+    if config.phases.is_empty() {
+        // Producers keep the last rate once the sender is gone
+        return Ok((config.total_target_rate().map(|rate| watch::channel(rate).1), None));
+    }
+    let profile = LoadProfile::new(&config.phases, config.rate_scale())?;
+    let initial = profile.at(Duration::ZERO).map_or(0.0, |p| p.rate);
+    let (rate_tx, rate_rx) = watch::channel(initial);
+    let driver = tokio::spawn(drive(profile, rate_tx, metrics, shutdown_rx, announce));
+    Ok((Some(rate_rx), Some(driver)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_phases() {
+        assert_eq!(Phase::parse("ramp:0:5000:60"), Ok(Phase::Ramp { from: 0.0, to: 5000.0, secs: 60 }));
+        assert_eq!(
+            Phase::parse("step:1000:4000:4:30"),
+            Ok(Phase::Step { from: 1000.0, to: 4000.0, steps: 4, step_secs: 30 })
+        );
+        assert_eq!(Phase::parse("idle:5"), Ok(Phase::Idle { secs: 5 }));
+        assert!(Phase::parse("hold:100").is_err());
+        assert!(Phase::parse("hold:100:0").is_err());
+        assert!(Phase::parse("spike:-5:10").is_err());
+        assert!(Phase::parse("soak:100:10").is_err());
+    }
+
+    #[test]
+    fn test_profile_scale() {
+        let profile = LoadProfile::new(&[Phase::Hold { rate: 100.0, secs: 1 }], 4.0).unwrap();
+        assert_eq!(profile.at(Duration::ZERO), Some(ProfilePoint { stage: 0, rate: 400.0 }));
+    }
+
+    #[test]
+    fn test_profile_rates() {
+        let profile = LoadProfile::new(&[
+            Phase::Ramp { from: 0.0, to: 1000.0, secs: 10 },
+            Phase::Step { from: 100.0, to: 300.0, steps: 3, step_secs: 5 },
+            Phase::Idle { secs: 2 },
+        ], 1.0)
+        .unwrap();
+        assert_eq!(profile.total_secs(), 27);
+
+        let at = |secs: f64| profile.at(Duration::from_secs_f64(secs));
+        assert_eq!(at(5.0), Some(ProfilePoint { stage: 0, rate: 500.0 }));
+        assert_eq!(at(10.0), Some(ProfilePoint { stage: 1, rate: 100.0 }));
+        assert_eq!(at(21.0), Some(ProfilePoint { stage: 3, rate: 300.0 }));
+        assert_eq!(at(26.0), Some(ProfilePoint { stage: 4, rate: 0.0 }));
+        assert_eq!(at(27.0), None);
+    }
+
+    #[test]
+    fn test_set_rate_notifies_on_change() {
+        let (rate_tx, mut rate_rx) = watch::channel(100.0);
+        set_rate(&rate_tx, 100.0);
+        assert!(!rate_rx.has_changed().unwrap());
+        set_rate(&rate_tx, 250.0);
+        assert!(rate_rx.has_changed().unwrap());
+        assert_eq!(*rate_rx.borrow_and_update(), 250.0);
+        set_rate(&rate_tx, 250.0);
+        assert!(!rate_rx.has_changed().unwrap());
+    }
+}
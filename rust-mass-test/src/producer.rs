@@ -1,15 +1,21 @@
+use crate::alias::{AliasUse, TopicAliases};
 use crate::config::Config;
+use crate::connection::{self, ClientEvent};
 use crate::metrics::ClientMetrics;
+use crate::payload::{MessageContext, PayloadGenerator};
+use crate::rate::{self, RateSchedule};
+use crate::selection::{TopicSelection, TopicSequencer};
 use crate::topic::TopicGenerator;
 use crate::ui::LogBuffer;
-use bytes::Bytes;
 use chrono::Utc;
-use rumqttc::{AsyncClient, Event, MqttOptions, QoS};
-use serde_json::json;
+use rumqttc::QoS;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::{time, sync::watch};
-use uuid::Uuid;
+use tokio::time::Instant;
+
+/// How often a paused producer with a target rate checks whether it was resumed
+const PAUSE_CHECK: Duration = Duration::from_millis(100);
 
 pub async fn run_producer(
     producer_id: usize,
@@ -17,9 +23,54 @@ pub async fn run_producer(
     metrics: Arc<ClientMetrics>,
     mut shutdown_rx: watch::Receiver<bool>,
     mut pause_rx: watch::Receiver<bool>,
+    mut rate_rx: Option<watch::Receiver<f64>>,
     log_buffer: LogBuffer,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client_id = format!("pub-{}", Uuid::new_v4().to_string());
+    let client_id = connection::client_id(&config, "pub", producer_id + 1);
+
+    // Topics of this producer, built on demand so large trees take no memory
+    let topics = TopicGenerator::from_config(&config, producer_id + 1)?.into_topics(config.use_leafs);
+    let qos = match config.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+
+    // Picks the topic of every message, round-robin unless the selection is skewed,
+    // and numbers the messages per topic within the run, kept across reconnects
+    let mut sequencer = TopicSequencer::new(&config.topic_selection, topics.len())?;
+    if config.topic_selection != TopicSelection::RoundRobin {
+        log_buffer.log(format!("Producer {}: Topic selection {:?}", producer_id + 1, config.topic_selection));
+    }
+
+    // Set after the first CONNACK so later ones count as reconnects
+    let mut has_connected = false;
+
+    // Open-loop schedule when a total target rate is sent (constant or from the load
+    // profile), kept across reconnects so messages missed while disconnected show up as lag
+    let mut schedule = rate_rx
+        .as_ref()
+        .map(|rx| RateSchedule::new(rate::producer_rate(*rx.borrow(), config.publishers())));
+
+    // Alias bindings of the current connection, sized from each CONNACK
+    let mut aliases = TopicAliases::new(0, config.topic_alias_churn);
+    if config.topic_alias_max > 0 && config.protocol_version != 5 {
+        log_buffer.log(format!("Producer {}: ⚠️  Topic aliases need protocol_version 5, publishing full topics", producer_id + 1));
+    }
+
+    let payloads = PayloadGenerator::new(&config.payload)?;
+
+    // Create MQTT connection options (reused for reconnections)
+    let mut mqttoptions = connection::mqtt_options(&config, client_id, producer_id + 1)?;
+    // Leave room for the topic and properties next to the largest payload
+    mqttoptions.set_max_packet_size((config.payload.max_size()? + 64 * 1024).max(100 * 1024));
+    mqttoptions.set_inflight(10); // Small buffer to avoid overwhelming broker and ensure sends
+
+    // Staggered start when a connect rate is set
+    tokio::select! {
+        _ = shutdown_rx.changed() => return Ok(()),
+        _ = time::sleep(connection::connect_delay(&config, producer_id)) => {}
+    }
 
     // Outer loop for reconnection attempts
     loop {
@@ -29,18 +80,9 @@ pub async fn run_producer(
             return Ok(());
         }
 
-        // Create MQTT connection options
-        let mut mqttoptions = MqttOptions::new(
-            client_id.clone(),
-            config.broker_host.clone(),
-            config.broker_port,
-        );
-        mqttoptions.set_keep_alive(Duration::from_secs(120));
-        mqttoptions.set_max_packet_size(100 * 1024, 100 * 1024);
-        mqttoptions.set_inflight(10); // Small buffer to avoid overwhelming broker and ensure sends
-
         // Create client and connection
-        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+        let connect_started = Instant::now();
+        let (client, mut eventloop) = mqttoptions.connect(10);
 
         log_buffer.log(format!("Producer {}: Waiting for connection to broker...", producer_id + 1));
 
@@ -55,18 +97,41 @@ pub async fn run_producer(
                 }
                 event = eventloop.poll() => {
                     match event {
-                        Ok(Event::Incoming(rumqttc::Packet::ConnAck(_ack))) => {
+                        Ok(ClientEvent::ConnAck { topic_alias_max, .. }) => {
                             log_buffer.log(format!("Producer {}: ✅ Connected to broker", producer_id + 1));
+                            metrics.record_connect_latency(connect_started.elapsed().as_micros() as u64);
+                            aliases.reset(config.topic_alias_max.min(topic_alias_max));
+                            // Unacknowledged publishes of the last connection are sent again
+                            metrics.clear_inflight();
+                            if config.topic_alias_max > 0 && config.protocol_version == 5 {
+                                log_buffer.log(format!(
+                                    "Producer {}: Using {} topic aliases for {} topics (broker maximum {})",
+                                    producer_id + 1,
+                                    config.topic_alias_max.min(topic_alias_max),
+                                    topics.len(),
+                                    topic_alias_max
+                                ));
+                            }
+                            if has_connected {
+                                metrics.increment_reconnects();
+                            } else if let Some(schedule) = schedule.as_mut() {
+                                // Connecting is not part of the schedule
+                                schedule.restart_at(Instant::now());
+                            }
+                            has_connected = true;
                             metrics.set_connected(true);
                             let is_connected = metrics.is_connected();
                             log_buffer.log(format!("Producer {}: [DEBUG] Connected flag set: {}", producer_id + 1, is_connected));
                             connected = true;
                             break;
                         }
-                        Ok(Event::Incoming(_)) => {},
-                        Ok(Event::Outgoing(_)) => {},
+                        Ok(_) => {},
                         Err(e) => {
                             log_buffer.log(format!("Producer {}: ❌ Connection error: {:?}", producer_id + 1, e));
+                            if let Some(code) = e.reason_code() {
+                                metrics.record_reason_code(&code);
+                            }
+                            metrics.increment_connection_errors();
                             break;
                         }
                     }
@@ -81,41 +146,28 @@ pub async fn run_producer(
             continue;
         }
 
-        // Generate topics for this producer (only once per connection)
-        let topic_gen = TopicGenerator::new(
-            config.topic_prefix.clone(),
-            producer_id + 1,
-            config.topics_per_node,
-            config.max_depth,
-        );
-
-        let topics: Vec<String> = if config.use_leafs {
-            topic_gen.generate_leaves_only()
-        } else {
-            topic_gen.generate_all()
-        };
-        let qos = match config.qos {
-            0 => QoS::AtMostOnce,
-            1 => QoS::AtLeastOnce,
-            _ => QoS::ExactlyOnce,
-        };
-
-        log_buffer.log(format!(
-            "Producer {}: Starting with {} topics, sleep_ms={}",
-            producer_id + 1,
-            topics.len(),
-            config.sleep_ms
-        ));
+        match &schedule {
+            Some(_) => log_buffer.log(format!(
+                "Producer {}: Starting with {} topics, target rate {:.1} msg/s",
+                producer_id + 1,
+                topics.len(),
+                rate::producer_rate(rate_rx.as_ref().map_or(0.0, |rx| *rx.borrow()), config.publishers())
+            )),
+            None => log_buffer.log(format!(
+                "Producer {}: Starting with {} topics, sleep_ms={}",
+                producer_id + 1,
+                topics.len(),
+                config.sleep_ms
+            )),
+        }
 
         // Create a timer for publishing with the configured sleep_ms
         let mut publish_timer = time::interval(Duration::from_millis(config.sleep_ms));
 
-        // Track which topic to publish to
-        let mut topic_index = 0;
-
         // Main publishing loop (inner loop, reconnects on error)
         let mut should_shutdown = false;
         loop {
+            let next_due = schedule.as_ref().map(|s| s.next_at());
             tokio::select! {
                 _ = shutdown_rx.changed() => {
                     log_buffer.log(format!("Producer {}: Received shutdown signal. Disconnecting...", producer_id + 1));
@@ -127,56 +179,107 @@ pub async fn run_producer(
                 _ = pause_rx.changed() => {
                     // Pause state changed, just acknowledge it by continuing the loop
                 }
-                event = eventloop.poll() => {
-                    match event {
-                        Ok(Event::Incoming(rumqttc::Packet::Disconnect)) => {
-                            log_buffer.log(format!("Producer {}: ⚠️  Broker sent DISCONNECT, reconnecting...", producer_id + 1));
-                            metrics.set_connected(false);
-                            break;
-                        }
-                        Ok(Event::Incoming(_)) => {},
-                        Ok(Event::Outgoing(_)) => {},
-                        Err(e) => {
-                            log_buffer.log(format!("Producer {}: ⚠️  Connection error: {:?}, reconnecting...", producer_id + 1, e));
-                            metrics.set_connected(false);
-                            // Break on connection errors to trigger reconnection
-                            break;
+                changed = async {
+                    match rate_rx.as_mut() {
+                        Some(rx) => rx.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match (changed, rate_rx.as_mut(), schedule.as_mut()) {
+                        (Ok(()), Some(rx), Some(schedule)) => {
+                            schedule.set_rate(rate::producer_rate(*rx.borrow_and_update(), config.publishers()));
                         }
+                        // Rate sender is gone, keep the last rate
+                        _ => rate_rx = None,
                     }
                 }
-                _ = publish_timer.tick() => {
-                    // Check if paused before publishing
-                    if *pause_rx.borrow() {
+                event = eventloop.poll() => {
+                    if !handle_event(producer_id, event, &metrics, &log_buffer) {
+                        // Break on connection errors to trigger reconnection
+                        break;
+                    }
+                }
+                _ = async {
+                    match next_due {
+                        Some(due) => time::sleep_until(due).await,
+                        None => {
+                            publish_timer.tick().await;
+                        }
+                    }
+                } => {
+                    // Check if paused (or idle in the load profile) before publishing
+                    if *pause_rx.borrow() || schedule.as_ref().is_some_and(|s| s.is_idle()) {
+                        if let Some(schedule) = schedule.as_mut() {
+                            // Check again shortly, resuming starts a fresh schedule instead of a burst
+                            schedule.restart_at(Instant::now() + PAUSE_CHECK);
+                        }
                         continue; // Skip publishing but keep the timer ticking
                     }
 
-                    // Generate payload
-                    let counter = metrics.get_counter();
-                    let random_value: f64 = fastrand::f64();
-                    let timestamp = Utc::now().to_rfc3339();
-
-                    let payload = json!({
-                        "ts": timestamp,
-                        "counter": counter,
-                        "value": random_value,
+                    // When this message was due, publishing late counts as schedule lag
+                    let now = Utc::now();
+                    let intended_ts = schedule.as_mut().map(|s| {
+                        let lag = Instant::now().saturating_duration_since(s.advance());
+                        metrics.record_schedule_lag(lag.as_micros() as u64);
+                        now - chrono::Duration::from_std(lag).unwrap_or_default()
                     });
 
-                    let payload_bytes = Bytes::from(payload.to_string());
+                    let (slot, seq) = sequencer.next();
+                    let topic = topics.get(slot);
 
-                    let topic = &topics[topic_index % topics.len()];
-                    match client.publish(topic.clone(), qos, config.retained, payload_bytes).await {
+                    // Generate payload
+                    let payload_bytes = payloads.generate(&MessageContext {
+                        topic: &topic,
+                        producer: producer_id + 1,
+                        counter: metrics.get_counter(),
+                        run: sequencer.run(),
+                        seq,
+                        ts: now,
+                        intended_ts,
+                    });
+                    let payload_len = payload_bytes.len() as u64;
+
+                    let alias_use = aliases.lookup(slot);
+                    let (publish_topic, topic_alias) = match alias_use {
+                        AliasUse::None => (topic.clone(), None),
+                        AliasUse::Assign(alias) => (topic.clone(), Some(alias)),
+                        AliasUse::Reuse(alias) => (String::new(), Some(alias)),
+                    };
+                    // Keep polling the event loop while the publish waits for room in the
+                    // request channel, a producer catching up on its schedule fills it quickly
+                    let publish = client.publish(publish_topic, qos, config.retained, payload_bytes, topic_alias);
+                    tokio::pin!(publish);
+                    let result = loop {
+                        tokio::select! {
+                            result = &mut publish => break Some(result),
+                            event = eventloop.poll() => {
+                                if !handle_event(producer_id, event, &metrics, &log_buffer) {
+                                    break None;
+                                }
+                            }
+                        }
+                    };
+                    let Some(result) = result else {
+                        break;
+                    };
+                    match result {
                         Ok(_) => {
+                            sequencer.published(slot, seq);
                             metrics.increment_published();
+                            metrics.add_published_bytes(payload_len);
+                            match alias_use {
+                                AliasUse::None => {}
+                                AliasUse::Assign(_) => metrics.increment_alias_assignments(),
+                                AliasUse::Reuse(_) => metrics.record_alias_reuse(topic.len() as u64),
+                            }
                             // Small yield to let eventloop process the message
                             tokio::task::yield_now().await;
                         }
                         Err(e) => {
                             log_buffer.log(format!("Producer {}: Publish error: {}", producer_id + 1, e));
+                            metrics.increment_publish_errors();
                         }
                     }
-
-                    // Move to next topic for next publish
-                    topic_index += 1;
                 }
             }
         }
@@ -192,3 +295,37 @@ pub async fn run_producer(
 
     Ok(())
 }
+
+/// Handle an event from the broker while connected, false when the connection is gone
+fn handle_event(
+    producer_id: usize,
+    event: Result<ClientEvent, connection::ConnectionError>,
+    metrics: &ClientMetrics,
+    log_buffer: &LogBuffer,
+) -> bool {
+    if let Ok(event) = &event {
+        metrics.record_ack_flow(event);
+    }
+    match event {
+        Ok(ClientEvent::Disconnect) => {
+            log_buffer.log(format!("Producer {}: ⚠️  Broker sent DISCONNECT, reconnecting...", producer_id + 1));
+            metrics.set_connected(false);
+            false
+        }
+        Ok(ClientEvent::PubAck { reason: Some(reason) }) | Ok(ClientEvent::PubRec { reason: Some(reason) }) => {
+            // Only non-failure codes get here (e.g. NoMatchingSubscribers), failures end the connection
+            metrics.record_reason_code(&reason);
+            true
+        }
+        Ok(_) => true,
+        Err(e) => {
+            log_buffer.log(format!("Producer {}: ⚠️  Connection error: {:?}, reconnecting...", producer_id + 1, e));
+            if let Some(code) = e.reason_code() {
+                metrics.record_reason_code(&code);
+            }
+            metrics.increment_connection_errors();
+            metrics.set_connected(false);
+            false
+        }
+    }
+}
@@ -1,12 +1,31 @@
+mod alias;
+mod benchmark;
+mod capture;
 mod config;
+mod connection;
+mod distributed;
+mod exporter;
+mod idle;
 mod metrics;
+mod payload;
+mod phases;
 mod producer;
+mod rate;
+mod retained;
+mod search;
+mod selection;
+mod sequence;
+mod storm;
+mod subscriber;
 mod topic;
 mod ui;
 
+use crate::benchmark::{AckTotals, Report};
 use crate::config::Config;
 use crate::metrics::GlobalMetrics;
+use crate::payload::PayloadKind;
 use crate::ui::{draw_config_screen, LogBuffer, UIContext};
+use chrono::Utc;
 use clap::Parser;
 use crossterm::event::{self, Event, KeyCode};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
@@ -14,7 +33,7 @@ use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
 use std::io;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 
 #[derive(Parser, Debug)]
@@ -29,6 +48,10 @@ struct Args {
     #[arg(long, default_value = "1883")]
     port: u16,
 
+    /// Broker URL (mqtt://, mqtts://, ws://, wss://), overrides --broker, --port and --tls
+    #[arg(long)]
+    url: Option<String>,
+
     /// Configuration file to load (JSON)
     #[arg(long)]
     config: Option<String>,
@@ -36,6 +59,249 @@ struct Args {
     /// Auto-start without UI (use config file)
     #[arg(long)]
     auto_start: bool,
+
+    /// Stop after this many seconds (auto-start only)
+    #[arg(long)]
+    duration: Option<u64>,
+
+    /// Stop after this many messages published in total (auto-start only)
+    #[arg(long)]
+    messages: Option<u64>,
+
+    /// Write a JSON report at the end of the run, "-" for stdout (auto-start only)
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Serve Prometheus metrics on this address (e.g. 0.0.0.0:9100)
+    #[arg(long)]
+    metrics_listen: Option<String>,
+
+    /// Connect to the broker over TLS
+    #[arg(long)]
+    tls: bool,
+
+    /// CA certificate file (PEM) used to verify the broker, implies --tls
+    #[arg(long)]
+    ca_file: Option<String>,
+
+    /// Client certificate file (PEM) for mutual TLS, implies --tls
+    #[arg(long)]
+    client_cert: Option<String>,
+
+    /// Client private key file (PEM) for mutual TLS
+    #[arg(long)]
+    client_key: Option<String>,
+
+    /// ALPN protocol to offer, can be repeated
+    #[arg(long)]
+    alpn: Vec<String>,
+
+    /// With a wss:// URL, send this name as SNI and verify the broker certificate against it
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// Skip broker certificate verification
+    #[arg(long)]
+    insecure: bool,
+
+    /// Username, "{id}" is replaced by the client number (e.g. "user-{id}")
+    #[arg(long)]
+    username: Option<String>,
+
+    /// Password, "{id}" is replaced by the client number
+    #[arg(long)]
+    password: Option<String>,
+
+    /// File with one password per line, client N uses line N
+    #[arg(long)]
+    password_file: Option<String>,
+
+    /// MQTT protocol level: 4 (3.1.1) or 5
+    #[arg(long)]
+    protocol_version: Option<u8>,
+
+    /// MQTT 5 session expiry interval in seconds
+    #[arg(long)]
+    session_expiry: Option<u32>,
+
+    /// Stable client ids, "{role}" becomes pub/sub and "{id}" the client number, e.g. "load-{role}-{id}"
+    #[arg(long)]
+    client_id_template: Option<String>,
+
+    /// Keep the session across reconnects (clean_session=false / clean start off)
+    #[arg(long)]
+    no_clean_session: bool,
+
+    /// MQTT keep-alive interval in seconds (default 120, 0 disables it on MQTT 3.1.1)
+    #[arg(long)]
+    keep_alive: Option<u64>,
+
+    /// Clients started per second instead of all at once
+    #[arg(long)]
+    connect_rate: Option<f64>,
+
+    /// Seconds to wait for all clients to connect
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Topology: one-to-one, fan-out, fan-in or many-to-many[:overlap] (default one-to-one)
+    #[arg(long, value_parser = config::parse_topology)]
+    topology: Option<config::Topology>,
+
+    /// Topic tree shape: uniform, levels:N,N,..., isa95[:SITES,AREAS,LINES,CELLS,DEVICES,METRICS],
+    /// random[:SEED] or file:PATH (default uniform)
+    #[arg(long, value_parser = topic::TopicShape::parse)]
+    topic_shape: Option<topic::TopicShape>,
+
+    /// Maximum topics per producer, taken breadth first; 0 (default) for the whole tree.
+    /// Overrides num_topics from the config file, which caps the tree the same way
+    #[arg(long)]
+    num_topics: Option<usize>,
+
+    /// Topic selection: round-robin, uniform, zipf[:EXPONENT], hot-set[:TOPICS_PCT:TRAFFIC_PCT]
+    /// or bursty[:ON_SECS:OFF_SECS] (default round-robin)
+    #[arg(long, value_parser = selection::TopicSelection::parse)]
+    topic_selection: Option<selection::TopicSelection>,
+
+    /// Connect-storm mode: open this many connections, report CONNACK latency and exit
+    /// (with --duration the connections are held)
+    #[arg(long)]
+    connect_storm: Option<usize>,
+
+    /// Coordinate a distributed run: listen on this address (e.g. 0.0.0.0:7878) for agents
+    #[arg(long)]
+    coordinator: Option<String>,
+
+    /// Number of agents the coordinator waits for
+    #[arg(long, default_value = "1")]
+    agents: usize,
+
+    /// Run as an agent of the coordinator at this address, the configuration comes from it
+    /// except for credentials (--password, --password-file, --client-cert, --client-key)
+    #[arg(long)]
+    agent: Option<String>,
+
+    /// Shared secret agents present to the coordinator, required for --coordinator and --agent
+    #[arg(long)]
+    cluster_token: Option<String>,
+
+    /// Capture the topics seen on the broker into this file for --topic-shape file:PATH and exit
+    #[arg(long)]
+    capture_topics: Option<String>,
+
+    /// Filter subscribed while capturing topics
+    #[arg(long, default_value = "#")]
+    capture_filter: String,
+
+    /// Seconds to capture topics for
+    #[arg(long, default_value = "10")]
+    capture_secs: u64,
+
+    /// Idle mode: hold this many connections that only send keep-alives
+    #[arg(long)]
+    idle_clients: Option<usize>,
+
+    /// Let every idle client subscribe to its own topic
+    #[arg(long)]
+    idle_subscribe: bool,
+
+    /// PID of a broker on this host, idle mode reports its memory per connection
+    #[arg(long)]
+    broker_pid: Option<u32>,
+
+    /// Retained benchmark: publish a retained message to every topic of the producers' trees
+    #[arg(long)]
+    retained_load: bool,
+
+    /// Retained benchmark: time how long this filter takes to deliver its retained messages,
+    /// can be repeated (e.g. "test00001/+/02/#")
+    #[arg(long)]
+    retained_read: Vec<String>,
+
+    /// Retained benchmark: clear every topic with an empty retained payload (after load and reads)
+    #[arg(long)]
+    retained_clear: bool,
+
+    /// Seconds a retained read waits for the next message before giving up
+    #[arg(long)]
+    retained_read_timeout: Option<u64>,
+
+    /// MQTT 5 message expiry interval in seconds
+    #[arg(long)]
+    message_expiry: Option<u32>,
+
+    /// MQTT 5 user property as key=value, can be repeated
+    #[arg(long, value_parser = config::parse_user_property)]
+    user_property: Vec<(String, String)>,
+
+    /// MQTT 5 content type of the published payloads
+    #[arg(long)]
+    content_type: Option<String>,
+
+    /// MQTT 5 topic aliases per producer, capped by the broker's maximum
+    #[arg(long)]
+    topic_alias_max: Option<u16>,
+
+    /// Rebind aliases when a producer has more topics than aliases
+    #[arg(long)]
+    topic_alias_churn: bool,
+
+    /// Target messages per second across all producers, replaces the sleep interval
+    #[arg(long)]
+    rate: Option<f64>,
+
+    /// Apply --rate to every producer instead of splitting it between them
+    #[arg(long)]
+    rate_per_producer: bool,
+
+    /// Load profile phase, repeat in order: ramp:FROM:TO:SECS, step:FROM:TO:STEPS:STEP_SECS,
+    /// hold:RATE:SECS, spike:RATE:SECS or idle:SECS (replaces --rate)
+    #[arg(long = "phase", value_parser = phases::Phase::parse)]
+    phases: Vec<phases::Phase>,
+
+    /// Search for the highest rate that meets the SLO, with subscribers in this process
+    #[arg(long)]
+    search: bool,
+
+    /// Total rate of the first search step
+    #[arg(long)]
+    search_start: Option<f64>,
+
+    /// Rate added after every passing search step
+    #[arg(long)]
+    search_step: Option<f64>,
+
+    /// Seconds per search step
+    #[arg(long)]
+    search_step_secs: Option<u64>,
+
+    /// Stop the search after this rate passes
+    #[arg(long)]
+    search_max: Option<f64>,
+
+    /// SLO for the search: p99 of the corrected latency in milliseconds
+    #[arg(long)]
+    slo_p99_ms: Option<f64>,
+
+    /// SLO for the search: maximum loss in percent
+    #[arg(long)]
+    slo_loss_pct: Option<f64>,
+
+    /// Payload kind: json, padded-json or random
+    #[arg(long)]
+    payload: Option<String>,
+
+    /// Payload size for padded-json/random: 1024, 1k-64k, normal:512:64 or 64,256,1k
+    #[arg(long, value_parser = payload::SizeDistribution::parse)]
+    payload_size: Option<payload::SizeDistribution>,
+
+    /// Send the contents of this file as every payload
+    #[arg(long)]
+    payload_file: Option<String>,
+
+    /// Payload template with {topic}, {producer}, {seq}, {counter}, {run} and {ts} placeholders
+    #[arg(long)]
+    payload_template: Option<String>,
 }
 
 #[tokio::main]
@@ -58,13 +324,197 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Config::load_or_default(config_file);
     config.broker_host = args.broker;
     config.broker_port = args.port;
+    if args.url.is_some() {
+        config.broker_url = args.url;
+    }
+    if args.duration.is_some() {
+        config.duration_secs = args.duration;
+    }
+    if args.messages.is_some() {
+        config.max_messages = args.messages;
+    }
+    if args.report.is_some() {
+        config.report_file = args.report;
+    }
+    if args.metrics_listen.is_some() {
+        config.metrics_listen = args.metrics_listen;
+    }
+    if args.ca_file.is_some() {
+        config.tls_ca_file = args.ca_file;
+    }
+    if args.client_cert.is_some() {
+        config.tls_client_cert = args.client_cert;
+    }
+    if args.client_key.is_some() {
+        config.tls_client_key = args.client_key;
+    }
+    if !args.alpn.is_empty() {
+        config.tls_alpn = args.alpn;
+    }
+    if args.tls_server_name.is_some() {
+        config.tls_server_name = args.tls_server_name;
+    }
+    config.tls_insecure |= args.insecure;
+    if args.username.is_some() {
+        config.username = args.username;
+    }
+    if args.password.is_some() {
+        config.password = args.password;
+    }
+    if args.password_file.is_some() {
+        config.password_file = args.password_file;
+    }
+    if let Some(version) = args.protocol_version {
+        config.protocol_version = version;
+    }
+    if args.session_expiry.is_some() {
+        config.session_expiry_secs = args.session_expiry;
+    }
+    if let Some(secs) = args.keep_alive {
+        config.keep_alive_secs = secs;
+    }
+    if args.client_id_template.is_some() {
+        config.client_id_template = args.client_id_template;
+    }
+    if args.no_clean_session {
+        config.clean_session = false;
+    }
+    if args.connect_rate.is_some() {
+        config.connect_rate = args.connect_rate;
+    }
+    if let Some(secs) = args.connect_timeout {
+        config.connect_timeout_secs = secs;
+    }
+    if let Some(topology) = args.topology {
+        config.topology = topology;
+    }
+    if let Some(shape) = args.topic_shape {
+        config.topic_shape = shape;
+    }
+    if let Some(num_topics) = args.num_topics {
+        config.num_topics = num_topics;
+    }
+    if let Some(selection) = args.topic_selection {
+        config.topic_selection = selection;
+    }
+    if config.connect_rate.is_some_and(|rate| rate <= 0.0) {
+        return Err("The connect rate must be positive".into());
+    }
+    if args.connect_storm.is_some() {
+        config.connect_storm = args.connect_storm;
+    }
+    if args.idle_clients.is_some() {
+        config.idle_clients = args.idle_clients;
+    }
+    config.idle_subscribe |= args.idle_subscribe;
+    if args.broker_pid.is_some() {
+        config.broker_pid = args.broker_pid;
+    }
+    if args.message_expiry.is_some() {
+        config.message_expiry_secs = args.message_expiry;
+    }
+    if !args.user_property.is_empty() {
+        config.user_properties = args.user_property;
+    }
+    if args.content_type.is_some() {
+        config.content_type = args.content_type;
+    }
+    if let Some(max) = args.topic_alias_max {
+        config.topic_alias_max = max;
+    }
+    config.topic_alias_churn |= args.topic_alias_churn;
+    if args.rate.is_some() {
+        config.target_rate = args.rate;
+    }
+    config.target_rate_per_producer |= args.rate_per_producer;
+    if config.target_rate.is_some_and(|rate| rate <= 0.0) {
+        return Err("The target rate must be positive".into());
+    }
+    if !args.phases.is_empty() {
+        config.phases = args.phases;
+    }
+    if !config.phases.is_empty() {
+        // The load profile replaces the constant rate, and a headless run ends with it
+        config.target_rate = None;
+        if config.duration_secs.is_none() {
+            config.duration_secs = Some(phases::LoadProfile::new(&config.phases, 1.0)?.total_secs());
+        }
+    }
+    if args.search {
+        config.search.get_or_insert_with(Default::default);
+    }
+    if let Some(search) = config.search.as_mut() {
+        search.start_rate = args.search_start.unwrap_or(search.start_rate);
+        search.step_rate = args.search_step.unwrap_or(search.step_rate);
+        search.step_secs = args.search_step_secs.unwrap_or(search.step_secs);
+        search.max_rate = args.search_max.or(search.max_rate);
+        search.max_p99_ms = args.slo_p99_ms.unwrap_or(search.max_p99_ms);
+        search.max_loss_pct = args.slo_loss_pct.unwrap_or(search.max_loss_pct);
+    }
+    if args.retained_load || args.retained_clear || !args.retained_read.is_empty() {
+        config.retained_bench.get_or_insert_with(Default::default);
+    }
+    if let Some(bench) = config.retained_bench.as_mut() {
+        bench.load |= args.retained_load;
+        bench.clear |= args.retained_clear;
+        bench.read.extend(args.retained_read);
+        bench.read_timeout_secs = args.retained_read_timeout.unwrap_or(bench.read_timeout_secs);
+    }
+    if let Some(kind) = args.payload {
+        config.payload.kind = match kind.as_str() {
+            "json" => PayloadKind::Json,
+            "padded-json" => PayloadKind::PaddedJson,
+            "random" => PayloadKind::Random,
+            other => return Err(format!("Unknown payload kind {}, use json, padded-json or random", other).into()),
+        };
+    }
+    if let Some(path) = args.payload_file {
+        config.payload.kind = PayloadKind::File { path };
+    }
+    if let Some(template) = args.payload_template {
+        config.payload.kind = PayloadKind::Template { template };
+    }
+    if let Some(size) = args.payload_size {
+        config.payload.size = size;
+    }
+    config.use_tls |= args.tls || config.tls_ca_file.is_some() || config.tls_client_cert.is_some();
 
     // Notify user if config was loaded
-    if config_file.is_some() {
-        eprintln!("✅ Loaded configuration from: {}", config_file.unwrap());
+    if let Some(path) = config_file {
+        eprintln!("✅ Loaded configuration from: {}", path);
+    }
+
+    // A missing or empty topic file fails here instead of in every client
+    if args.capture_topics.is_none() && args.agent.is_none() {
+        topic::TopicGenerator::from_config(&config, 1)?;
     }
 
-    if args.auto_start {
+    let distributed = args.agent.is_some() || args.coordinator.is_some();
+    if distributed && args.capture_topics.is_some() {
+        return Err("--capture-topics cannot run distributed".into());
+    }
+    let cluster_token = match (&args.cluster_token, distributed) {
+        (None, true) => return Err("--coordinator and --agent need a --cluster-token".into()),
+        (token, _) => token.as_deref().unwrap_or_default(),
+    };
+
+    if let Some(path) = &args.capture_topics {
+        capture::run(&config, path, &args.capture_filter, args.capture_secs)
+            .await
+            .map_err(|e| e.to_string())?;
+    } else if let Some(coordinator) = &args.agent {
+        distributed::run_agent(coordinator, cluster_token, &config).await?;
+    } else if let Some(listen) = &args.coordinator {
+        distributed::run_coordinator(&config, listen, args.agents, cluster_token).await?;
+    } else if config.retained_bench.is_some() {
+        retained::run(&config).await?;
+    } else if config.idle_clients.is_some() {
+        idle::run(&config).await?;
+    } else if config.connect_storm.is_some() {
+        storm::run(&config).await?;
+    } else if config.search.is_some() {
+        search::run(&config).await?;
+    } else if args.auto_start {
         run_producers(&config).await?;
     } else {
         run_ui(&config).await?;
@@ -181,28 +631,42 @@ async fn run_ui(initial_config: &Config) -> Result<(), Box<dyn std::error::Error
 
 async fn run_producers_with_ui(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let config = Arc::new(config.clone());
-    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.num_producers)));
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.publishers())));
     let log_buffer = LogBuffer::new(100); // Keep last 100 log lines
 
-    println!("\n📊 Starting {} producers...", config.num_producers);
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    println!("\n📊 Starting {} producers...", config.publishers());
 
     let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
     let (pause_tx, pause_rx) = tokio::sync::watch::channel(false);
     let mut is_paused = false;
 
+    // Phase changes are printed between the metrics lines
+    let (rate_rx, profile_driver) = phases::start(&config, metrics.clone(), shutdown_rx.clone(), |line| {
+        let _ = disable_raw_mode();
+        println!("{}", line);
+        let _ = enable_raw_mode();
+    })?;
+
     let mut handles: Vec<JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>> =
         Vec::new();
 
-    for producer_id in 0..config.num_producers {
+    for producer_id in 0..config.publishers() {
         let config_clone = config.clone();
         let client_metrics = metrics.lock().unwrap().clients[producer_id].clone();
         let shutdown_rx_clone = shutdown_rx.clone();
         let pause_rx_clone = pause_rx.clone();
+        let rate_rx_clone = rate_rx.clone();
         let log_buffer_clone = log_buffer.clone();
 
         let handle: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
             tokio::spawn(async move {
-            crate::producer::run_producer(producer_id, config_clone, Arc::new(client_metrics), shutdown_rx_clone, pause_rx_clone, log_buffer_clone)
+            crate::producer::run_producer(producer_id, config_clone, Arc::new(client_metrics), shutdown_rx_clone, pause_rx_clone, rate_rx_clone, log_buffer_clone)
                 .await
         });
 
@@ -210,8 +674,13 @@ async fn run_producers_with_ui(config: &Config) -> Result<(), Box<dyn std::error
     }
 
     // Small delay to let producers connect
-    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
-    println!("✅ All producers connected!");
+    match benchmark::wait_for_connected(&metrics, Duration::from_secs(config.connect_timeout_secs)).await {
+        Ok(elapsed) => println!("✅ All {} producers connected in {:.2}s", config.publishers(), elapsed.as_secs_f64()),
+        Err(connected) => println!(
+            "⚠️  Only {}/{} producers connected after {}s, continuing...",
+            connected, config.publishers(), config.connect_timeout_secs
+        ),
+    }
     println!("📊 Producers running (press P to pause/resume, C to clear, Q to quit)...");
 
     // Enable raw mode to capture keyboard input
@@ -232,7 +701,14 @@ async fn run_producers_with_ui(config: &Config) -> Result<(), Box<dyn std::error
 
                 // Temporarily disable raw mode to print metrics properly
                 let _ = disable_raw_mode();
-                println!("📈 Connected: {}/{} clients | Published: {} | v/s: {:.2} | {}", connected_clients, total_clients, total_published, total_vps, status);
+                match rate_rx.as_ref().map(|rx| *rx.borrow()) {
+                    Some(intended) => println!(
+                        "📈 Connected: {}/{} clients | Published: {} | v/s: {:.2} of {:.2} | Lag p99: {:.2} ms | {}",
+                        connected_clients, total_clients, total_published, total_vps, intended,
+                        metrics_guard.get_schedule_lag().p99 as f64 / 1000.0, status
+                    ),
+                    None => println!("📈 Connected: {}/{} clients | Published: {} | v/s: {:.2} | {}", connected_clients, total_clients, total_published, total_vps, status),
+                }
                 let _ = enable_raw_mode();
 
                 // Debug: Show individual client states
@@ -311,43 +787,153 @@ async fn run_producers_with_ui(config: &Config) -> Result<(), Box<dyn std::error
 
     println!("✅ Test completed!");
     println!("Total messages published: {}", metrics.lock().unwrap().get_total_published());
+    if let Some(driver) = profile_driver {
+        println!("Phases:");
+        for phase in driver.await.unwrap_or_default() {
+            println!("{}", phase.summary_line());
+        }
+    }
+    let reason_codes = metrics.lock().unwrap().get_reason_codes();
+    if !reason_codes.is_empty() {
+        let codes: Vec<String> = reason_codes.iter().map(|(code, count)| format!("{}={}", code, count)).collect();
+        println!("Reason codes: {}", codes.join(", "));
+    }
+    if let Some(acks) = AckTotals::new(&metrics.lock().unwrap()) {
+        println!("{}", acks.summary_line());
+    }
+    if config.topic_alias_max > 0 {
+        let final_metrics = metrics.lock().unwrap();
+        println!(
+            "Topic aliases: {} reuses | {} assignments | {} bytes saved",
+            final_metrics.get_total_alias_reuses(),
+            final_metrics.get_total_alias_assignments(),
+            final_metrics.get_total_alias_bytes_saved()
+        );
+    }
 
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
     Ok(())
 }
 
 async fn run_producers(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let config = Arc::new(config.clone());
-    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.num_producers)));
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.publishers())));
     let log_buffer = LogBuffer::new(100); // Keep last 100 log lines
 
-    eprintln!("Starting {} producers...", config.num_producers);
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    eprintln!("Starting {} producers...", config.publishers());
 
-    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
     let (_pause_tx, pause_rx) = tokio::sync::watch::channel(false);
+    let (rate_rx, profile_driver) = phases::start(&config, metrics.clone(), shutdown_rx.clone(), |line| eprintln!("{}", line))?;
+
+    let started_at = Utc::now();
+    let started = Instant::now();
 
     let mut handles: Vec<JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>> =
         Vec::new();
 
-    for producer_id in 0..config.num_producers {
+    for producer_id in 0..config.publishers() {
         let config_clone = config.clone();
         let client_metrics = metrics.lock().unwrap().clients[producer_id].clone();
         let shutdown_rx_clone = shutdown_rx.clone();
         let pause_rx_clone = pause_rx.clone();
+        let rate_rx_clone = rate_rx.clone();
         let log_buffer_clone = log_buffer.clone();
 
         let handle: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
             tokio::spawn(async move {
-            crate::producer::run_producer(producer_id, config_clone, Arc::new(client_metrics), shutdown_rx_clone, pause_rx_clone, log_buffer_clone)
+            crate::producer::run_producer(producer_id, config_clone, Arc::new(client_metrics), shutdown_rx_clone, pause_rx_clone, rate_rx_clone, log_buffer_clone)
                 .await
         });
 
         handles.push(handle);
     }
+    let connect_watch = benchmark::spawn_connect_watch(metrics.clone());
+
+    // Run until the duration or message limit is reached (or forever if neither is set)
+    let stop_reason = benchmark::wait_for_stop(
+        &config,
+        &metrics,
+        GlobalMetrics::get_total_published,
+        || handles.iter().all(|h| h.is_finished()),
+    )
+    .await;
+    let elapsed = started.elapsed();
+    let _ = shutdown_tx.send(true);
 
     // Wait for all tasks to finish (they should exit gracefully on shutdown signal)
     for handle in handles {
-        let _ = handle.await;
+        if let Ok(Ok(Err(e))) = tokio::time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Producer failed: {}", e);
+        }
     }
+    let all_connected = benchmark::all_connected_secs(connect_watch).await;
+    let phase_reports = match profile_driver {
+        Some(driver) => driver.await.unwrap_or_default(),
+        None => Vec::new(),
+    };
 
+    let final_metrics = metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    eprintln!("Total messages published: {}", final_metrics.get_total_published());
+    eprintln!("{}", benchmark::connect_summary(&final_metrics, all_connected));
+    eprintln!(
+        "Payload bytes published: {} ({:.2} MB/s)",
+        final_metrics.get_total_published_bytes(),
+        final_metrics.get_total_published_bytes() as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001)
+    );
+    if let Some(intended) = config.total_target_rate() {
+        eprintln!(
+            "Rate: intended {:.2} msg/s | achieved {:.2} msg/s",
+            intended,
+            final_metrics.get_total_published() as f64 / elapsed.as_secs_f64().max(0.001)
+        );
+        eprintln!("Schedule lag: {}", final_metrics.get_schedule_lag().format_ms());
+    }
+    if !phase_reports.is_empty() {
+        eprintln!("Phases:");
+        for phase in &phase_reports {
+            eprintln!("{}", phase.summary_line());
+        }
+        eprintln!("Schedule lag: {}", final_metrics.get_schedule_lag().format_ms());
+    }
+    let reason_codes = final_metrics.get_reason_codes();
+    if !reason_codes.is_empty() {
+        let codes: Vec<String> = reason_codes.iter().map(|(code, count)| format!("{}={}", code, count)).collect();
+        eprintln!("Reason codes: {}", codes.join(", "));
+    }
+    if let Some(acks) = AckTotals::new(&final_metrics) {
+        eprintln!("{}", acks.summary_line());
+    }
+    if config.topic_alias_max > 0 {
+        eprintln!(
+            "Topic aliases: {} reuses | {} assignments | {} bytes saved",
+            final_metrics.get_total_alias_reuses(),
+            final_metrics.get_total_alias_assignments(),
+            final_metrics.get_total_alias_bytes_saved()
+        );
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-publish", &config, &final_metrics, started_at, elapsed, stop_reason);
+        report.phases = phase_reports;
+        report.all_connected_secs = all_connected;
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
     Ok(())
 }
@@ -0,0 +1,525 @@
+mod alias;
+mod benchmark;
+mod config;
+mod connection;
+mod metrics;
+mod payload;
+mod phases;
+mod producer;
+mod rate;
+mod selection;
+mod sequence;
+mod subscriber;
+mod topic;
+mod ui;
+
+use crate::benchmark::{AckTotals, DeliveryReport, Report, SessionReport, SharedReport, StopReason};
+use crate::config::{Config, Topology};
+use crate::metrics::GlobalMetrics;
+use crate::topic::{topic_matches, TopicGenerator};
+use crate::ui::{draw_metrics_screen, LogBuffer};
+use chrono::Utc;
+use clap::Parser;
+use crossterm::event::{self, Event, KeyCode};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::Terminal;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+type ClientHandle = JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>;
+
+/// How long the subscribers get to drain in-flight messages after the producers stop
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Parser, Debug)]
+#[command(name = "MQTT Pub/Sub")]
+#[command(about = "Publishers and subscribers in one process with end-to-end delivery metrics", long_about = None)]
+struct Args {
+    /// MQTT broker host
+    #[arg(long, default_value = "localhost")]
+    broker: String,
+
+    /// MQTT broker port
+    #[arg(long, default_value = "1883")]
+    port: u16,
+
+    /// Broker URL (mqtt://, mqtts://, ws://, wss://), overrides --broker, --port and --tls
+    #[arg(long)]
+    url: Option<String>,
+
+    /// Configuration file to load (JSON)
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Run without the metrics screen and print a summary at the end
+    #[arg(long)]
+    auto_start: bool,
+
+    /// Stop after this many seconds
+    #[arg(long)]
+    duration: Option<u64>,
+
+    /// Stop after this many messages published in total
+    #[arg(long)]
+    messages: Option<u64>,
+
+    /// Write a JSON report at the end of the run, "-" for stdout
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Connect to the broker over TLS
+    #[arg(long)]
+    tls: bool,
+
+    /// Accept any server certificate (self-signed test brokers)
+    #[arg(long)]
+    insecure: bool,
+
+    /// Username sent in CONNECT, "{id}" is replaced by the client number
+    #[arg(long)]
+    username: Option<String>,
+
+    /// Password sent in CONNECT, "{id}" is replaced by the client number
+    #[arg(long)]
+    password: Option<String>,
+
+    /// MQTT protocol version: 4 (3.1.1) or 5
+    #[arg(long)]
+    protocol_version: Option<u8>,
+
+    /// MQTT 5 session expiry interval in seconds
+    #[arg(long)]
+    session_expiry: Option<u32>,
+
+    /// Stable client ids, "{role}" becomes pub/sub and "{id}" the client number, e.g. "load-{role}-{id}"
+    #[arg(long)]
+    client_id_template: Option<String>,
+
+    /// Keep the session across reconnects (clean_session=false / clean start off)
+    #[arg(long)]
+    no_clean_session: bool,
+
+    /// Offline scenario: disconnect the subscribers for this many seconds, then resume their sessions
+    #[arg(long)]
+    offline: Option<u64>,
+
+    /// Seconds the subscribers receive before going offline (default 10)
+    #[arg(long)]
+    offline_after: Option<u64>,
+
+    /// Number of publisher/subscriber pairs, or the larger side of the topology
+    #[arg(long)]
+    producers: Option<usize>,
+
+    /// Topology: one-to-one, fan-out, fan-in or many-to-many[:overlap] (default one-to-one)
+    #[arg(long, value_parser = config::parse_topology)]
+    topology: Option<config::Topology>,
+
+    /// Topic tree shape: uniform, levels:N,N,..., isa95[:SITES,AREAS,LINES,CELLS,DEVICES,METRICS],
+    /// random[:SEED] or file:PATH (default uniform)
+    #[arg(long, value_parser = topic::TopicShape::parse)]
+    topic_shape: Option<topic::TopicShape>,
+
+    /// Maximum topics per producer, taken breadth first; 0 (default) for the whole tree.
+    /// Overrides num_topics from the config file, which caps the tree the same way
+    #[arg(long)]
+    num_topics: Option<usize>,
+
+    /// Topic selection: round-robin, uniform, zipf[:EXPONENT], hot-set[:TOPICS_PCT:TRAFFIC_PCT]
+    /// or bursty[:ON_SECS:OFF_SECS] (default round-robin)
+    #[arg(long, value_parser = selection::TopicSelection::parse)]
+    topic_selection: Option<selection::TopicSelection>,
+
+    /// Publish QoS (0, 1 or 2)
+    #[arg(long)]
+    qos: Option<i32>,
+
+    /// Total messages per second across all producers, replaces sleep_ms
+    #[arg(long)]
+    rate: Option<f64>,
+
+    /// Percentage of topics every subscriber subscribes to (0-100)
+    #[arg(long)]
+    subscribe_percentage: Option<u8>,
+
+    /// QoS of the subscriptions (default: 0, or 1 with a persistent session)
+    #[arg(long)]
+    subscribe_qos: Option<i32>,
+
+    /// Percent of subscriptions at QoS 0,1,2, e.g. "50,30,20"
+    #[arg(long, value_parser = config::parse_qos_mix)]
+    subscribe_qos_mix: Option<[u8; 3]>,
+
+    /// Shared subscriptions: split the subscribers into this many share groups
+    #[arg(long)]
+    shared_groups: Option<usize>,
+
+    /// Filter the share groups subscribe to (default "+/#"), implies shared subscriptions
+    #[arg(long)]
+    shared_filter: Option<String>,
+
+    /// Seconds to wait for all clients to connect
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let config_file = if args.config.is_some() {
+        args.config.as_deref()
+    } else if std::path::Path::new("config.json").exists() {
+        Some("config.json")
+    } else {
+        None
+    };
+
+    let mut config = Config::load_or_default(config_file);
+    config.broker_host = args.broker;
+    config.broker_port = args.port;
+    if args.url.is_some() {
+        config.broker_url = args.url;
+    }
+    if args.duration.is_some() {
+        config.duration_secs = args.duration;
+    }
+    if args.messages.is_some() {
+        config.max_messages = args.messages;
+    }
+    if args.report.is_some() {
+        config.report_file = args.report;
+    }
+    config.tls_insecure |= args.insecure;
+    if args.username.is_some() {
+        config.username = args.username;
+    }
+    if args.password.is_some() {
+        config.password = args.password;
+    }
+    if let Some(version) = args.protocol_version {
+        config.protocol_version = version;
+    }
+    if args.session_expiry.is_some() {
+        config.session_expiry_secs = args.session_expiry;
+    }
+    if args.client_id_template.is_some() {
+        config.client_id_template = args.client_id_template;
+    }
+    if args.no_clean_session {
+        config.clean_session = false;
+    }
+    if args.offline.is_some() {
+        config.offline_secs = args.offline;
+    }
+    if let Some(secs) = args.offline_after {
+        config.offline_after_secs = secs;
+    }
+    if let Some(producers) = args.producers {
+        config.num_producers = producers;
+    }
+    if let Some(topology) = args.topology {
+        config.topology = topology;
+    }
+    if let Some(shape) = args.topic_shape {
+        config.topic_shape = shape;
+    }
+    if let Some(num_topics) = args.num_topics {
+        config.num_topics = num_topics;
+    }
+    if let Some(selection) = args.topic_selection {
+        config.topic_selection = selection;
+    }
+    if let Some(qos) = args.qos {
+        config.qos = qos;
+    }
+    if args.rate.is_some() {
+        config.target_rate = args.rate;
+    }
+    if config.target_rate.is_some_and(|rate| rate <= 0.0) {
+        return Err("The target rate must be positive".into());
+    }
+    if let Some(percentage) = args.subscribe_percentage {
+        config.subscribe_percentage = percentage;
+    }
+    if args.subscribe_qos.is_some() {
+        config.subscribe_qos = args.subscribe_qos;
+    }
+    if args.subscribe_qos_mix.is_some() {
+        config.subscribe_qos_mix = args.subscribe_qos_mix;
+    }
+    if args.shared_groups.is_some() || args.shared_filter.is_some() {
+        let shared = config.shared.get_or_insert_with(Default::default);
+        if let Some(groups) = args.shared_groups {
+            shared.groups = groups;
+        }
+        if let Some(filter) = args.shared_filter {
+            shared.filter = filter;
+        }
+    }
+    if config.shared.as_ref().is_some_and(|shared| shared.groups == 0 || shared.groups > config.subscribers()) {
+        return Err("Shared subscriptions need between 1 and one group per subscriber".into());
+    }
+    if let Some(secs) = args.connect_timeout {
+        config.connect_timeout_secs = secs;
+    }
+    config.use_tls |= args.tls || config.tls_ca_file.is_some() || config.tls_client_cert.is_some();
+
+    if let Some(path) = config_file {
+        eprintln!("✅ Loaded configuration from: {}", path);
+    }
+
+    // A missing or empty topic file fails here instead of in every client
+    topic::TopicGenerator::from_config(&config, 1)?;
+
+    run(&config, !args.auto_start).await
+}
+
+/// Subscribers expected to receive each published message, following the
+/// topic selection in `subscriber::run`. With shared subscriptions every
+/// group gets each message its filter matches once.
+fn expected_fan_out(config: &Config) -> f64 {
+    if let Some(shared) = &config.shared {
+        return shared.groups as f64 * shared_match_ratio(config, &shared.filter);
+    }
+    if config.topology == Topology::FanIn {
+        // One wildcard per tree, subscribe_percentage picks the trees
+        let trees = config.num_producers.max(1) as f64;
+        return (trees * (config.subscribe_percentage as f64 / 100.0)).round() / trees;
+    }
+    let subscriptions = subscriber::tree_subscriptions(config, 1).map_or(0, |(topics, _)| topics.len());
+    if subscriptions == 0 {
+        return 0.0;
+    }
+    let subscribed = (subscriptions as f64 * (config.subscribe_percentage as f64 / 100.0)).round();
+    config.topology.subscribers_per_tree(config.num_producers) as f64 * subscribed / subscriptions as f64
+}
+
+/// Share of all published messages whose topic matches `filter`, every
+/// producer publishes at the same rate and spreads it over its topics
+/// following the topic selection
+fn shared_match_ratio(config: &Config, filter: &str) -> f64 {
+    let mut ratios = Vec::new();
+    for producer in 1..=config.publishers() {
+        let Ok(generator) = TopicGenerator::from_config(config, producer) else {
+            continue;
+        };
+        let producer_topics = generator.into_topics(config.use_leafs);
+        let count = producer_topics.len();
+        let (mut total, mut matched) = (0.0, 0.0);
+        for (index, topic) in producer_topics.iter().enumerate() {
+            let weight = config.topic_selection.weight(index, count);
+            total += weight;
+            if topic_matches(filter, &topic) {
+                matched += weight;
+            }
+        }
+        if total > 0.0 {
+            ratios.push(matched / total);
+        }
+    }
+    if ratios.is_empty() {
+        return 0.0;
+    }
+    ratios.iter().sum::<f64>() / ratios.len() as f64
+}
+
+/// Start the subscribers and producers of the topology, subscribers first so
+/// nothing is published before the subscriptions exist, then run until the
+/// duration or message limit is reached, Ctrl+C, or Q on the metrics screen
+async fn run(config: &Config, with_ui: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Arc::new(config.clone());
+    let pub_metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.publishers())));
+    let sub_metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.subscribers())));
+    let log_buffer = LogBuffer::new(100); // Keep last 100 log lines
+    let fan_out = expected_fan_out(&config);
+    let delivery = || {
+        let published = pub_metrics.lock().unwrap().get_total_published();
+        DeliveryReport::new(published, sub_metrics.lock().unwrap().get_total_received(), fan_out)
+    };
+
+    // Producers stop first, subscribers get to drain what is still in flight
+    let (pub_shutdown_tx, pub_shutdown_rx) = watch::channel(false);
+    let (sub_shutdown_tx, sub_shutdown_rx) = watch::channel(false);
+    let (_pause_tx, pause_rx) = watch::channel(false);
+
+    // Catch configuration errors before waiting for subscribers that never connect
+    if let Err(e) = connection::mqtt_options(&config, connection::client_id(&config, "sub", 1), 1) {
+        return Err(e.to_string().into());
+    }
+    eprintln!("Starting {} subscribers...", config.subscribers());
+    let mut sub_handles: Vec<ClientHandle> = Vec::new();
+    for id in 0..config.subscribers() {
+        let client_metrics = Arc::new(sub_metrics.lock().unwrap().clients[id].clone());
+        sub_handles.push(tokio::spawn(subscriber::run(config.clone(), client_metrics, sub_shutdown_rx.clone(), log_buffer.clone())));
+    }
+    let timeout = Duration::from_secs(config.connect_timeout_secs);
+    if let Err(connected) = benchmark::wait_for_connected(&sub_metrics, timeout).await {
+        let _ = sub_shutdown_tx.send(true);
+        return Err(format!("Only {}/{} subscribers connected after {}s", connected, config.subscribers(), config.connect_timeout_secs).into());
+    }
+    // Give the subscriptions time to complete
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    eprintln!("Starting {} producers...", config.publishers());
+    let (rate_rx, profile_driver) = phases::start(&config, pub_metrics.clone(), pub_shutdown_rx.clone(), |line| eprintln!("{}", line))?;
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let mut pub_handles: Vec<ClientHandle> = Vec::new();
+    for id in 0..config.publishers() {
+        let client_metrics = Arc::new(pub_metrics.lock().unwrap().clients[id].clone());
+        pub_handles.push(tokio::spawn(producer::run_producer(
+            id,
+            config.clone(),
+            client_metrics,
+            pub_shutdown_rx.clone(),
+            pause_rx.clone(),
+            rate_rx.clone(),
+            log_buffer.clone(),
+        )));
+    }
+    let connect_watch = benchmark::spawn_connect_watch(pub_metrics.clone());
+
+    let stop_reason = if with_ui {
+        run_metrics_screen(&config, &pub_metrics, &sub_metrics, &delivery, started, &log_buffer).await?
+    } else {
+        benchmark::wait_for_stop(
+            &config,
+            &pub_metrics,
+            GlobalMetrics::get_total_published,
+            || pub_handles.iter().all(|h| h.is_finished()),
+        )
+        .await
+    };
+    let elapsed = started.elapsed();
+    let _ = pub_shutdown_tx.send(true);
+    for handle in pub_handles {
+        if let Ok(Ok(Err(e))) = tokio::time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Producer failed: {}", e);
+        }
+    }
+
+    // Wait until the received count stops moving or everything expected arrived
+    let drain_started = Instant::now();
+    let mut last_received = delivery().received;
+    while drain_started.elapsed() < DRAIN_TIMEOUT && delivery().delivery_ratio < 1.0 {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let received = delivery().received;
+        if received == last_received {
+            break;
+        }
+        last_received = received;
+    }
+    let _ = sub_shutdown_tx.send(true);
+    for handle in sub_handles {
+        if let Ok(Ok(Err(e))) = tokio::time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Subscriber failed: {}", e);
+        }
+    }
+    let all_connected = benchmark::all_connected_secs(connect_watch).await;
+    if let Some(driver) = profile_driver {
+        let _ = driver.await;
+    }
+
+    let delivery = delivery();
+    let publishers = pub_metrics.lock().unwrap();
+    let subscribers = sub_metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    eprintln!("Published: {} | Received: {}", delivery.published, delivery.received);
+    eprintln!("{}", delivery.summary_line());
+    eprintln!("{}", benchmark::connect_summary(&publishers, all_connected));
+    eprintln!(
+        "Pub v/s: {:.2} | Recv v/s: {:.2}",
+        delivery.published as f64 / elapsed.as_secs_f64().max(0.001),
+        delivery.received as f64 / elapsed.as_secs_f64().max(0.001)
+    );
+    eprintln!("Latency: {}", subscribers.get_latency().format_ms());
+    eprintln!("Lost: {} | Duplicates: {} | Out of order: {}", subscribers.get_total_lost(), subscribers.get_total_duplicates(), subscribers.get_total_out_of_order());
+    if let Some(acks) = AckTotals::new(&publishers) {
+        eprintln!("Publishers: {}", acks.summary_line());
+    }
+    if let Some(acks) = AckTotals::new(&subscribers) {
+        eprintln!("Subscribers: {}", acks.summary_line());
+    }
+    let session = SessionReport::new(&config, &subscribers);
+    if let Some(session) = &session {
+        eprintln!("{}", session.summary_line());
+    }
+    let shared = SharedReport::new(&config, &subscribers);
+    if let Some(shared) = &shared {
+        eprintln!("{}", shared.summary_line());
+        for line in shared.group_lines() {
+            eprintln!("{}", line);
+        }
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-pubsub", &config, &subscribers, started_at, elapsed, stop_reason);
+        report.add_publishers(&config, &publishers);
+        report.all_connected_secs = all_connected;
+        report.delivery = Some(delivery);
+        report.session = session;
+        report.shared = shared;
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+    Ok(())
+}
+
+/// Draw both sides on one metrics screen until Q, Ctrl+C or a configured limit
+async fn run_metrics_screen(
+    config: &Config,
+    pub_metrics: &Arc<Mutex<GlobalMetrics>>,
+    sub_metrics: &Arc<Mutex<GlobalMetrics>>,
+    delivery: &impl Fn() -> DeliveryReport,
+    started: Instant,
+    log_buffer: &LogBuffer,
+) -> Result<StopReason, Box<dyn std::error::Error>> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    crossterm::execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+    terminal.clear()?;
+    terminal.hide_cursor()?;
+
+    let deadline = config.duration_secs.map(|secs| started + Duration::from_secs(secs));
+    let mut redraw = tokio::time::interval(Duration::from_millis(250));
+    let stop_reason = loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break StopReason::Interrupted,
+            _ = redraw.tick() => {
+                let delivery = delivery();
+                {
+                    let publishers = pub_metrics.lock().unwrap();
+                    let subscribers = sub_metrics.lock().unwrap();
+                    let shared = SharedReport::new(config, &subscribers);
+                    terminal.draw(|f| draw_metrics_screen(f, &publishers, &subscribers, &delivery, shared.as_ref(), started.elapsed(), log_buffer))?;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    break StopReason::Duration;
+                }
+                if config.max_messages.is_some_and(|limit| delivery.published >= limit) {
+                    break StopReason::Messages;
+                }
+                if event::poll(Duration::from_millis(0))? {
+                    if let Event::Key(key) = event::read()? {
+                        if matches!(key.code, KeyCode::Char('q') | KeyCode::Char('Q')) {
+                            break StopReason::Interrupted;
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    disable_raw_mode()?;
+    crossterm::execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(stop_reason)
+}
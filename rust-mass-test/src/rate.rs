@@ -0,0 +1,113 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Open-loop publish schedule: message N is due at `start + N / rate`, no matter
+/// when earlier messages actually went out. A producer that falls behind
+/// publishes back to back until it has caught up, and the distance to the
+/// schedule is reported as lag instead of silently lowering the rate.
+#[allow(dead_code)]
+pub struct RateSchedule {
+    rate: f64,
+    start: Instant,
+    sent: u64,
+}
+
+#[allow(dead_code)]
+impl RateSchedule {
+    /// `rate` in messages per second
+    pub fn new(rate: f64) -> Self {
+        RateSchedule { rate, start: Instant::now(), sent: 0 }
+    }
+
+    /// Start over at `at`, e.g. after a pause, so skipped messages are not sent in a burst
+    pub fn restart_at(&mut self, at: Instant) {
+        self.start = at;
+        self.sent = 0;
+    }
+
+    /// Switch to `rate`: the next message is due one new interval after the last
+    /// one. 0 stops the schedule until the rate goes up again.
+    pub fn set_rate(&mut self, rate: f64) {
+        if self.is_idle() {
+            self.start = Instant::now();
+            self.sent = 0;
+        } else if self.sent > 0 {
+            // Rebase on the last slot, which keeps any lag
+            self.start += Duration::from_secs_f64((self.sent - 1) as f64 / self.rate);
+            self.sent = 1;
+        }
+        self.rate = rate;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.rate <= 0.0
+    }
+
+    /// When the next message is due
+    pub fn next_at(&self) -> Instant {
+        if self.sent == 0 || self.is_idle() {
+            return self.start;
+        }
+        // Offsets are computed from the start, so rounding errors don't accumulate
+        self.start + Duration::from_secs_f64(self.sent as f64 / self.rate)
+    }
+
+    /// Take the next slot and return when it was due
+    pub fn advance(&mut self) -> Instant {
+        let due = self.next_at();
+        self.sent += 1;
+        due
+    }
+}
+
+/// One producer's share of a total rate
+#[allow(dead_code)]
+pub fn producer_rate(total_rate: f64, num_producers: usize) -> f64 {
+    total_rate / num_producers.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_schedule_does_not_drift() {
+        let mut schedule = RateSchedule::new(1000.0);
+        let start = schedule.next_at();
+        for _ in 0..1000 {
+            schedule.advance();
+        }
+        assert_eq!(schedule.next_at() - start, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_producer_keeps_schedule() {
+        let mut schedule = RateSchedule::new(10.0);
+        schedule.advance();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        // Everything due during the stall is still due at its original time
+        let due = schedule.advance();
+        assert_eq!(Instant::now() - due, Duration::from_millis(900));
+
+        schedule.restart_at(Instant::now());
+        assert_eq!(schedule.next_at(), Instant::now());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_change_applies_from_last_slot() {
+        let mut schedule = RateSchedule::new(0.1);
+        let first = schedule.advance();
+        schedule.set_rate(100.0);
+        // A slow rate's next slot is not kept when speeding up
+        assert_eq!(schedule.advance(), first + Duration::from_millis(10));
+        assert_eq!(schedule.next_at() - first, Duration::from_millis(20));
+
+        schedule.set_rate(0.0);
+        assert!(schedule.is_idle());
+        schedule.next_at();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        // Idle time is not owed when the rate goes up again
+        schedule.set_rate(10.0);
+        assert_eq!(schedule.next_at(), Instant::now());
+    }
+}
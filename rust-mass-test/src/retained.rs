@@ -0,0 +1,313 @@
+use crate::benchmark::{Report, RetainedLoad, RetainedRead, RetainedReport, StopReason};
+use crate::config::{Config, RetainedConfig};
+use crate::connection::{self, BoxError, ClientEvent, MqttEventLoop};
+use crate::exporter;
+use crate::metrics::{ClientMetrics, GlobalMetrics};
+use crate::payload::{MessageContext, PayloadGenerator};
+use crate::topic::{topic_matches, TopicGenerator, TopicList};
+use bytes::Bytes;
+use chrono::Utc;
+use rumqttc::QoS;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// How often the load progress is printed
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for the broker to close the connection after DISCONNECT
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run the retained-message benchmark: load a retained message onto every
+/// topic of the producers' trees (one client per tree), time the configured
+/// wildcard reads one after the other, then clear the trees with empty
+/// retained payloads. Each step only runs if it is configured.
+pub async fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let bench = config.retained_bench.clone().unwrap_or_default();
+    let config = Arc::new(config.clone());
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.num_producers)));
+
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    let trees: Vec<TopicList> = (1..=config.num_producers)
+        .map(|tree| Ok(TopicGenerator::from_config(&config, tree)?.into_topics(false)))
+        .collect::<Result<_, String>>()?;
+    let topics: usize = trees.iter().map(|tree| tree.len()).sum();
+    eprintln!("📦 Retained benchmark: {} topics in {} trees", topics, trees.len());
+
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let mut retained = RetainedReport::default();
+    let stop_reason = tokio::select! {
+        _ = tokio::signal::ctrl_c() => StopReason::Interrupted,
+        result = run_steps(&config, &bench, &trees, &metrics, &mut retained) => {
+            result.map_err(|e| e.to_string())?;
+            StopReason::Completed
+        }
+    };
+    let elapsed = started.elapsed();
+
+    let final_metrics = metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    if let Some(load) = &retained.load {
+        eprintln!("Load: {} topics in {:.2}s ({:.2} msg/s)", load.topics, load.secs, load.rate);
+    }
+    for read in &retained.reads {
+        eprintln!("{}", read.summary_line());
+    }
+    if let Some(clear) = &retained.clear {
+        eprintln!("Clear: {} topics in {:.2}s ({:.2} msg/s)", clear.topics, clear.secs, clear.rate);
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-publish", &config, &final_metrics, started_at, elapsed, stop_reason);
+        report.retained = Some(retained);
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
+    Ok(())
+}
+
+/// Load, read and clear in that order, filling in `retained` as the steps finish
+async fn run_steps(
+    config: &Arc<Config>,
+    bench: &RetainedConfig,
+    trees: &[TopicList],
+    metrics: &Arc<Mutex<GlobalMetrics>>,
+    retained: &mut RetainedReport,
+) -> Result<(), BoxError> {
+    if bench.load {
+        eprintln!("Loading retained messages...");
+        retained.load = Some(publish_trees(config, trees, metrics, false).await?);
+    }
+    for filter in &bench.read {
+        let expected = trees.iter().flat_map(|tree| tree.iter()).filter(|topic| topic_matches(filter, topic)).count();
+        eprintln!("Reading {} ({} expected)...", filter, expected);
+        let read = read_filter(config, filter, expected as u64, Duration::from_secs(bench.read_timeout_secs)).await?;
+        eprintln!("{}", read.summary_line());
+        retained.reads.push(read);
+    }
+    if bench.clear {
+        eprintln!("Clearing retained messages...");
+        retained.clear = Some(publish_trees(config, trees, metrics, true).await?);
+    }
+    Ok(())
+}
+
+/// Publish a retained message (empty with `clear`) to every topic, one client per tree
+async fn publish_trees(
+    config: &Arc<Config>,
+    trees: &[TopicList],
+    metrics: &Arc<Mutex<GlobalMetrics>>,
+    clear: bool,
+) -> Result<RetainedLoad, BoxError> {
+    let clients = metrics.lock().unwrap().clients.clone();
+    let before: u64 = clients.iter().map(|c| c.get_total_published()).sum();
+    let topics: usize = trees.iter().map(|tree| tree.len()).sum();
+    let started = Instant::now();
+
+    let handles: Vec<JoinHandle<Result<(), BoxError>>> = trees
+        .iter()
+        .zip(&clients)
+        .enumerate()
+        .map(|(index, (tree, client_metrics))| tokio::spawn(load_tree(index, config.clone(), tree.clone(), client_metrics.clone(), clear)))
+        .collect();
+    let all_loaded = async {
+        for handle in handles {
+            handle.await??;
+        }
+        Ok::<(), BoxError>(())
+    };
+    tokio::pin!(all_loaded);
+
+    let mut progress = time::interval_at(started + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut all_loaded => {
+                result?;
+                break;
+            }
+            _ = progress.tick() => {
+                let published = clients.iter().map(|c| c.get_total_published()).sum::<u64>() - before;
+                eprintln!(
+                    "[{:>5.0}s] Published: {}/{} ({:.0} msg/s)",
+                    started.elapsed().as_secs_f64(),
+                    published,
+                    topics,
+                    published as f64 / started.elapsed().as_secs_f64()
+                );
+            }
+        }
+    }
+    Ok(RetainedLoad::new(topics as u64, started.elapsed()))
+}
+
+/// Publish one tree and wait until the broker has it: every message
+/// acknowledged (PUBACK or PUBCOMP) with QoS 1 and 2, the DISCONNECT after the
+/// last one sent with QoS 0
+async fn load_tree(index: usize, config: Arc<Config>, topics: TopicList, metrics: ClientMetrics, clear: bool) -> Result<(), BoxError> {
+    let qos = match config.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+    let payloads = PayloadGenerator::new(&config.payload)?;
+    let mut options = connection::mqtt_options(&config, connection::client_id(&config, "retained", index + 1), index + 1)?;
+    options.set_max_packet_size((config.payload.max_size()? + 64 * 1024).max(100 * 1024));
+
+    let (client, mut eventloop) = options.connect(100);
+    wait_for_connack(&mut eventloop, &metrics).await?;
+
+    let mut acked = 0;
+    for topic in topics.iter() {
+        let payload = if clear {
+            Bytes::new()
+        } else {
+            payloads.generate(&MessageContext {
+                topic: &topic,
+                producer: index + 1,
+                counter: metrics.get_counter(),
+                run: "retained",
+                seq: 1,
+                ts: Utc::now(),
+                intended_ts: None,
+            })
+        };
+        let payload_len = payload.len() as u64;
+        // Keep polling the event loop while the publish waits for room in the request channel
+        let publish = client.publish(topic, qos, true, payload, None);
+        tokio::pin!(publish);
+        loop {
+            tokio::select! {
+                result = &mut publish => {
+                    result?;
+                    break;
+                }
+                event = eventloop.poll() => count_ack(event, &metrics, &mut acked)?,
+            }
+        }
+        metrics.increment_published();
+        metrics.add_published_bytes(payload_len);
+    }
+
+    if qos != QoS::AtMostOnce {
+        while acked < topics.len() {
+            count_ack(eventloop.poll().await, &metrics, &mut acked)?;
+        }
+    }
+    // Requests go out in order, so the DISCONNECT follows the last publish
+    client.disconnect().await?;
+    let _ = time::timeout(DISCONNECT_TIMEOUT, async { while eventloop.poll().await.is_ok() {} }).await;
+    metrics.set_connected(false);
+    Ok(())
+}
+
+/// Count acknowledgements of our publishes, an error if the connection is gone
+fn count_ack(event: Result<ClientEvent, connection::ConnectionError>, metrics: &ClientMetrics, acked: &mut usize) -> Result<(), BoxError> {
+    if let Ok(event) = &event {
+        metrics.record_ack_flow(event);
+    }
+    match event {
+        Ok(ClientEvent::PubAck { reason }) => {
+            if let Some(reason) = reason {
+                metrics.record_reason_code(&reason);
+            }
+            *acked += 1;
+            Ok(())
+        }
+        Ok(ClientEvent::PubRec { reason: Some(reason) }) => {
+            metrics.record_reason_code(&reason);
+            Ok(())
+        }
+        Ok(ClientEvent::PubComp) => {
+            *acked += 1;
+            Ok(())
+        }
+        Ok(ClientEvent::Disconnect) => Err("Broker sent DISCONNECT".into()),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            if let Some(code) = e.reason_code() {
+                metrics.record_reason_code(&code);
+            }
+            metrics.increment_connection_errors();
+            Err(format!("Connection lost: {}", e).into())
+        }
+    }
+}
+
+async fn wait_for_connack(eventloop: &mut MqttEventLoop, metrics: &ClientMetrics) -> Result<(), BoxError> {
+    let connect_started = Instant::now();
+    loop {
+        match eventloop.poll().await {
+            Ok(ClientEvent::ConnAck { .. }) => {
+                metrics.record_connect_latency(connect_started.elapsed().as_micros() as u64);
+                metrics.set_connected(true);
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if let Some(code) = e.reason_code() {
+                    metrics.record_reason_code(&code);
+                }
+                metrics.increment_connection_errors();
+                return Err(format!("Connect failed: {}", e).into());
+            }
+        }
+    }
+}
+
+/// Subscribe to `filter` with a fresh clean session and time the retained
+/// messages that arrive, until `expected` arrived or nothing came for `timeout`
+async fn read_filter(config: &Config, filter: &str, expected: u64, timeout: Duration) -> Result<RetainedRead, BoxError> {
+    // A leftover session could hold the subscription and deliver early
+    let config = Config { clean_session: true, ..config.clone() };
+    let mut options = connection::mqtt_options(&config, connection::client_id(&config, "reader", 1), 1)?;
+    options.set_max_packet_size(connection::MAX_PACKET_SIZE);
+    let (client, mut eventloop) = options.connect(10);
+    wait_for_connack(&mut eventloop, &ClientMetrics::new(0)).await?;
+
+    let mut read = RetainedRead {
+        filter: filter.to_string(),
+        expected,
+        received: 0,
+        suback_ms: None,
+        first_ms: None,
+        last_ms: None,
+    };
+    let subscribed = Instant::now();
+    client.subscribe(filter, QoS::AtMostOnce).await?;
+    let ms = || subscribed.elapsed().as_secs_f64() * 1000.0;
+    while expected == 0 || read.received < expected {
+        match time::timeout(timeout, eventloop.poll()).await {
+            Err(_) => break,
+            Ok(Ok(ClientEvent::SubAck { rejected })) => {
+                if !rejected.is_empty() {
+                    return Err(format!("Subscription to {} rejected: {}", filter, rejected.join(", ")).into());
+                }
+                read.suback_ms = Some(ms());
+            }
+            Ok(Ok(ClientEvent::Publish { .. })) => {
+                read.received += 1;
+                read.first_ms.get_or_insert_with(ms);
+                read.last_ms = Some(ms());
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(format!("Connection lost while reading {}: {}", filter, e).into()),
+        }
+    }
+
+    client.disconnect().await?;
+    let _ = time::timeout(DISCONNECT_TIMEOUT, async { while eventloop.poll().await.is_ok() {} }).await;
+    Ok(read)
+}
@@ -0,0 +1,205 @@
+use crate::benchmark::{self, Report, SearchReport, SearchStep, StopReason};
+use crate::config::{Config, SearchConfig};
+use crate::exporter;
+use crate::metrics::GlobalMetrics;
+use crate::ui::LogBuffer;
+use chrono::Utc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+type ClientHandle = JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>;
+
+/// Check one step against the SLO
+pub fn evaluate_step(
+    search: &SearchConfig,
+    rate: f64,
+    elapsed: Duration,
+    published: u64,
+    received: u64,
+    lost: u64,
+    p99_us: Option<u64>,
+) -> SearchStep {
+    let secs = elapsed.as_secs_f64().max(0.001);
+    let published_rate = published as f64 / secs;
+    let loss_pct = if received + lost > 0 { lost as f64 * 100.0 / (received + lost) as f64 } else { 0.0 };
+    let p99_ms = p99_us.map(|us| us as f64 / 1000.0);
+
+    let mut violations = Vec::new();
+    if published_rate < rate * search.min_rate_ratio {
+        violations.push(format!("publishers reached {:.0}% of the rate", published_rate * 100.0 / rate));
+    }
+    if published > 0 && received == 0 {
+        violations.push("nothing received".to_string());
+    }
+    if loss_pct > search.max_loss_pct {
+        violations.push(format!("loss {:.3}% > {}%", loss_pct, search.max_loss_pct));
+    }
+    if let Some(p99) = p99_ms.filter(|&p99| p99 > search.max_p99_ms) {
+        violations.push(format!("p99 {:.2} ms > {} ms", p99, search.max_p99_ms));
+    }
+
+    SearchStep {
+        rate,
+        published_rate,
+        received_rate: received as f64 / secs,
+        p99_ms,
+        loss_pct,
+        violations,
+    }
+}
+
+/// Run producers and matching subscribers in this process and raise the total
+/// rate by `step_rate` every `step_secs` until a step breaks the SLO.
+///
+/// Subscriber metrics are reset at the start of every step, so each step is
+/// judged on its own latency and loss.
+pub async fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let search = config.search.clone().unwrap_or_default();
+    if search.start_rate <= 0.0 || search.step_rate <= 0.0 || search.step_secs == 0 {
+        return Err("The search needs a positive start rate, step rate and step duration".into());
+    }
+    let mut config = config.clone();
+    config.target_rate = None;
+    config.phases.clear();
+    let config = Arc::new(config);
+
+    let pub_metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.publishers())));
+    let sub_metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.subscribers())));
+    let log_buffer = LogBuffer::new(100);
+
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, pub_metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (_pause_tx, pause_rx) = watch::channel(false);
+    // Producers stay idle until everything is connected
+    let (rate_tx, rate_rx) = watch::channel(0.0);
+
+    eprintln!("Starting {} producers and {} subscribers...", config.publishers(), config.subscribers());
+    let mut handles: Vec<ClientHandle> = Vec::new();
+    for id in 0..config.subscribers() {
+        let sub_client = Arc::new(sub_metrics.lock().unwrap().clients[id].clone());
+        handles.push(tokio::spawn(crate::subscriber::run(config.clone(), sub_client, shutdown_rx.clone(), log_buffer.clone())));
+    }
+    for id in 0..config.publishers() {
+        let pub_client = Arc::new(pub_metrics.lock().unwrap().clients[id].clone());
+        handles.push(tokio::spawn(crate::producer::run_producer(
+            id,
+            config.clone(),
+            pub_client,
+            shutdown_rx.clone(),
+            pause_rx.clone(),
+            Some(rate_rx.clone()),
+            log_buffer.clone(),
+        )));
+    }
+
+    let timeout = Duration::from_secs(config.connect_timeout_secs);
+    for metrics in [&pub_metrics, &sub_metrics] {
+        if benchmark::wait_for_connected(metrics, timeout).await.is_err() {
+            let _ = shutdown_tx.send(true);
+            return Err(format!("Clients did not connect within {}s", config.connect_timeout_secs).into());
+        }
+    }
+    // Give the subscriptions time to complete
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let mut steps: Vec<SearchStep> = Vec::new();
+    let mut rate = search.start_rate;
+    let stop_reason = loop {
+        if search.max_rate.is_some_and(|max| rate > max) {
+            break StopReason::MaxRate;
+        }
+
+        sub_metrics.lock().unwrap().reset();
+        let published_before = pub_metrics.lock().unwrap().get_total_published();
+        let step_started = Instant::now();
+        let _ = rate_tx.send(rate);
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break StopReason::Interrupted,
+            _ = tokio::time::sleep(Duration::from_secs(search.step_secs)) => {}
+        }
+
+        let step = {
+            let published = pub_metrics.lock().unwrap().get_total_published() - published_before;
+            let subs = sub_metrics.lock().unwrap();
+            let latency = subs.get_corrected_latency();
+            evaluate_step(
+                &search,
+                rate,
+                step_started.elapsed(),
+                published,
+                subs.get_total_received(),
+                subs.get_total_lost(),
+                (latency.count > 0).then_some(latency.p99),
+            )
+        };
+        eprintln!("{}", step.summary_line());
+        let passed = step.passed();
+        steps.push(step);
+        if !passed {
+            break StopReason::SloViolated;
+        }
+        rate += search.step_rate;
+    };
+    let elapsed = started.elapsed();
+    let _ = shutdown_tx.send(true);
+
+    for handle in handles {
+        if let Ok(Ok(Err(e))) = tokio::time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Client failed: {}", e);
+        }
+    }
+
+    let max_sustainable_rate = steps.iter().take_while(|s| s.passed()).last().map(|s| s.rate);
+    eprintln!("✅ Search completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    match max_sustainable_rate {
+        Some(rate) => eprintln!("Maximum sustainable rate: {:.2} msg/s", rate),
+        None => eprintln!("No step met the SLO"),
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-publish", &config, &pub_metrics.lock().unwrap(), started_at, elapsed, stop_reason);
+        report.search = Some(SearchReport { max_sustainable_rate, steps });
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_step() {
+        let search = SearchConfig::default();
+        let second = Duration::from_secs(1);
+
+        let step = evaluate_step(&search, 1000.0, second, 1000, 1000, 0, Some(5_000));
+        assert!(step.passed());
+        assert_eq!(step.received_rate, 1000.0);
+
+        let step = evaluate_step(&search, 1000.0, second, 900, 995, 5, Some(150_000));
+        assert_eq!(step.violations.len(), 3);
+        assert_eq!(step.loss_pct, 0.5);
+
+        let step = evaluate_step(&search, 1000.0, second, 1000, 0, 0, None);
+        assert_eq!(step.violations, vec!["nothing received".to_string()]);
+    }
+}
@@ -0,0 +1,431 @@
+use crate::topic::splitmix64;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Draws for an active topic before a bursty producer takes an inactive one
+const BURSTY_DRAWS: usize = 64;
+
+/// Topics a producer with a random selection keeps sequence numbers for before
+/// it starts a new run
+const MAX_SEQUENCED_TOPICS: usize = 10_000;
+
+/// How a producer picks the topic of its next message
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TopicSelection {
+    /// Every topic in turn, all get the same rate
+    #[default]
+    RoundRobin,
+    /// A random topic each time
+    Uniform,
+    /// Topic N (from 1) is picked in proportion to 1/N^exponent, the first topics are the hottest
+    Zipf { exponent: f64 },
+    /// The first `topics_pct` percent of the topics get `traffic_pct` percent of the messages
+    HotSet { topics_pct: f64, traffic_pct: f64 },
+    /// Every topic is active for `on_secs` and silent for `off_secs`, with its own phase
+    Bursty { on_secs: f64, off_secs: f64 },
+}
+
+#[allow(dead_code)]
+impl TopicSelection {
+    /// Parse "round-robin", "uniform", "zipf[:EXPONENT]", "hot-set[:TOPICS_PCT:TRAFFIC_PCT]"
+    /// or "bursty[:ON_SECS:OFF_SECS]"
+    pub fn parse(spec: &str) -> Result<TopicSelection, String> {
+        let mut parts = spec.split(':');
+        let kind = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let number = |i: usize| -> Result<f64, String> {
+            args[i].parse::<f64>().map_err(|_| format!("invalid number '{}' in '{}'", args[i], spec))
+        };
+
+        let selection = match (kind, args.len()) {
+            ("round-robin", 0) => TopicSelection::RoundRobin,
+            ("uniform", 0) => TopicSelection::Uniform,
+            ("zipf", 0) => TopicSelection::Zipf { exponent: 1.0 },
+            ("zipf", 1) => TopicSelection::Zipf { exponent: number(0)? },
+            ("hot-set", 0) => TopicSelection::HotSet { topics_pct: 10.0, traffic_pct: 90.0 },
+            ("hot-set", 2) => TopicSelection::HotSet { topics_pct: number(0)?, traffic_pct: number(1)? },
+            ("bursty", 0) => TopicSelection::Bursty { on_secs: 10.0, off_secs: 50.0 },
+            ("bursty", 2) => TopicSelection::Bursty { on_secs: number(0)?, off_secs: number(1)? },
+            _ => {
+                return Err(format!(
+                    "expected round-robin, uniform, zipf[:EXPONENT], hot-set[:TOPICS_PCT:TRAFFIC_PCT] or bursty[:ON_SECS:OFF_SECS], got '{}'",
+                    spec
+                ))
+            }
+        };
+        selection.validate()?;
+        Ok(selection)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            TopicSelection::Zipf { exponent } if !(exponent > 0.0 && exponent.is_finite()) => {
+                Err(format!("The Zipf exponent must be positive, got {}", exponent))
+            }
+            TopicSelection::HotSet { topics_pct, traffic_pct }
+                if !(topics_pct > 0.0 && topics_pct <= 100.0 && (0.0..=100.0).contains(&traffic_pct)) =>
+            {
+                Err(format!("The hot set needs 0 < topics_pct <= 100 and 0 <= traffic_pct <= 100, got {} and {}", topics_pct, traffic_pct))
+            }
+            TopicSelection::Bursty { on_secs, off_secs } if !(on_secs > 0.0 && off_secs >= 0.0) => {
+                Err(format!("Bursty topics need on_secs > 0 and off_secs >= 0, got {} and {}", on_secs, off_secs))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Relative share of the messages topic `index` out of `topics` gets,
+    /// bursty topics average out to the same share
+    pub fn weight(&self, index: usize, topics: usize) -> f64 {
+        match *self {
+            TopicSelection::RoundRobin | TopicSelection::Uniform | TopicSelection::Bursty { .. } => 1.0,
+            TopicSelection::Zipf { exponent } => ((index + 1) as f64).powf(-exponent),
+            TopicSelection::HotSet { topics_pct, traffic_pct } => {
+                let hot = hot_topics(topics_pct, topics);
+                match (index < hot, hot < topics) {
+                    (true, true) => traffic_pct / hot as f64,
+                    (true, false) => 1.0,
+                    (false, _) => (100.0 - traffic_pct) / (topics - hot) as f64,
+                }
+            }
+        }
+    }
+}
+
+/// Topics in the hot set, at least one
+fn hot_topics(topics_pct: f64, topics: usize) -> usize {
+    ((topics as f64 * topics_pct / 100.0).round() as usize).clamp(1, topics.max(1))
+}
+
+/// Picks the topic of every message of one producer
+pub struct TopicPicker {
+    selection: TopicSelection,
+    topics: usize,
+    /// Next topic of the round-robin
+    next: usize,
+    zipf: Option<ZipfSampler>,
+    started: Instant,
+}
+
+#[allow(dead_code)]
+impl TopicPicker {
+    pub fn new(selection: &TopicSelection, topics: usize) -> Result<Self, String> {
+        selection.validate()?;
+        let zipf = match *selection {
+            TopicSelection::Zipf { exponent } => Some(ZipfSampler::new(topics, exponent)),
+            _ => None,
+        };
+        Ok(TopicPicker { selection: selection.clone(), topics, next: 0, zipf, started: Instant::now() })
+    }
+
+    /// Topic index of the next message
+    pub fn next(&mut self) -> usize {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        self.next_at(elapsed)
+    }
+
+    /// Topic index of a message `elapsed` seconds into the run
+    pub fn next_at(&mut self, elapsed: f64) -> usize {
+        match self.selection {
+            TopicSelection::RoundRobin => {
+                let topic = self.next % self.topics;
+                self.next = topic + 1;
+                topic
+            }
+            TopicSelection::Uniform => fastrand::usize(..self.topics),
+            TopicSelection::Zipf { .. } => self.zipf.as_ref().map_or(0, |zipf| zipf.sample() - 1),
+            TopicSelection::HotSet { topics_pct, traffic_pct } => {
+                let hot = hot_topics(topics_pct, self.topics);
+                if hot == self.topics || fastrand::f64() * 100.0 < traffic_pct {
+                    fastrand::usize(..hot)
+                } else {
+                    fastrand::usize(hot..self.topics)
+                }
+            }
+            TopicSelection::Bursty { on_secs, off_secs } => {
+                // Draw until an active topic comes up, the active share is on / (on + off)
+                let mut topic = fastrand::usize(..self.topics);
+                for _ in 1..BURSTY_DRAWS {
+                    if is_active(topic, elapsed, on_secs, off_secs) {
+                        break;
+                    }
+                    topic = fastrand::usize(..self.topics);
+                }
+                topic
+            }
+        }
+    }
+}
+
+/// Picks the topic of every message together with its sequence number on that
+/// topic, and the run id the numbers belong to.
+///
+/// Round-robin derives the sequence from the messages published so far and
+/// keeps nothing per topic. The other selections remember the last sequence of
+/// the topics they published to; once that reaches `max_topics` topics they
+/// forget them all and start a new run, which subscribers see as a restarted
+/// producer rather than as duplicates.
+#[allow(dead_code)]
+pub struct TopicSequencer {
+    picker: TopicPicker,
+    topics: u64,
+    /// Messages published, the round-robin position
+    published: u64,
+    /// Last sequence per topic, `None` for round-robin
+    last: Option<HashMap<usize, u64>>,
+    max_topics: usize,
+    run: String,
+}
+
+#[allow(dead_code)]
+impl TopicSequencer {
+    pub fn new(selection: &TopicSelection, topics: usize) -> Result<Self, String> {
+        TopicSequencer::with_max_topics(selection, topics, MAX_SEQUENCED_TOPICS)
+    }
+
+    pub fn with_max_topics(selection: &TopicSelection, topics: usize, max_topics: usize) -> Result<Self, String> {
+        let last = (*selection != TopicSelection::RoundRobin).then(HashMap::new);
+        Ok(TopicSequencer {
+            picker: TopicPicker::new(selection, topics)?,
+            topics: topics.max(1) as u64,
+            published: 0,
+            last,
+            max_topics,
+            run: new_run_id(),
+        })
+    }
+
+    /// Topic index and sequence number of the next message, may start a new run
+    pub fn next(&mut self) -> (usize, u64) {
+        let Some(last) = self.last.as_mut() else {
+            return ((self.published % self.topics) as usize, self.published / self.topics + 1);
+        };
+        let topic = self.picker.next();
+        match last.get(&topic) {
+            Some(seq) => (topic, seq + 1),
+            None => {
+                if last.len() >= self.max_topics {
+                    last.clear();
+                    self.run = new_run_id();
+                }
+                (topic, 1)
+            }
+        }
+    }
+
+    /// The message `next` returned went out, a failed one is simply not reported
+    pub fn published(&mut self, topic: usize, seq: u64) {
+        self.published += 1;
+        if let Some(last) = self.last.as_mut() {
+            last.insert(topic, seq);
+        }
+    }
+
+    /// Identifies this producer run in the payloads, subscribers use it to tell
+    /// a restarted producer (sequences start over) apart from lost messages
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+}
+
+fn new_run_id() -> String {
+    Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+/// Whether a bursty topic is in its active window, each topic's cycle is
+/// shifted by a hash of its index so the active topics change all the time
+fn is_active(topic: usize, elapsed: f64, on_secs: f64, off_secs: f64) -> bool {
+    let period = on_secs + off_secs;
+    // Top 53 bits as a uniform value in [0, 1)
+    let phase = (splitmix64(topic as u64) >> 11) as f64 / (1u64 << 53) as f64 * period;
+    (elapsed + phase) % period < on_secs
+}
+
+/// Zipf distribution over 1..=n by rejection-inversion (Hörmann and Derflinger),
+/// constant time per sample and no table, so it works for millions of topics
+struct ZipfSampler {
+    n: f64,
+    exponent: f64,
+    h_integral_x1: f64,
+    h_integral_n: f64,
+    s: f64,
+}
+
+impl ZipfSampler {
+    fn new(n: usize, exponent: f64) -> Self {
+        let mut sampler = ZipfSampler { n: n.max(1) as f64, exponent, h_integral_x1: 0.0, h_integral_n: 0.0, s: 0.0 };
+        sampler.h_integral_x1 = sampler.h_integral(1.5) - 1.0;
+        sampler.h_integral_n = sampler.h_integral(sampler.n + 0.5);
+        sampler.s = 2.0 - sampler.h_integral_inverse(sampler.h_integral(2.5) - sampler.h(2.0));
+        sampler
+    }
+
+    /// A rank from 1 (most frequent) to n
+    fn sample(&self) -> usize {
+        loop {
+            let u = self.h_integral_n + fastrand::f64() * (self.h_integral_x1 - self.h_integral_n);
+            let x = self.h_integral_inverse(u);
+            let k = (x + 0.5).floor().clamp(1.0, self.n);
+            if k - x <= self.s || u >= self.h_integral(k + 0.5) - self.h(k) {
+                return k as usize;
+            }
+        }
+    }
+
+    fn h(&self, x: f64) -> f64 {
+        (-self.exponent * x.ln()).exp()
+    }
+
+    fn h_integral(&self, x: f64) -> f64 {
+        let log_x = x.ln();
+        helper2((1.0 - self.exponent) * log_x) * log_x
+    }
+
+    fn h_integral_inverse(&self, x: f64) -> f64 {
+        let t = (x * (1.0 - self.exponent)).max(-1.0);
+        (helper1(t) * x).exp()
+    }
+}
+
+/// ln(1 + x) / x, accurate near 0
+fn helper1(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.ln_1p() / x
+    } else {
+        1.0 - x * (0.5 - x * (1.0 / 3.0 - 0.25 * x))
+    }
+}
+
+/// (e^x - 1) / x, accurate near 0
+fn helper2(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.exp_m1() / x
+    } else {
+        1.0 + x * 0.5 * (1.0 + x / 3.0 * (1.0 + 0.25 * x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pick_counts(picker: &mut TopicPicker, topics: usize, draws: usize) -> Vec<usize> {
+        let mut counts = vec![0; topics];
+        for _ in 0..draws {
+            counts[picker.next_at(0.0)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_parse_topic_selection() {
+        assert_eq!(TopicSelection::parse("round-robin"), Ok(TopicSelection::RoundRobin));
+        assert_eq!(TopicSelection::parse("zipf"), Ok(TopicSelection::Zipf { exponent: 1.0 }));
+        assert_eq!(TopicSelection::parse("zipf:1.2"), Ok(TopicSelection::Zipf { exponent: 1.2 }));
+        assert_eq!(
+            TopicSelection::parse("hot-set:5:95"),
+            Ok(TopicSelection::HotSet { topics_pct: 5.0, traffic_pct: 95.0 })
+        );
+        assert_eq!(TopicSelection::parse("bursty:1:9"), Ok(TopicSelection::Bursty { on_secs: 1.0, off_secs: 9.0 }));
+        assert!(TopicSelection::parse("zipf:0").is_err());
+        assert!(TopicSelection::parse("hot-set:5").is_err());
+        assert!(TopicSelection::parse("hot-set:0:90").is_err());
+        assert!(TopicSelection::parse("bursty:0:10").is_err());
+        assert!(TopicSelection::parse("gaussian").is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut picker = TopicPicker::new(&TopicSelection::RoundRobin, 3).unwrap();
+        let picked: Vec<usize> = (0..7).map(|_| picker.next_at(0.0)).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_zipf() {
+        let mut picker = TopicPicker::new(&TopicSelection::Zipf { exponent: 1.0 }, 1000).unwrap();
+        let counts = pick_counts(&mut picker, 1000, 100_000);
+        // P(1) = 1 / H(1000) ~ 13.4%, P(2) half of that
+        assert!((12_000..15_000).contains(&counts[0]), "{}", counts[0]);
+        assert!((5_800..7_600).contains(&counts[1]), "{}", counts[1]);
+        assert!(counts[..10].iter().sum::<usize>() > counts[10..].iter().sum::<usize>() / 2);
+
+        // Steeper exponents concentrate more, and a single topic always works
+        let mut steep = TopicPicker::new(&TopicSelection::Zipf { exponent: 2.5 }, 1000).unwrap();
+        assert!(pick_counts(&mut steep, 1000, 10_000)[0] > 7_000);
+        let mut single = TopicPicker::new(&TopicSelection::Zipf { exponent: 1.0 }, 1).unwrap();
+        assert_eq!(single.next_at(0.0), 0);
+    }
+
+    #[test]
+    fn test_hot_set() {
+        let selection = TopicSelection::HotSet { topics_pct: 10.0, traffic_pct: 90.0 };
+        let mut picker = TopicPicker::new(&selection, 100).unwrap();
+        let counts = pick_counts(&mut picker, 100, 10_000);
+        let hot: usize = counts[..10].iter().sum();
+        assert!((8_700..9_300).contains(&hot), "{}", hot);
+
+        let weights: f64 = (0..100).map(|index| selection.weight(index, 100)).sum();
+        assert!((selection.weight(0, 100) * 10.0 / weights - 0.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_bursty() {
+        let (on_secs, off_secs) = (1.0, 9.0);
+        // A tenth of the topics are active at any time, each for a tenth of the period
+        let active = |elapsed: f64| (0..10_000).filter(|&topic| is_active(topic, elapsed, on_secs, off_secs)).count();
+        assert!((800..1_200).contains(&active(0.0)));
+        assert!((800..1_200).contains(&active(5.5)));
+        let windows = (0..100).filter(|&step| is_active(7, step as f64 * 0.1, on_secs, off_secs)).count();
+        assert!((9..=11).contains(&windows));
+
+        let mut picker = TopicPicker::new(&TopicSelection::Bursty { on_secs, off_secs }, 10_000).unwrap();
+        let picked = (0..1000).filter(|_| is_active(picker.next_at(3.0), 3.0, on_secs, off_secs)).count();
+        assert!(picked > 990);
+    }
+
+    #[test]
+    fn test_round_robin_sequences_need_no_state() {
+        let mut sequencer = TopicSequencer::new(&TopicSelection::RoundRobin, 3).unwrap();
+        let mut sent = Vec::new();
+        for _ in 0..7 {
+            let (topic, seq) = sequencer.next();
+            sent.push((topic, seq));
+            sequencer.published(topic, seq);
+        }
+        assert_eq!(sent, vec![(0, 1), (1, 1), (2, 1), (0, 2), (1, 2), (2, 2), (0, 3)]);
+        // A failed publish is retried with the same topic and sequence
+        assert_eq!(sequencer.next(), (1, 3));
+        assert_eq!(sequencer.next(), (1, 3));
+        assert!(sequencer.last.is_none());
+    }
+
+    #[test]
+    fn test_random_sequences_start_a_new_run_when_full() {
+        let mut sequencer = TopicSequencer::with_max_topics(&TopicSelection::Uniform, 1000, 5).unwrap();
+        let run = sequencer.run().to_string();
+        let mut seen = HashMap::new();
+        while seen.len() < 5 {
+            let (topic, seq) = sequencer.next();
+            assert_eq!(seq, seen.get(&topic).copied().unwrap_or(0) + 1);
+            seen.insert(topic, seq);
+            sequencer.published(topic, seq);
+        }
+        assert_eq!(sequencer.run(), run);
+        // The sixth topic starts over in a new run
+        let (topic, seq) = loop {
+            let (topic, seq) = sequencer.next();
+            if !seen.contains_key(&topic) {
+                break (topic, seq);
+            }
+            sequencer.published(topic, seq);
+        };
+        assert_eq!(seq, 1);
+        assert_ne!(sequencer.run(), run);
+        sequencer.published(topic, seq);
+        assert_eq!(sequencer.last.as_ref().map(HashMap::len), Some(1));
+    }
+}
@@ -0,0 +1,246 @@
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Gaps remembered per stream so late arrivals can be told apart from duplicates.
+/// Older gaps beyond this are forgotten and stay counted as lost.
+const MAX_TRACKED_GAPS: usize = 10_000;
+
+/// What a received sequence number means for its stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceOutcome {
+    /// First message seen from this producer run on this topic
+    First,
+    /// Exactly the next expected sequence
+    InOrder,
+    /// Sequence jumped ahead, the given number of messages are missing
+    Gap(u64),
+    /// A message that was counted as missing arrived late
+    OutOfOrder,
+    /// A sequence that was already received
+    Duplicate,
+}
+
+#[allow(dead_code)]
+struct StreamState {
+    last_seq: u64,
+    missing: BTreeSet<u64>,
+}
+
+/// Streams tracked at most. Once half of them have been seen since the last
+/// rotation, the streams not seen since the one before are forgotten; a
+/// forgotten stream starts over as if its producer had restarted.
+const MAX_TRACKED_STREAMS: usize = 200_000;
+
+/// topic -> run id -> state, nested so lookups don't allocate
+type Streams = HashMap<String, HashMap<String, StreamState>>;
+
+/// Tracks expected sequence numbers per (topic, producer run)
+///
+/// Producers stamp every message with a run id and a per-topic sequence that
+/// starts at 1, so a new run id (producer restarted) starts a fresh stream
+/// instead of being reported as a gap.
+#[allow(dead_code)]
+pub struct SequenceTracker {
+    // Streams seen since the last rotation, and the ones seen only before it
+    recent: Streams,
+    older: Streams,
+    recent_streams: usize,
+    max_streams: usize,
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        SequenceTracker::new()
+    }
+}
+
+#[allow(dead_code)]
+impl SequenceTracker {
+    pub fn new() -> Self {
+        SequenceTracker::with_max_streams(MAX_TRACKED_STREAMS)
+    }
+
+    pub fn with_max_streams(max_streams: usize) -> Self {
+        SequenceTracker { recent: HashMap::new(), older: HashMap::new(), recent_streams: 0, max_streams }
+    }
+
+    pub fn track(&mut self, topic: &str, run: &str, seq: u64) -> SequenceOutcome {
+        // Not seen since the last rotation: rotate if due, then bring the stream
+        // back from `older` or start it
+        if !self.recent.get(topic).is_some_and(|runs| runs.contains_key(run)) {
+            if self.recent_streams >= (self.max_streams / 2).max(1) {
+                self.older = std::mem::take(&mut self.recent);
+                self.recent_streams = 0;
+            }
+            let older = self.older.get_mut(topic).and_then(|runs| runs.remove(run));
+            if self.older.get(topic).is_some_and(|runs| runs.is_empty()) {
+                self.older.remove(topic);
+            }
+            if !self.recent.contains_key(topic) {
+                self.recent.insert(topic.to_string(), HashMap::new());
+            }
+            let runs = self.recent.get_mut(topic).expect("inserted above");
+            self.recent_streams += 1;
+            match older {
+                Some(state) => {
+                    runs.insert(run.to_string(), state);
+                }
+                None => {
+                    runs.insert(run.to_string(), StreamState { last_seq: seq, missing: BTreeSet::new() });
+                    return SequenceOutcome::First;
+                }
+            }
+        }
+        let state = self.recent.get_mut(topic).and_then(|runs| runs.get_mut(run)).expect("tracked above");
+
+        if seq == state.last_seq + 1 {
+            state.last_seq = seq;
+            SequenceOutcome::InOrder
+        } else if seq > state.last_seq {
+            // Gap(missing) counts every missing message as lost, only the
+            // newest ones are remembered so a wild jump stays cheap
+            let missing = seq - state.last_seq - 1;
+            let first_tracked = (state.last_seq + 1).max(seq.saturating_sub(MAX_TRACKED_GAPS as u64));
+            for gap in first_tracked..seq {
+                if state.missing.len() >= MAX_TRACKED_GAPS {
+                    state.missing.pop_first();
+                }
+                state.missing.insert(gap);
+            }
+            state.last_seq = seq;
+            SequenceOutcome::Gap(missing)
+        } else if state.missing.remove(&seq) {
+            SequenceOutcome::OutOfOrder
+        } else {
+            SequenceOutcome::Duplicate
+        }
+    }
+}
+
+/// What a resumed session's queue contributed to one received message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueOutcome {
+    /// Published while we were offline and delivered from the session queue
+    pub queued: bool,
+    /// Messages missing in front of this one that the queue should have held
+    pub lost: u64,
+}
+
+/// Splits what arrives after resuming a persistent session into messages the
+/// broker queued while we were offline and live traffic.
+///
+/// A message sent before the resume came from the queue. Until the first live
+/// message on a topic arrives, sequence gaps on that topic are queued messages
+/// the broker dropped.
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct QueueTracker {
+    resumed_at: Option<DateTime<Utc>>,
+    live_topics: HashSet<String>,
+}
+
+#[allow(dead_code)]
+impl QueueTracker {
+    pub fn new() -> Self {
+        QueueTracker::default()
+    }
+
+    /// The session was resumed at `at`, everything sent earlier is queued
+    pub fn resume(&mut self, at: DateTime<Utc>) {
+        self.resumed_at = Some(at);
+        self.live_topics.clear();
+    }
+
+    pub fn track(&mut self, topic: &str, sent_at: Option<DateTime<Utc>>, outcome: Option<SequenceOutcome>) -> QueueOutcome {
+        let resumed_at = match self.resumed_at {
+            Some(at) if !self.live_topics.contains(topic) => at,
+            _ => return QueueOutcome::default(),
+        };
+        let queued = sent_at.is_some_and(|sent_at| sent_at < resumed_at);
+        if !queued {
+            self.live_topics.insert(topic.to_string());
+        }
+        let lost = match outcome {
+            Some(SequenceOutcome::Gap(missing)) => missing,
+            _ => 0,
+        };
+        QueueOutcome { queued, lost }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_order_and_gap() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.track("t", "run", 1), SequenceOutcome::First);
+        assert_eq!(tracker.track("t", "run", 2), SequenceOutcome::InOrder);
+        assert_eq!(tracker.track("t", "run", 5), SequenceOutcome::Gap(2));
+        assert_eq!(tracker.track("t", "run", 6), SequenceOutcome::InOrder);
+    }
+
+    #[test]
+    fn test_late_arrival_and_duplicate() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track("t", "run", 1);
+        tracker.track("t", "run", 3);
+        assert_eq!(tracker.track("t", "run", 2), SequenceOutcome::OutOfOrder);
+        assert_eq!(tracker.track("t", "run", 2), SequenceOutcome::Duplicate);
+        assert_eq!(tracker.track("t", "run", 3), SequenceOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_huge_gap_tracks_only_the_newest() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track("t", "run", 1);
+        assert_eq!(tracker.track("t", "run", 1_000_000_000_001), SequenceOutcome::Gap(999_999_999_999));
+        assert_eq!(tracker.track("t", "run", 1_000_000_000_000), SequenceOutcome::OutOfOrder);
+        // Beyond the tracked gaps a late arrival can't be told from a duplicate
+        assert_eq!(tracker.track("t", "run", 2), SequenceOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_least_recently_seen_streams_are_forgotten() {
+        let mut tracker = SequenceTracker::with_max_streams(4);
+        assert_eq!(tracker.track("a", "run", 1), SequenceOutcome::First);
+        assert_eq!(tracker.track("b", "run", 1), SequenceOutcome::First);
+        // Rotates a and b out, seeing a again brings it back
+        assert_eq!(tracker.track("c", "run", 1), SequenceOutcome::First);
+        assert_eq!(tracker.track("a", "run", 2), SequenceOutcome::InOrder);
+        // Rotates c and a out and forgets b, which starts over
+        assert_eq!(tracker.track("d", "run", 1), SequenceOutcome::First);
+        assert_eq!(tracker.track("a", "run", 3), SequenceOutcome::InOrder);
+        assert_eq!(tracker.track("b", "run", 5), SequenceOutcome::First);
+        assert!(tracker.recent.len() + tracker.older.len() <= 4);
+    }
+
+    #[test]
+    fn test_streams_are_independent() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track("a", "run1", 10);
+        assert_eq!(tracker.track("b", "run1", 1), SequenceOutcome::First);
+        // A restarted producer gets a new run id and starts over at 1
+        assert_eq!(tracker.track("a", "run2", 1), SequenceOutcome::First);
+        assert_eq!(tracker.track("a", "run1", 11), SequenceOutcome::InOrder);
+    }
+
+    #[test]
+    fn test_queue_after_resume() {
+        let resumed_at = Utc::now();
+        let before = resumed_at - chrono::Duration::seconds(5);
+        let after = resumed_at + chrono::Duration::seconds(1);
+        let mut queue = QueueTracker::new();
+        // Nothing is queued before a resume
+        assert_eq!(queue.track("t", Some(before), Some(SequenceOutcome::Gap(3))), QueueOutcome::default());
+
+        queue.resume(resumed_at);
+        assert_eq!(queue.track("t", Some(before), Some(SequenceOutcome::InOrder)), QueueOutcome { queued: true, lost: 0 });
+        assert_eq!(queue.track("t", Some(before), Some(SequenceOutcome::Gap(2))), QueueOutcome { queued: true, lost: 2 });
+        // The first live message closes the queue for its topic, a gap in front of it was dropped from the queue
+        assert_eq!(queue.track("t", Some(after), Some(SequenceOutcome::Gap(4))), QueueOutcome { queued: false, lost: 4 });
+        assert_eq!(queue.track("t", Some(after), Some(SequenceOutcome::Gap(1))), QueueOutcome::default());
+        assert_eq!(queue.track("u", Some(before), None), QueueOutcome { queued: true, lost: 0 });
+    }
+}
@@ -0,0 +1,167 @@
+use crate::benchmark::{self, Report, StopReason};
+use crate::config::Config;
+use crate::connection::{self, ClientEvent};
+use crate::exporter;
+use crate::metrics::{ClientMetrics, GlobalMetrics};
+use chrono::Utc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// Pause before a storm client retries a failed connect
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Open `connect_storm` connections (at `connect_rate` per second, or all at
+/// once) without publishing and measure CONNACK latency, failed attempts and
+/// the time until every client is connected.
+///
+/// Without a duration the run ends once all clients are connected or
+/// `connect_timeout_secs` passes. With a duration the connections are held and
+/// dropped ones reconnect, so restarting or failing over the broker during the
+/// run measures a reconnect storm.
+pub async fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let clients = config.connect_storm.unwrap_or(config.num_producers);
+    let config = Arc::new(config.clone());
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(clients)));
+
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "publisher").await?),
+        None => None,
+    };
+
+    match config.connect_rate {
+        Some(rate) => eprintln!("⚡ Connect storm: {} clients at {} per second", clients, rate),
+        None => eprintln!("⚡ Connect storm: {} clients at once", clients),
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let started_at = Utc::now();
+    let started = Instant::now();
+
+    let handles: Vec<JoinHandle<Result<(), connection::BoxError>>> = (0..clients)
+        .map(|index| {
+            let client_metrics = metrics.lock().unwrap().clients[index].clone();
+            tokio::spawn(storm_client(index, config.clone(), client_metrics, shutdown_rx.clone()))
+        })
+        .collect();
+
+    let timeout = Duration::from_secs(config.connect_timeout_secs);
+    let mut all_connected = None;
+    let mut stop_reason = tokio::select! {
+        _ = tokio::signal::ctrl_c() => StopReason::Interrupted,
+        result = benchmark::wait_for_connected(&metrics, timeout) => match result {
+            Ok(elapsed) => {
+                all_connected = Some(elapsed.as_secs_f64());
+                eprintln!("✅ All {} clients connected in {:.2}s", clients, elapsed.as_secs_f64());
+                StopReason::AllConnected
+            }
+            Err(connected) => {
+                eprintln!("⚠️  Only {}/{} clients connected after {}s", connected, clients, config.connect_timeout_secs);
+                StopReason::ConnectTimeout
+            }
+        },
+    };
+
+    // Hold the connections for the rest of the duration
+    if let (Some(secs), StopReason::AllConnected) = (config.duration_secs, stop_reason) {
+        eprintln!("Holding connections for {}s...", secs);
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => stop_reason = StopReason::Interrupted,
+            _ = time::sleep_until(started + Duration::from_secs(secs)) => stop_reason = StopReason::Duration,
+        }
+    }
+    let elapsed = started.elapsed();
+    let _ = shutdown_tx.send(true);
+
+    for handle in handles {
+        if let Ok(Ok(Err(e))) = time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Client failed: {}", e);
+        }
+    }
+
+    let final_metrics = metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
+    eprintln!("{}", benchmark::connect_summary(&final_metrics, all_connected));
+    eprintln!(
+        "Failed connection attempts: {} | Reconnects: {}",
+        final_metrics.get_total_connection_errors(),
+        final_metrics.get_total_reconnects()
+    );
+    let reason_codes = final_metrics.get_reason_codes();
+    if !reason_codes.is_empty() {
+        let codes: Vec<String> = reason_codes.iter().map(|(code, count)| format!("{}={}", code, count)).collect();
+        eprintln!("Reason codes: {}", codes.join(", "));
+    }
+
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-publish", &config, &final_metrics, started_at, elapsed, stop_reason);
+        report.all_connected_secs = all_connected;
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
+    Ok(())
+}
+
+/// Connect, then keep the connection alive (reconnecting when it drops) until shutdown
+async fn storm_client(
+    index: usize,
+    config: Arc<Config>,
+    metrics: ClientMetrics,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<(), connection::BoxError> {
+    let mqttoptions = connection::mqtt_options(&config, connection::client_id(&config, "storm", index + 1), index + 1)?;
+
+    tokio::select! {
+        _ = shutdown_rx.changed() => return Ok(()),
+        _ = time::sleep(connection::connect_delay(&config, index)) => {}
+    }
+
+    let mut has_connected = false;
+    loop {
+        let connect_started = Instant::now();
+        let (client, mut eventloop) = mqttoptions.connect(10);
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.changed() => {
+                    let _ = client.disconnect().await;
+                    metrics.set_connected(false);
+                    return Ok(());
+                }
+                event = eventloop.poll() => match event {
+                    Ok(ClientEvent::ConnAck { .. }) => {
+                        metrics.record_connect_latency(connect_started.elapsed().as_micros() as u64);
+                        if has_connected {
+                            metrics.increment_reconnects();
+                        }
+                        has_connected = true;
+                        metrics.set_connected(true);
+                    }
+                    Ok(ClientEvent::Disconnect) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        if let Some(code) = e.reason_code() {
+                            metrics.record_reason_code(&code);
+                        }
+                        metrics.increment_connection_errors();
+                        break;
+                    }
+                },
+            }
+        }
+
+        metrics.set_connected(false);
+        tokio::select! {
+            _ = shutdown_rx.changed() => return Ok(()),
+            _ = time::sleep(RETRY_DELAY) => {}
+        }
+    }
+}
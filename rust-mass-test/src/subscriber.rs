@@ -1,54 +1,83 @@
-use rumqttc::{AsyncClient, Event, MqttOptions, QoS};
+use chrono::Utc;
+use rumqttc::QoS;
 use tokio::time;
 use std::time::Duration;
+use std::collections::BTreeSet;
 use std::sync::Arc;
 use tokio::sync::watch;
-use uuid::Uuid;
 
-use crate::config::Config;
+use crate::config::{Config, Topology};
+use crate::connection::{self, ClientEvent};
 use crate::metrics::ClientMetrics;
-use crate::topic::TopicGenerator;
+use crate::payload::PayloadHeader;
+use crate::sequence::{QueueTracker, SequenceTracker};
+use crate::topic::{TopicGenerator, TopicList};
 use crate::ui::LogBuffer;
 
 pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_rx: watch::Receiver<bool>, log_buffer: LogBuffer) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client_id = format!("sub-{}", Uuid::new_v4().to_string());
-
-    // Generate topic generator (once, reuse for reconnections)
-    let topic_generator = TopicGenerator::new(
-        config.topic_prefix.clone(),
-        metrics.id + 1,
-        config.topics_per_node,
-        config.max_depth,
-    );
+    let client_id = connection::client_id(&config, "sub", metrics.id + 1);
 
     // Get topics to subscribe to (once, reuse for reconnections)
-    let (all_topics, is_wildcard) = if config.use_leafs {
-        if config.use_wildcard {
-            (topic_generator.generate_wildcard_subscriptions(), true)
-        } else {
-            (topic_generator.generate_leaves_only(), false)
+    let (all_topics, is_wildcard) = if let Some(shared) = &config.shared {
+        (vec![TopicList::from(vec![shared.subscription(metrics.id)])], true)
+    } else if config.topology == Topology::FanIn {
+        // The single wildcard of every producer tree rather than "#", so other
+        // traffic on the broker does not count
+        let mut filters = BTreeSet::new();
+        for tree in config.topology.subscribed_trees(metrics.id, config.num_producers) {
+            filters.extend(TopicGenerator::from_config(&config, tree)?.generate_single_wildcard());
         }
+        (vec![TopicList::from(filters.into_iter().collect::<Vec<_>>())], true)
     } else {
-        if config.use_wildcard {
-            (topic_generator.generate_single_wildcard(), true)
-        } else {
-            (topic_generator.generate_all(), false)
+        let mut all_topics = Vec::new();
+        let mut is_wildcard = false;
+        for tree in config.topology.subscribed_trees(metrics.id, config.num_producers) {
+            let (topics, wildcard) = tree_subscriptions(&config, tree)?;
+            all_topics.push(topics);
+            is_wildcard |= wildcard;
         }
+        (all_topics, is_wildcard)
     };
 
-    let total_topics_count = all_topics.len();
-
     // Select a percentage of topics to subscribe to
-    let num_topics_to_subscribe = (total_topics_count as f64 * (config.subscribe_percentage as f64 / 100.0)).round() as usize;
-    let mut topics_to_subscribe: Vec<String> = all_topics
-        .into_iter()
-        .collect();
-    if !is_wildcard {
-        fastrand::shuffle(&mut topics_to_subscribe);
+    let topics_to_subscribe = Subscriptions::new(all_topics, !is_wildcard, config.subscribe_percentage as f64);
+    let total_topics_count = topics_to_subscribe.total;
+    let sub_count = topics_to_subscribe.count;
+
+    // Expected sequence per topic and producer run, kept across reconnects so
+    // messages lost while we were disconnected show up as gaps. Members of a
+    // share group only see part of every stream, so they don't track them.
+    let mut sequences = config.shared.is_none().then(SequenceTracker::new);
+    // Tells queued from live messages after a persistent session is resumed
+    let mut queue = QueueTracker::new();
+
+    if config.subscribe_qos_mix.is_some() {
+        let count = |level: QoS| (0..sub_count).filter(|&index| subscription_qos(&config, index, sub_count) == level).count();
+        log_buffer.log(format!(
+            "Subscriber {}: QoS mix {}/{}/{} subscriptions at QoS 0/1/2",
+            metrics.id + 1,
+            count(QoS::AtMostOnce),
+            count(QoS::AtLeastOnce),
+            count(QoS::ExactlyOnce)
+        ));
     }
-    topics_to_subscribe.truncate(num_topics_to_subscribe);
 
-    let sub_count = topics_to_subscribe.len();
+    // Set after the first CONNACK so later ones count as reconnects
+    let mut has_connected = false;
+    // The offline scenario runs once, on the first connection
+    let mut offline_pending = config.offline_secs.is_some();
+
+    // Create MQTT connection options (reused for reconnections)
+    let mut mqttoptions = connection::mqtt_options(&config, client_id.clone(), metrics.id + 1)?;
+    // Accept whatever payload size the producers were configured with
+    mqttoptions.set_max_packet_size(connection::MAX_PACKET_SIZE);
+    let broker = connection::broker_url(&config)?;
+
+    // Staggered start when a connect rate is set
+    tokio::select! {
+        _ = shutdown_rx.changed() => return Ok(()),
+        _ = time::sleep(connection::connect_delay(&config, metrics.id)) => {}
+    }
 
     // Outer loop for reconnection attempts
     loop {
@@ -58,21 +87,22 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
             return Ok(());
         }
 
-        log_buffer.log(format!("Subscriber {}: [DEBUG] Connecting to {}:{} with client ID {}", metrics.id + 1, &config.broker_host, config.broker_port, client_id));
-        let mut mqttoptions = MqttOptions::new(client_id.clone(), &config.broker_host, config.broker_port);
-        mqttoptions.set_keep_alive(Duration::from_secs(120));
-        log_buffer.log(format!("Subscriber {}: [DEBUG] MqttOptions configured", metrics.id + 1));
-
-        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+        log_buffer.log(format!("Subscriber {}: [DEBUG] Connecting to {}:{} with client ID {}", metrics.id + 1, broker.host, broker.port, client_id));
+        let connect_started = std::time::Instant::now();
+        let (client, mut eventloop) = mqttoptions.connect(10);
         log_buffer.log(format!("Subscriber {}: [DEBUG] Client created, waiting for events", metrics.id + 1));
 
         // Debug output to show what we're actually subscribing to
-        if config.use_leafs && config.use_wildcard {
-            log_buffer.log(format!("Subscriber {}: Using WILDCARD at parent-of-leaf level: {:?}", metrics.id + 1, topics_to_subscribe));
+        if config.shared.is_some() {
+            log_buffer.log(format!("Subscriber {}: Using SHARED subscription: {:?}", metrics.id + 1, topics_to_subscribe.all()));
+        } else if config.topology == Topology::FanIn {
+            log_buffer.log(format!("Subscriber {}: Using FAN-IN subscription to every tree: {:?}", metrics.id + 1, topics_to_subscribe.all()));
+        } else if config.use_leafs && config.use_wildcard {
+            log_buffer.log(format!("Subscriber {}: Using WILDCARD at parent-of-leaf level: {:?}", metrics.id + 1, topics_to_subscribe.all()));
         } else if config.use_leafs {
             log_buffer.log(format!("Subscriber {}: Using individual LEAF topics ({} total)", metrics.id + 1, sub_count));
         } else if config.use_wildcard {
-            log_buffer.log(format!("Subscriber {}: Using WILDCARD subscription at base level: {:?}", metrics.id + 1, topics_to_subscribe));
+            log_buffer.log(format!("Subscriber {}: Using WILDCARD subscription at base level: {:?}", metrics.id + 1, topics_to_subscribe.all()));
         } else {
             log_buffer.log(format!("Subscriber {}: Using ALL topics ({} total)", metrics.id + 1, sub_count));
         }
@@ -96,15 +126,30 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
                 }
                 event = eventloop.poll() => {
                     match event {
-                        Ok(Event::Incoming(rumqttc::Packet::ConnAck(ack))) => {
-                            log_buffer.log(format!("Subscriber {}: ✅ Connected to broker: {:?}", metrics.id + 1, ack));
+                        Ok(ClientEvent::ConnAck { session_present, .. }) => {
+                            log_buffer.log(format!("Subscriber {}: ✅ Connected to broker (session present: {})", metrics.id + 1, session_present));
+                            metrics.record_connect_latency(connect_started.elapsed().as_micros() as u64);
+                            if has_connected {
+                                metrics.increment_reconnects();
+                                if !config.clean_session {
+                                    if session_present {
+                                        queue.resume(Utc::now());
+                                    } else {
+                                        log_buffer.log(format!("Subscriber {}: ⚠️  Broker did not keep the session, queued messages are gone", metrics.id + 1));
+                                        metrics.increment_sessions_lost();
+                                    }
+                                }
+                            }
+                            has_connected = true;
+                            // QoS 2 flows of the last connection are not completed
+                            metrics.clear_inflight();
                             metrics.set_connected(true);
                             let is_connected = metrics.is_connected();
                             log_buffer.log(format!("Subscriber {}: [DEBUG] Connected flag set: {}", metrics.id + 1, is_connected));
                             // After ConnAck, subscribe to the first topic
                             if topic_index < sub_count {
-                                let topic = &topics_to_subscribe[topic_index];
-                                match client.subscribe(topic, QoS::AtMostOnce).await {
+                                let topic = topics_to_subscribe.get(topic_index);
+                                match client.subscribe(&topic, subscription_qos(&config, topic_index, sub_count)).await {
                                     Ok(_) => {},
                                     Err(e) => {
                                         log_buffer.log(format!("Subscriber {}: ❌ Failed to subscribe to {}: {:?}", metrics.id + 1, topic, e));
@@ -113,8 +158,12 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
                                 topic_index += 1;
                             }
                         }
-                        Ok(Event::Incoming(rumqttc::Packet::SubAck(_suback))) => {
+                        Ok(ClientEvent::SubAck { rejected }) => {
                             subscribed_count += 1;
+                            for reason in &rejected {
+                                log_buffer.log(format!("Subscriber {}: ❌ Subscription rejected: {}", metrics.id + 1, reason));
+                                metrics.record_reason_code(reason);
+                            }
                             log_buffer.log(format!("Subscriber {}: ✅ Received SUBACK {}/{}", metrics.id + 1, subscribed_count, sub_count));
 
                             if subscribed_count >= sub_count {
@@ -125,8 +174,8 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
 
                             // Subscribe to next topic
                             if topic_index < sub_count {
-                                let topic = &topics_to_subscribe[topic_index];
-                                match client.subscribe(topic, QoS::AtMostOnce).await {
+                                let topic = topics_to_subscribe.get(topic_index);
+                                match client.subscribe(&topic, subscription_qos(&config, topic_index, sub_count)).await {
                                     Ok(_) => {},
                                     Err(e) => {
                                         log_buffer.log(format!("Subscriber {}: ❌ Failed to subscribe to {}: {:?}", metrics.id + 1, topic, e));
@@ -135,14 +184,19 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
                                 topic_index += 1;
                             }
                         }
-                        Ok(Event::Incoming(_packet)) => {
-                            // Ignore other packets during subscription phase
+                        Ok(ClientEvent::Publish { topic, payload }) => {
+                            // A resumed session delivers its queue before the SUBACKs
+                            record_message(&metrics, sequences.as_mut(), &mut queue, &topic, &payload);
                         }
-                        Ok(Event::Outgoing(_outgoing)) => {
-                            // Ignore outgoing events
+                        Ok(event) => {
+                            metrics.record_ack_flow(&event);
                         }
                         Err(e) => {
                             log_buffer.log(format!("Subscriber {}: ⚠️  Connection error during subscription: {:?}, reconnecting...", metrics.id + 1, e));
+                            if let Some(code) = e.reason_code() {
+                                metrics.record_reason_code(&code);
+                            }
+                            metrics.increment_connection_errors();
                             metrics.set_connected(false);
                             break;
                         }
@@ -163,12 +217,18 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
             continue;
         }
 
-        log_buffer.log(format!("Subscriber {}: Subscribed to {} of {} topics.", metrics.id + 1, topics_to_subscribe.len(), total_topics_count));
+        log_buffer.log(format!("Subscriber {}: Subscribed to {} of {} topics.", metrics.id + 1, sub_count, total_topics_count));
 
         // Event loop to process incoming messages
         log_buffer.log(format!("Subscriber {}: Now receiving messages...", metrics.id + 1));
+        let offline_at = time::Instant::now() + Duration::from_secs(config.offline_after_secs);
+        let mut go_offline = false;
         loop {
             tokio::select! {
+                _ = time::sleep_until(offline_at), if offline_pending => {
+                    go_offline = true;
+                    break;
+                }
                 _ = shutdown_rx.changed() => {
                     log_buffer.log(format!("Subscriber {}: Received shutdown signal. Disconnecting...", metrics.id + 1));
                     let _ = client.disconnect().await;
@@ -178,22 +238,23 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
                 }
                 event = eventloop.poll() => {
                     match event {
-                        Ok(Event::Incoming(rumqttc::Packet::Publish(_p))) => {
-                            metrics.increment_received();
+                        Ok(ClientEvent::Publish { topic, payload }) => {
+                            record_message(&metrics, sequences.as_mut(), &mut queue, &topic, &payload);
                         }
-                        Ok(Event::Incoming(rumqttc::Packet::Disconnect)) => {
+                        Ok(ClientEvent::Disconnect) => {
                             log_buffer.log(format!("Subscriber {}: ⚠️  Broker sent DISCONNECT, reconnecting...", metrics.id + 1));
                             metrics.set_connected(false);
                             break;
                         }
-                        Ok(Event::Incoming(_packet)) => {
-                            // Ignore other packets
-                        }
-                        Ok(Event::Outgoing(_outgoing)) => {
-                            // Ignore outgoing events
+                        Ok(event) => {
+                            metrics.record_ack_flow(&event);
                         }
                         Err(e) => {
                             log_buffer.log(format!("Subscriber {}: ⚠️  Connection error: {:?}, reconnecting...", metrics.id + 1, e));
+                            if let Some(code) = e.reason_code() {
+                                metrics.record_reason_code(&code);
+                            }
+                            metrics.increment_connection_errors();
                             metrics.set_connected(false);
                             break;
                         }
@@ -207,9 +268,195 @@ pub async fn run(config: Arc<Config>, metrics: Arc<ClientMetrics>, mut shutdown_
             break;
         }
 
+        if go_offline {
+            offline_pending = false;
+            let offline_secs = config.offline_secs.unwrap_or_default();
+            log_buffer.log(format!("Subscriber {}: 📴 Going offline for {}s", metrics.id + 1, offline_secs));
+            let _ = client.disconnect().await;
+            // Let the event loop write the DISCONNECT so the broker keeps the session
+            let _ = time::timeout(Duration::from_secs(1), async { while eventloop.poll().await.is_ok() {} }).await;
+            metrics.set_connected(false);
+            tokio::select! {
+                _ = shutdown_rx.changed() => break,
+                _ = time::sleep(Duration::from_secs(offline_secs)) => continue,
+            }
+        }
+
         log_buffer.log(format!("Subscriber {}: Reconnecting in 2 seconds...", metrics.id + 1));
         time::sleep(Duration::from_secs(2)).await;
     }
 
     Ok(())
 }
+
+/// Subscriptions covering the tree of producer `tree` (1-based) and whether they are wildcards
+///
+/// Topics read from a file have no common tree, they are always subscribed one by one.
+pub fn tree_subscriptions(config: &Config, tree: usize) -> Result<(TopicList, bool), String> {
+    let topic_generator = TopicGenerator::from_config(config, tree)?;
+    let subscriptions = if !topic_generator.is_tree() {
+        (topic_generator.into_topics(false), false)
+    } else if config.use_leafs {
+        if config.use_wildcard {
+            (topic_generator.into_wildcard_subscriptions(), true)
+        } else {
+            (topic_generator.into_topics(true), false)
+        }
+    } else if config.use_wildcard {
+        (topic_generator.into_single_wildcard(), true)
+    } else {
+        (topic_generator.into_topics(false), false)
+    };
+    Ok(subscriptions)
+}
+
+/// The subscriptions of one subscriber, a share of the topics of its trees
+///
+/// Exact topics are taken in a random order so any share covers the trees
+/// evenly: subscription `i` is topic `(i * step + offset) % total` with a
+/// step coprime to `total`, which visits every topic once without a table.
+struct Subscriptions {
+    lists: Vec<TopicList>,
+    /// Topics of all lists
+    total: usize,
+    /// Subscriptions made, `subscribe_percentage` of `total`
+    count: usize,
+    step: usize,
+    offset: usize,
+}
+
+impl Subscriptions {
+    fn new(lists: Vec<TopicList>, shuffle: bool, percentage: f64) -> Self {
+        let total: usize = lists.iter().map(TopicList::len).sum();
+        let count = ((total as f64 * (percentage / 100.0)).round() as usize).min(total);
+        let (mut step, mut offset) = (1, 0);
+        if shuffle && total > 1 {
+            step = fastrand::usize(1..total);
+            while gcd(step, total) != 1 {
+                step = fastrand::usize(1..total);
+            }
+            offset = fastrand::usize(..total);
+        }
+        Subscriptions { lists, total, count, step, offset }
+    }
+
+    /// Topic of subscription `index`
+    fn get(&self, index: usize) -> String {
+        let mut topic = ((index as u128 * self.step as u128 + self.offset as u128) % self.total as u128) as usize;
+        for list in &self.lists {
+            if topic < list.len() {
+                return list.get(topic);
+            }
+            topic -= list.len();
+        }
+        unreachable!("subscription {} of {} topics", index, self.total)
+    }
+
+    /// Every subscription, for the few wildcards
+    fn all(&self) -> Vec<String> {
+        (0..self.count).map(|index| self.get(index)).collect()
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// QoS of subscription `index` out of `count`
+///
+/// With `subscribe_qos_mix` the subscriptions are split in order so the shares
+/// match the percentages, otherwise all use `subscribe_qos`, or 0 by default and
+/// 1 with a persistent session since brokers only queue QoS 1 and 2.
+pub fn subscription_qos(config: &Config, index: usize, count: usize) -> QoS {
+    let level = match (config.subscribe_qos_mix, config.subscribe_qos) {
+        (Some(mix), _) => {
+            let position = (index as f64 + 0.5) * 100.0 / count.max(1) as f64;
+            let mut cumulative = 0.0;
+            mix.iter()
+                .position(|&percent| {
+                    cumulative += percent as f64;
+                    position < cumulative
+                })
+                .unwrap_or(2) as i32
+        }
+        (None, Some(level)) => level,
+        (None, None) if !config.clean_session => 1,
+        (None, None) => 0,
+    };
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+/// Count a received message: latency, sequence and what came from a resumed session's queue
+fn record_message(metrics: &ClientMetrics, sequences: Option<&mut SequenceTracker>, queue: &mut QueueTracker, topic: &str, payload: &[u8]) {
+    let received_at = Utc::now();
+    metrics.increment_received();
+    metrics.add_received_bytes(payload.len() as u64);
+    let header = PayloadHeader::parse(payload);
+    if let Some(latency) = header.latency_us(received_at) {
+        metrics.record_latency(latency);
+    }
+    if let Some(latency) = header.corrected_latency_us(received_at) {
+        metrics.record_corrected_latency(latency);
+    }
+    let outcome = match (sequences, header.run.as_deref(), header.seq) {
+        (Some(sequences), Some(run), Some(seq)) => Some(sequences.track(topic, run, seq)),
+        _ => None,
+    };
+    if let Some(outcome) = outcome {
+        metrics.record_sequence(outcome);
+    }
+    metrics.record_queue(queue.track(topic, header.sent_at, outcome));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscriptions_cover_every_topic_once() {
+        let config = Config { topics_per_node: 3, max_depth: 2, num_topics: 0, ..Config::default() };
+        let lists: Vec<TopicList> = (1..=2).map(|tree| tree_subscriptions(&config, tree).unwrap().0).collect();
+        let mut expected: Vec<String> = lists.iter().flat_map(TopicList::iter).collect();
+
+        let subscriptions = Subscriptions::new(lists.clone(), true, 100.0);
+        assert_eq!((subscriptions.total, subscriptions.count), (26, 26));
+        let mut subscribed = subscriptions.all();
+        subscribed.sort();
+        expected.sort();
+        assert_eq!(subscribed, expected);
+
+        let half = Subscriptions::new(lists, true, 50.0);
+        assert_eq!(half.count, 13);
+        assert_eq!(half.all().len(), 13);
+    }
+
+    #[test]
+    fn test_subscription_qos() {
+        let mut config = Config::default();
+        assert_eq!(subscription_qos(&config, 0, 1), QoS::AtMostOnce);
+        // The publish QoS does not carry over to the subscriptions
+        config.qos = 2;
+        assert_eq!(subscription_qos(&config, 0, 1), QoS::AtMostOnce);
+        config.subscribe_qos = Some(1);
+        assert_eq!(subscription_qos(&config, 0, 1), QoS::AtLeastOnce);
+
+        let config = Config { clean_session: false, qos: 2, ..Config::default() };
+        assert_eq!(subscription_qos(&config, 0, 1), QoS::AtLeastOnce);
+
+        let config = Config { subscribe_qos_mix: Some([50, 30, 20]), ..Config::default() };
+        let levels: Vec<QoS> = (0..10).map(|index| subscription_qos(&config, index, 10)).collect();
+        assert_eq!(levels.iter().filter(|&&qos| qos == QoS::AtMostOnce).count(), 5);
+        assert_eq!(levels.iter().filter(|&&qos| qos == QoS::AtLeastOnce).count(), 3);
+        assert_eq!(levels.iter().filter(|&&qos| qos == QoS::ExactlyOnce).count(), 2);
+        // A single wildcard subscription gets the level covering the middle
+        assert_eq!(subscription_qos(&config, 0, 1), QoS::AtLeastOnce);
+    }
+}
@@ -1,12 +1,22 @@
+mod alias;
+mod benchmark;
 mod config;
+mod connection;
+mod exporter;
 mod metrics;
+mod payload;
+mod phases;
+mod selection;
+mod sequence;
 mod subscriber;
 mod topic;
 mod ui;
 
+use crate::benchmark::{AckTotals, Report, SessionReport, SharedReport};
 use crate::config::Config;
 use crate::metrics::GlobalMetrics;
 use crate::ui::{draw_config_screen, LogBuffer, UIContext};
+use chrono::Utc;
 use clap::Parser;
 use crossterm::event::{self, Event, KeyCode};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
@@ -14,7 +24,7 @@ use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
 use std::io;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 
 #[derive(Parser, Debug)]
@@ -29,6 +39,10 @@ struct Args {
     #[arg(long, default_value = "1883")]
     port: u16,
 
+    /// Broker URL (mqtt://, mqtts://, ws://, wss://), overrides --broker, --port and --tls
+    #[arg(long)]
+    url: Option<String>,
+
     /// Configuration file to load (JSON)
     #[arg(long)]
     config: Option<String>,
@@ -37,9 +51,131 @@ struct Args {
     #[arg(long)]
     auto_start: bool,
 
+    /// Stop after this many seconds (auto-start only)
+    #[arg(long)]
+    duration: Option<u64>,
+
+    /// Stop after this many messages received in total (auto-start only)
+    #[arg(long)]
+    messages: Option<u64>,
+
+    /// Write a JSON report at the end of the run, "-" for stdout (auto-start only)
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Serve Prometheus metrics on this address (e.g. 0.0.0.0:9100)
+    #[arg(long)]
+    metrics_listen: Option<String>,
+
+    /// Connect to the broker over TLS
+    #[arg(long)]
+    tls: bool,
+
+    /// CA certificate file (PEM) used to verify the broker, implies --tls
+    #[arg(long)]
+    ca_file: Option<String>,
+
+    /// Client certificate file (PEM) for mutual TLS, implies --tls
+    #[arg(long)]
+    client_cert: Option<String>,
+
+    /// Client private key file (PEM) for mutual TLS
+    #[arg(long)]
+    client_key: Option<String>,
+
+    /// ALPN protocol to offer, can be repeated
+    #[arg(long)]
+    alpn: Vec<String>,
+
+    /// With a wss:// URL, send this name as SNI and verify the broker certificate against it
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// Skip broker certificate verification
+    #[arg(long)]
+    insecure: bool,
+
+    /// Username, "{id}" is replaced by the client number (e.g. "user-{id}")
+    #[arg(long)]
+    username: Option<String>,
+
+    /// Password, "{id}" is replaced by the client number
+    #[arg(long)]
+    password: Option<String>,
+
+    /// File with one password per line, client N uses line N
+    #[arg(long)]
+    password_file: Option<String>,
+
+    /// MQTT protocol level: 4 (3.1.1) or 5
+    #[arg(long)]
+    protocol_version: Option<u8>,
+
+    /// MQTT 5 session expiry interval in seconds
+    #[arg(long)]
+    session_expiry: Option<u32>,
+
+    /// Stable client ids, "{role}" becomes pub/sub and "{id}" the client number, e.g. "load-{role}-{id}"
+    #[arg(long)]
+    client_id_template: Option<String>,
+
+    /// Keep the session across reconnects (clean_session=false / clean start off)
+    #[arg(long)]
+    no_clean_session: bool,
+
+    /// Offline scenario: disconnect the subscribers for this many seconds, then resume their sessions
+    #[arg(long)]
+    offline: Option<u64>,
+
+    /// Seconds the subscribers receive before going offline (default 10)
+    #[arg(long)]
+    offline_after: Option<u64>,
+
+    /// MQTT keep-alive interval in seconds (default 120, 0 disables it on MQTT 3.1.1)
+    #[arg(long)]
+    keep_alive: Option<u64>,
+
+    /// Clients started per second instead of all at once
+    #[arg(long)]
+    connect_rate: Option<f64>,
+
+    /// Seconds to wait for all clients to connect
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Topology: one-to-one, fan-out, fan-in or many-to-many[:overlap] (default one-to-one)
+    #[arg(long, value_parser = config::parse_topology)]
+    topology: Option<config::Topology>,
+
+    /// Topic tree shape: uniform, levels:N,N,..., isa95[:SITES,AREAS,LINES,CELLS,DEVICES,METRICS],
+    /// random[:SEED] or file:PATH (default uniform)
+    #[arg(long, value_parser = topic::TopicShape::parse)]
+    topic_shape: Option<topic::TopicShape>,
+
+    /// Maximum topics per producer, taken breadth first; 0 (default) for the whole tree.
+    /// Overrides num_topics from the config file, which caps the tree the same way
+    #[arg(long)]
+    num_topics: Option<usize>,
+
     /// Percentage of topics to subscribe to (0-100)
     #[arg(long, default_value = "100")]
     subscribe_percentage: u8,
+
+    /// QoS of the subscriptions (default: 0, or 1 with a persistent session)
+    #[arg(long)]
+    subscribe_qos: Option<i32>,
+
+    /// Percent of subscriptions at QoS 0,1,2, e.g. "50,30,20"
+    #[arg(long, value_parser = config::parse_qos_mix)]
+    subscribe_qos_mix: Option<[u8; 3]>,
+
+    /// Shared subscriptions: split the subscribers into this many share groups
+    #[arg(long)]
+    shared_groups: Option<usize>,
+
+    /// Filter the share groups subscribe to (default "+/#"), implies shared subscriptions
+    #[arg(long)]
+    shared_filter: Option<String>,
 }
 
 #[tokio::main]
@@ -60,12 +196,113 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Config::load_or_default(config_file);
     config.broker_host = args.broker;
     config.broker_port = args.port;
+    if args.url.is_some() {
+        config.broker_url = args.url;
+    }
+    if args.duration.is_some() {
+        config.duration_secs = args.duration;
+    }
+    if args.messages.is_some() {
+        config.max_messages = args.messages;
+    }
+    if args.report.is_some() {
+        config.report_file = args.report;
+    }
+    if args.metrics_listen.is_some() {
+        config.metrics_listen = args.metrics_listen;
+    }
+    if args.ca_file.is_some() {
+        config.tls_ca_file = args.ca_file;
+    }
+    if args.client_cert.is_some() {
+        config.tls_client_cert = args.client_cert;
+    }
+    if args.client_key.is_some() {
+        config.tls_client_key = args.client_key;
+    }
+    if !args.alpn.is_empty() {
+        config.tls_alpn = args.alpn;
+    }
+    if args.tls_server_name.is_some() {
+        config.tls_server_name = args.tls_server_name;
+    }
+    config.tls_insecure |= args.insecure;
+    if args.username.is_some() {
+        config.username = args.username;
+    }
+    if args.password.is_some() {
+        config.password = args.password;
+    }
+    if args.password_file.is_some() {
+        config.password_file = args.password_file;
+    }
+    if let Some(version) = args.protocol_version {
+        config.protocol_version = version;
+    }
+    if args.session_expiry.is_some() {
+        config.session_expiry_secs = args.session_expiry;
+    }
+    if let Some(secs) = args.keep_alive {
+        config.keep_alive_secs = secs;
+    }
+    if args.client_id_template.is_some() {
+        config.client_id_template = args.client_id_template;
+    }
+    if args.no_clean_session {
+        config.clean_session = false;
+    }
+    if args.offline.is_some() {
+        config.offline_secs = args.offline;
+    }
+    if let Some(secs) = args.offline_after {
+        config.offline_after_secs = secs;
+    }
+    if args.connect_rate.is_some() {
+        config.connect_rate = args.connect_rate;
+    }
+    if let Some(secs) = args.connect_timeout {
+        config.connect_timeout_secs = secs;
+    }
+    if let Some(topology) = args.topology {
+        config.topology = topology;
+    }
+    if let Some(shape) = args.topic_shape {
+        config.topic_shape = shape;
+    }
+    if let Some(num_topics) = args.num_topics {
+        config.num_topics = num_topics;
+    }
+    if config.connect_rate.is_some_and(|rate| rate <= 0.0) {
+        return Err("The connect rate must be positive".into());
+    }
+    config.use_tls |= args.tls || config.tls_ca_file.is_some() || config.tls_client_cert.is_some();
     config.subscribe_percentage = args.subscribe_percentage;
+    if args.subscribe_qos.is_some() {
+        config.subscribe_qos = args.subscribe_qos;
+    }
+    if args.subscribe_qos_mix.is_some() {
+        config.subscribe_qos_mix = args.subscribe_qos_mix;
+    }
+    if args.shared_groups.is_some() || args.shared_filter.is_some() {
+        let shared = config.shared.get_or_insert_with(Default::default);
+        if let Some(groups) = args.shared_groups {
+            shared.groups = groups;
+        }
+        if let Some(filter) = args.shared_filter {
+            shared.filter = filter;
+        }
+    }
+    if config.shared.as_ref().is_some_and(|shared| shared.groups == 0 || shared.groups > config.subscribers()) {
+        return Err("Shared subscriptions need between 1 and one group per subscriber".into());
+    }
 
-    if config_file.is_some() {
-        eprintln!("✅ Loaded configuration from: {}", config_file.unwrap());
+    if let Some(path) = config_file {
+        eprintln!("✅ Loaded configuration from: {}", path);
     }
 
+    // A missing or empty topic file fails here instead of in every client
+    topic::TopicGenerator::from_config(&config, 1)?;
+
     if args.auto_start {
         run_subscribers(&config).await?;
     } else {
@@ -159,17 +396,23 @@ async fn run_ui(initial_config: &Config) -> Result<(), Box<dyn std::error::Error
 
 async fn run_subscribers_with_ui(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let config = Arc::new(config.clone());
-    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.num_producers)));
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.subscribers())));
     let log_buffer = LogBuffer::new(100); // Keep last 100 log lines
 
-    println!("\n📊 Starting {} subscribers...", config.num_producers);
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "subscriber").await?),
+        None => None,
+    };
+
+    println!("\n📊 Starting {} subscribers...", config.subscribers());
 
     let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
 
     let mut handles: Vec<JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>> =
         Vec::new();
 
-    for subscriber_id in 0..config.num_producers {
+    for subscriber_id in 0..config.subscribers() {
         let config_clone = config.clone();
         let client_metrics = metrics.lock().unwrap().clients[subscriber_id].clone();
         let shutdown_rx_clone = shutdown_rx.clone();
@@ -184,8 +427,13 @@ async fn run_subscribers_with_ui(config: &Config) -> Result<(), Box<dyn std::err
         handles.push(handle);
     }
 
-    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
-    println!("✅ All subscribers connected!");
+    match benchmark::wait_for_connected(&metrics, Duration::from_secs(config.connect_timeout_secs)).await {
+        Ok(elapsed) => println!("✅ All {} subscribers connected in {:.2}s", config.subscribers(), elapsed.as_secs_f64()),
+        Err(connected) => println!(
+            "⚠️  Only {}/{} subscribers connected after {}s, continuing...",
+            connected, config.subscribers(), config.connect_timeout_secs
+        ),
+    }
     println!("📊 Subscribers running (press C to clear, Q to quit)...");
 
     // Enable raw mode to capture keyboard input
@@ -202,10 +450,14 @@ async fn run_subscribers_with_ui(config: &Config) -> Result<(), Box<dyn std::err
                 let total_received_vps = metrics_guard.get_total_received_vps();
                 let connected_clients = metrics_guard.get_connected_count();
                 let total_clients = metrics_guard.clients.len();
+                let latency = metrics_guard.get_latency();
 
                 // Temporarily disable raw mode to print metrics properly
                 let _ = disable_raw_mode();
-                println!("📈 Connected: {}/{} clients | Received: {} | v/s: {:.2} | ▶️  Running", connected_clients, total_clients, total_received, total_received_vps);
+                println!("📈 Connected: {}/{} clients | Received: {} | v/s: {:.2} | Latency p50/p99: {:.2}/{:.2} ms | Lost: {} Dup: {} OoO: {} | ▶️  Running", connected_clients, total_clients, total_received, total_received_vps, latency.p50 as f64 / 1000.0, latency.p99 as f64 / 1000.0, metrics_guard.get_total_lost(), metrics_guard.get_total_duplicates(), metrics_guard.get_total_out_of_order());
+                if let Some(shared) = SharedReport::new(&config, &metrics_guard) {
+                    println!("   {}", shared.summary_line());
+                }
                 let _ = enable_raw_mode();
 
                 // Debug: Show individual client states
@@ -277,21 +529,59 @@ async fn run_subscribers_with_ui(config: &Config) -> Result<(), Box<dyn std::err
     let final_metrics = metrics.lock().unwrap();
     println!("Total messages received: {}", final_metrics.get_total_received());
     println!("Average throughput: {:.2} msg/s", final_metrics.get_total_received_vps());
+    println!("Latency: {}", final_metrics.get_latency().format_ms());
+    let corrected = final_metrics.get_corrected_latency();
+    if corrected.count > 0 {
+        println!("Latency (CO-corrected): {}", corrected.format_ms());
+    }
+    println!("Lost: {} | Duplicates: {} | Out of order: {}", final_metrics.get_total_lost(), final_metrics.get_total_duplicates(), final_metrics.get_total_out_of_order());
+    if let Some(session) = SessionReport::new(&config, &final_metrics) {
+        println!("{}", session.summary_line());
+    }
+    let reason_codes = final_metrics.get_reason_codes();
+    if !reason_codes.is_empty() {
+        let codes: Vec<String> = reason_codes.iter().map(|(code, count)| format!("{}={}", code, count)).collect();
+        println!("Reason codes: {}", codes.join(", "));
+    }
+    if let Some(acks) = AckTotals::new(&final_metrics) {
+        println!("{}", acks.summary_line());
+    }
+    if let Some(shared) = SharedReport::new(&config, &final_metrics) {
+        println!("{}", shared.summary_line());
+        for line in shared.group_lines() {
+            println!("{}", line);
+        }
+    }
+    for client in &final_metrics.clients {
+        println!("  Client {}: {}", client.id + 1, client.get_latency().format_ms());
+    }
 
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
     Ok(())
 }
 
 async fn run_subscribers(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let config = Arc::new(config.clone());
-    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.num_producers)));
+    let metrics = Arc::new(Mutex::new(GlobalMetrics::new(config.subscribers())));
     let log_buffer = LogBuffer::new(100); // Keep last 100 log lines
 
-    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    // Optional Prometheus endpoint, lives as long as this run
+    let exporter = match &config.metrics_listen {
+        Some(addr) => Some(exporter::spawn(addr, metrics.clone(), "subscriber").await?),
+        None => None,
+    };
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+    let started_at = Utc::now();
+    let started = Instant::now();
 
     let mut handles: Vec<JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>> =
         Vec::new();
 
-    for subscriber_id in 0..config.num_producers {
+    for subscriber_id in 0..config.subscribers() {
         let config_clone = config.clone();
         let client_metrics = metrics.lock().unwrap().clients[subscriber_id].clone();
         let shutdown_rx_clone = shutdown_rx.clone();
@@ -305,16 +595,79 @@ async fn run_subscribers(config: &Config) -> Result<(), Box<dyn std::error::Erro
 
         handles.push(handle);
     }
+    let connect_watch = benchmark::spawn_connect_watch(metrics.clone());
+
+    // Run until the duration or message limit is reached (or forever if neither is set)
+    let stop_reason = benchmark::wait_for_stop(
+        &config,
+        &metrics,
+        GlobalMetrics::get_total_received,
+        || handles.iter().all(|h| h.is_finished()),
+    )
+    .await;
+    let elapsed = started.elapsed();
+    let _ = shutdown_tx.send(true);
 
     // Wait for all tasks to finish (they should exit gracefully on shutdown signal)
     for handle in handles {
-        let _ = handle.await;
+        if let Ok(Ok(Err(e))) = tokio::time::timeout(Duration::from_secs(5), handle).await {
+            eprintln!("❌ Subscriber failed: {}", e);
+        }
     }
+    let all_connected = benchmark::all_connected_secs(connect_watch).await;
 
-    eprintln!("✅ Test completed!");
     let final_metrics = metrics.lock().unwrap();
+    eprintln!("✅ Test completed ({:?} after {:.1}s)", stop_reason, elapsed.as_secs_f64());
     eprintln!("Total messages received: {}", final_metrics.get_total_received());
-    eprintln!("Average throughput: {:.2} msg/s", final_metrics.get_total_received_vps());
+    eprintln!("{}", benchmark::connect_summary(&final_metrics, all_connected));
+    eprintln!("Average throughput: {:.2} msg/s", final_metrics.get_total_received() as f64 / elapsed.as_secs_f64().max(0.001));
+    eprintln!(
+        "Payload bytes received: {} ({:.2} MB/s)",
+        final_metrics.get_total_received_bytes(),
+        final_metrics.get_total_received_bytes() as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001)
+    );
+    eprintln!("Latency: {}", final_metrics.get_latency().format_ms());
+    let corrected = final_metrics.get_corrected_latency();
+    if corrected.count > 0 {
+        eprintln!("Latency (CO-corrected): {}", corrected.format_ms());
+    }
+    eprintln!("Lost: {} | Duplicates: {} | Out of order: {}", final_metrics.get_total_lost(), final_metrics.get_total_duplicates(), final_metrics.get_total_out_of_order());
+    let session = SessionReport::new(&config, &final_metrics);
+    if let Some(session) = &session {
+        eprintln!("{}", session.summary_line());
+    }
+    let reason_codes = final_metrics.get_reason_codes();
+    if !reason_codes.is_empty() {
+        let codes: Vec<String> = reason_codes.iter().map(|(code, count)| format!("{}={}", code, count)).collect();
+        eprintln!("Reason codes: {}", codes.join(", "));
+    }
+    if let Some(acks) = AckTotals::new(&final_metrics) {
+        eprintln!("{}", acks.summary_line());
+    }
+    let shared = SharedReport::new(&config, &final_metrics);
+    if let Some(shared) = &shared {
+        eprintln!("{}", shared.summary_line());
+        for line in shared.group_lines() {
+            eprintln!("{}", line);
+        }
+    }
+    for client in &final_metrics.clients {
+        eprintln!("  Client {}: {}", client.id + 1, client.get_latency().format_ms());
+    }
 
+    if let Some(path) = &config.report_file {
+        let mut report = Report::new("mqtt-subscribe", &config, &final_metrics, started_at, elapsed, stop_reason);
+        report.all_connected_secs = all_connected;
+        report.session = session;
+        report.shared = shared;
+        report.write(path)?;
+        if path != "-" {
+            eprintln!("📄 Report written to {}", path);
+        }
+    }
+
+    if let Some(exporter) = exporter {
+        exporter.abort();
+    }
     Ok(())
-}
\ No newline at end of file
+}
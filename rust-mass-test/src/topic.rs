@@ -1,108 +1,460 @@
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Shape of every producer's topic tree
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TopicShape {
+    /// `topics_per_node` numbered children on every level down to `max_depth`
+    #[default]
+    Uniform,
+    /// Numbered children per level, e.g. [4, 50, 10], replaces topics_per_node and max_depth
+    Levels { fan_out: Vec<usize> },
+    /// ISA-95 style paths like prefix00001/site01/area02/line01/cell03/robot02/temperature
+    Isa95(Isa95Shape),
+    /// A random number of children per node, most nodes get few and some get many
+    Random(RandomShape),
+    /// Topics from a file, one per line, dealt out to the producers in turn
+    File { path: String },
+}
+
+/// Children per level of an ISA-95 tree, every device publishes `metrics` data points
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Isa95Shape {
+    pub sites: usize,
+    pub areas: usize,
+    pub lines: usize,
+    pub cells: usize,
+    pub devices: usize,
+    pub metrics: usize,
+}
+
+impl Default for Isa95Shape {
+    fn default() -> Self {
+        Isa95Shape {
+            sites: 2,
+            areas: 3,
+            lines: 4,
+            cells: 3,
+            devices: 5,
+            metrics: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RandomShape {
+    pub depth: usize,
+    pub max_fan_out: usize,
+    /// Children are `max_fan_out * u^skew` for a uniform u, 1.0 spreads them evenly
+    pub skew: f64,
+    /// Trees only depend on the seed, so publishers and subscribers agree
+    pub seed: u64,
+}
+
+impl Default for RandomShape {
+    fn default() -> Self {
+        RandomShape {
+            depth: 4,
+            max_fan_out: 20,
+            skew: 3.0,
+            seed: 1,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl TopicShape {
+    /// Parse "uniform", "levels:4,50,10", "isa95[:SITES,AREAS,LINES,CELLS,DEVICES,METRICS]",
+    /// "random[:SEED]" or "file:PATH" from the command line
+    pub fn parse(spec: &str) -> Result<TopicShape, String> {
+        let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+        let counts = || {
+            params
+                .split(',')
+                .map(|count| count.trim().parse::<usize>().map_err(|e| format!("'{}': {}", count, e)))
+                .collect::<Result<Vec<usize>, String>>()
+        };
+        match (name, params) {
+            ("uniform", "") => Ok(TopicShape::Uniform),
+            ("levels", _) => Ok(TopicShape::Levels { fan_out: counts()? }),
+            ("isa95", "") => Ok(TopicShape::Isa95(Isa95Shape::default())),
+            ("isa95", _) => match counts()?.as_slice() {
+                &[sites, areas, lines, cells, devices, metrics] => {
+                    Ok(TopicShape::Isa95(Isa95Shape { sites, areas, lines, cells, devices, metrics }))
+                }
+                _ => Err(format!("expected isa95:SITES,AREAS,LINES,CELLS,DEVICES,METRICS, got '{}'", spec)),
+            },
+            ("random", "") => Ok(TopicShape::Random(RandomShape::default())),
+            ("random", seed) => {
+                let seed = seed.parse::<u64>().map_err(|e| format!("'{}': {}", seed, e))?;
+                Ok(TopicShape::Random(RandomShape { seed, ..RandomShape::default() }))
+            }
+            ("file", path) if !path.is_empty() => Ok(TopicShape::File { path: path.to_string() }),
+            _ => Err(format!("expected uniform, levels:N,N,..., isa95, random[:SEED] or file:PATH, got '{}'", spec)),
+        }
+    }
+}
+
+/// Device kinds of an ISA-95 cell, numbered per kind
+const DEVICE_KINDS: [&str; 6] = ["robot", "conveyor", "press", "plc", "drive", "sensor"];
+
+/// Data points of an ISA-95 device, repeated with a number when there are more
+const METRIC_NAMES: [&str; 12] = [
+    "temperature", "pressure", "vibration", "speed", "current", "voltage",
+    "state", "count", "fault", "setpoint", "humidity", "flow",
+];
+
+/// How the children of a level are named, `index` starts at 1
+#[derive(Debug, Clone, Copy)]
+enum Naming {
+    /// 01, 02, ...
+    Numbered,
+    /// site01, site02, ...
+    Prefixed(&'static str),
+    /// robot01, conveyor01, ..., robot02, ...
+    Device,
+    /// temperature, pressure, ..., temperature2, ...
+    Metric,
+}
+
+impl Naming {
+    fn component(self, index: usize) -> String {
+        match self {
+            Naming::Numbered => format!("{:02}", index),
+            Naming::Prefixed(prefix) => format!("{}{:02}", prefix, index),
+            Naming::Device => {
+                let kind = DEVICE_KINDS[(index - 1) % DEVICE_KINDS.len()];
+                format!("{}{:02}", kind, (index - 1) / DEVICE_KINDS.len() + 1)
+            }
+            Naming::Metric => {
+                let name = METRIC_NAMES[(index - 1) % METRIC_NAMES.len()];
+                match (index - 1) / METRIC_NAMES.len() {
+                    0 => name.to_string(),
+                    round => format!("{}{}", name, round + 1),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    children: usize,
+    naming: Naming,
+}
+
+enum Tree {
+    /// The same number of children for every node of a level
+    Levels(Vec<Level>),
+    /// `children[d][j]` counts the children of nodes 0..=j of depth d, only
+    /// inner nodes are kept so a tree costs no memory per leaf
+    Random { children: Vec<Vec<usize>> },
+    /// Fixed topics without a tree below the base topic
+    List(Vec<String>),
+}
+
 /// Generate MQTT topics in a tree structure
 ///
 /// For example with max_depth=2, topics_per_node=3, prefix="test", start_num=1:
 /// - test00001
 /// - test00001/01, test00001/02, test00001/03
 /// - test00001/01/01, test00001/01/02, ..., test00001/03/03
-
+///
+/// Topics are numbered breadth first in this order and built from their
+/// number, so nothing is generated before it is used. Other shapes replace the
+/// numbered levels below the base topic, see [`TopicShape`].
 pub struct TopicGenerator {
-    prefix: String,
-    base_topic_index: usize,
-    topics_per_node: usize,
-    max_depth: usize,
+    base_topic: String,
+    tree: Tree,
+    /// Number of the first topic of every depth, plus the end of the tree
+    depth_starts: Vec<usize>,
+    /// Topics per producer, from `num_topics`
+    max_topics: Option<usize>,
 }
 
 impl TopicGenerator {
+    #[allow(dead_code)]
     pub fn new(
         prefix: String,
         base_topic_index: usize,
         topics_per_node: usize,
         max_depth: usize,
     ) -> Self {
-        TopicGenerator {
-            prefix,
-            base_topic_index,
-            topics_per_node,
-            max_depth,
-        }
+        let level = Level { children: topics_per_node, naming: Naming::Numbered };
+        TopicGenerator::build(format!("{}{:05}", prefix, base_topic_index), Tree::Levels(vec![level; max_depth]), None)
     }
 
-    pub fn generate_all(&self) -> Vec<String> {
-        let mut topics = Vec::new();
-        let base_topic = format!("{}{:05}", self.prefix, self.base_topic_index);
-
-        // Add root topic
-        topics.push(base_topic.clone());
+    /// Generator for the tree of producer `tree` (1-based) with the configured
+    /// shape, capped at `num_topics` topics unless that is 0
+    #[allow(dead_code)]
+    pub fn from_config(config: &Config, tree: usize) -> Result<Self, String> {
+        let numbered = |children: usize| Level { children, naming: Naming::Numbered };
+        let shape = match &config.topic_shape {
+            TopicShape::Uniform => Tree::Levels(vec![numbered(config.topics_per_node); config.max_depth]),
+            TopicShape::Levels { fan_out } => Tree::Levels(fan_out.iter().map(|&children| numbered(children)).collect()),
+            TopicShape::Isa95(shape) => Tree::Levels(vec![
+                Level { children: shape.sites, naming: Naming::Prefixed("site") },
+                Level { children: shape.areas, naming: Naming::Prefixed("area") },
+                Level { children: shape.lines, naming: Naming::Prefixed("line") },
+                Level { children: shape.cells, naming: Naming::Prefixed("cell") },
+                Level { children: shape.devices, naming: Naming::Device },
+                Level { children: shape.metrics, naming: Naming::Metric },
+            ]),
+            TopicShape::Random(shape) => random_tree(shape, tree),
+            TopicShape::File { path } => {
+                let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                let producers = config.publishers().max(1);
+                let topics: Vec<String> = contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .enumerate()
+                    .filter(|(index, _)| index % producers == tree - 1)
+                    .map(|(_, topic)| topic.to_string())
+                    .collect();
+                if topics.is_empty() {
+                    return Err(format!("{}: no topics for producer {}", path, tree));
+                }
+                Tree::List(topics)
+            }
+        };
+        let max_topics = (config.num_topics > 0).then_some(config.num_topics);
+        Ok(TopicGenerator::build(format!("{}{:05}", config.topic_prefix, tree), shape, max_topics))
+    }
 
-        // Generate topics for each depth level
-        for depth in 1..=self.max_depth {
-            self.generate_at_depth(&mut topics, &base_topic, depth, Vec::new());
+    fn build(base_topic: String, tree: Tree, max_topics: Option<usize>) -> Self {
+        let mut depth_starts: Vec<usize> = vec![0];
+        match &tree {
+            Tree::Levels(levels) => {
+                let mut nodes: usize = 1;
+                for level in levels {
+                    depth_starts.push(depth_starts[depth_starts.len() - 1].saturating_add(nodes));
+                    nodes = nodes.saturating_mul(level.children);
+                }
+                depth_starts.push(depth_starts[depth_starts.len() - 1].saturating_add(nodes));
+            }
+            Tree::Random { children } => {
+                depth_starts.push(1);
+                for counts in children {
+                    let nodes = counts.last().copied().unwrap_or(0);
+                    depth_starts.push(depth_starts[depth_starts.len() - 1] + nodes);
+                }
+            }
+            Tree::List(topics) => depth_starts.push(topics.len()),
         }
+        TopicGenerator { base_topic, tree, depth_starts, max_topics }
+    }
 
-        topics
+    /// Whether the topics form a tree below the base topic that wildcards can cover
+    #[allow(dead_code)]
+    pub fn is_tree(&self) -> bool {
+        !matches!(self.tree, Tree::List(_))
     }
 
+    /// Topics to publish to, the whole tree or only its leaves, capped at `num_topics`
     #[allow(dead_code)]
-    pub fn generate_leaves_only(&self) -> Vec<String> {
-        let mut topics = Vec::new();
-        let base_topic = format!("{}{:05}", self.prefix, self.base_topic_index);
+    pub fn into_topics(self, leaves_only: bool) -> TopicList {
+        let range = self.topic_range(leaves_only);
+        TopicList::new(self, range, "")
+    }
+
+    /// `<parent>/#` for every parent of a leaf, `<base>/#` when the tree has one level
+    #[allow(dead_code)]
+    pub fn into_wildcard_subscriptions(self) -> TopicList {
+        let range = self.wildcard_range();
+        TopicList::new(self, range, "/#")
+    }
 
-        // Only generate topics at max_depth (leaf nodes)
-        if self.max_depth > 0 {
-            self.generate_at_depth(&mut topics, &base_topic, self.max_depth, Vec::new());
+    /// Same filters as [`TopicGenerator::generate_single_wildcard`]
+    #[allow(dead_code)]
+    pub fn into_single_wildcard(self) -> TopicList {
+        if self.is_tree() {
+            TopicList::new(self, 0..1, "/#")
+        } else {
+            TopicList::from(self.generate_single_wildcard())
         }
+    }
 
-        topics
+    #[allow(dead_code)]
+    pub fn generate_all(&self) -> Vec<String> {
+        self.topic_range(false).map(|index| self.topic(index)).collect()
     }
 
     #[allow(dead_code)]
-    pub fn generate_wildcard_subscriptions(&self) -> Vec<String> {
-        let mut topics = Vec::new();
-        let base_topic = format!("{}{:05}", self.prefix, self.base_topic_index);
-
-        // Generate topics at max_depth - 1 level with wildcard
-        if self.max_depth > 1 {
-            self.generate_at_depth(&mut topics, &base_topic, self.max_depth - 1, Vec::new());
-            // Add wildcard suffix to each topic
-            topics = topics.into_iter().map(|t| format!("{}/#", t)).collect();
-        } else if self.max_depth == 1 {
-            // If max_depth is 1, just subscribe to base_topic/#
-            topics.push(format!("{}/#", base_topic));
-        }
+    pub fn generate_leaves_only(&self) -> Vec<String> {
+        self.topic_range(true).map(|index| self.topic(index)).collect()
+    }
 
-        topics
+    #[allow(dead_code)]
+    pub fn generate_wildcard_subscriptions(&self) -> Vec<String> {
+        self.wildcard_range().map(|index| format!("{}/#", self.topic(index))).collect()
     }
 
+    /// `<base>/#` covering the whole tree. Topic lists share no base, they get
+    /// `<first level>/#` for every distinct first level of their topics.
     #[allow(dead_code)]
     pub fn generate_single_wildcard(&self) -> Vec<String> {
-        let base_topic = format!("{}{:05}", self.prefix, self.base_topic_index);
-        vec![format!("{}/#", base_topic)]
-    }
-
-    fn generate_at_depth(
-        &self,
-        topics: &mut Vec<String>,
-        base: &str,
-        target_depth: usize,
-        path: Vec<usize>,
-    ) {
-        if path.len() == target_depth {
-            // Build the full topic path
-            let mut full_path = base.to_string();
-            for component in &path {
-                full_path.push_str(&format!("/{:02}", component));
+        match &self.tree {
+            Tree::List(topics) => {
+                let levels: BTreeSet<&str> =
+                    self.topic_range(false).map(|index| topics[index].split('/').next().unwrap_or_default()).collect();
+                levels.into_iter().map(|level| format!("{}/#", level)).collect()
             }
-            topics.push(full_path);
-            return;
+            _ => vec![format!("{}/#", self.base_topic)],
         }
+    }
+
+    fn depth(&self) -> usize {
+        self.depth_starts.len() - 2
+    }
 
-        // Recursively generate for each possible child
-        for i in 1..=self.topics_per_node {
-            let mut new_path = path.clone();
-            new_path.push(i);
-            self.generate_at_depth(topics, base, target_depth, new_path);
+    fn topic_range(&self, leaves_only: bool) -> Range<usize> {
+        let start = if leaves_only { self.depth_starts[self.depth()] } else { 0 };
+        let end = self.depth_starts[self.depth() + 1];
+        let len = (end - start).min(self.max_topics.unwrap_or(usize::MAX));
+        start..start + len
+    }
+
+    /// Parents of the leaves, nothing for a topic list
+    fn wildcard_range(&self) -> Range<usize> {
+        match self.depth() {
+            0 => 0..0,
+            depth => self.depth_starts[depth - 1]..self.depth_starts[depth],
         }
     }
+
+    /// Topic number `index`, breadth first from the base topic
+    fn topic(&self, index: usize) -> String {
+        if let Tree::List(topics) = &self.tree {
+            return topics[index].clone();
+        }
+        let depth = self.depth_starts.partition_point(|&start| start <= index) - 1;
+        // Position within the depth, turned into one child number per level from the bottom up
+        let mut offset = index - self.depth_starts[depth];
+        let mut components = Vec::with_capacity(depth);
+        for level in (0..depth).rev() {
+            match &self.tree {
+                Tree::Levels(levels) => {
+                    let children = levels[level].children;
+                    components.push(levels[level].naming.component(offset % children + 1));
+                    offset /= children;
+                }
+                Tree::Random { children } => {
+                    let counts = &children[level];
+                    let parent = counts.partition_point(|&count| count <= offset);
+                    let first = if parent == 0 { 0 } else { counts[parent - 1] };
+                    components.push(Naming::Numbered.component(offset - first + 1));
+                    offset = parent;
+                }
+                Tree::List(_) => unreachable!("topic lists have no levels"),
+            }
+        }
+
+        let mut topic = self.base_topic.clone();
+        for component in components.iter().rev() {
+            topic.push('/');
+            topic.push_str(component);
+        }
+        topic
+    }
+}
+
+/// Child counts of the inner nodes of a random tree, every node's count is a
+/// hash of the seed, the tree, its depth and its position within that depth
+fn random_tree(shape: &RandomShape, tree: usize) -> Tree {
+    let max = shape.max_fan_out.max(1);
+    let mut children = Vec::with_capacity(shape.depth);
+    let mut nodes = 1;
+    for depth in 0..shape.depth {
+        let mut total = 0;
+        let counts: Vec<usize> = (0..nodes)
+            .map(|node| {
+                let hash = splitmix64(splitmix64(splitmix64(shape.seed ^ tree as u64) ^ depth as u64) ^ node as u64);
+                // Top 53 bits as a uniform value in [0, 1)
+                let uniform = (hash >> 11) as f64 / (1u64 << 53) as f64;
+                total += ((max as f64 * uniform.powf(shape.skew)).ceil() as usize).clamp(1, max);
+                total
+            })
+            .collect();
+        nodes = total;
+        children.push(counts);
+    }
+    Tree::Random { children }
+}
+
+/// A run of topics of one tree, built when they are read so even a tree of
+/// millions of topics takes no memory per topic
+#[derive(Clone)]
+pub struct TopicList {
+    generator: Arc<TopicGenerator>,
+    range: Range<usize>,
+    /// Appended to every topic, "/#" for wildcard subscriptions
+    suffix: &'static str,
+}
+
+#[allow(dead_code)]
+impl TopicList {
+    fn new(generator: TopicGenerator, range: Range<usize>, suffix: &'static str) -> Self {
+        TopicList { generator: Arc::new(generator), range, suffix }
+    }
+
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    /// Topic `index`, panics past the end like a slice
+    pub fn get(&self, index: usize) -> String {
+        assert!(index < self.len(), "topic {} of {}", index, self.len());
+        let mut topic = self.generator.topic(self.range.start + index);
+        topic.push_str(self.suffix);
+        topic
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+}
+
+impl From<Vec<String>> for TopicList {
+    fn from(topics: Vec<String>) -> Self {
+        let range = 0..topics.len();
+        TopicList::new(TopicGenerator::build(String::new(), Tree::List(topics), None), range, "")
+    }
+}
+
+/// SplitMix64 step, a stable hash that does not change between Rust versions
+pub fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Whether `topic` matches the MQTT subscription `filter` (with `+` and `#` wildcards)
+#[allow(dead_code)]
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            (_, None) => return false,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
 }
 
 #[cfg(test)]
@@ -159,4 +511,143 @@ mod tests {
         assert_eq!(topics.len(), 1);
         assert_eq!(topics[0], "test00001/#");
     }
+
+    #[test]
+    fn test_single_wildcard_of_lists() {
+        let lines = ["plant/a/temp", "plant/b/temp", "site/x", "lone"].map(str::to_string).to_vec();
+        let gen = TopicGenerator::build(String::new(), Tree::List(lines.clone()), None);
+        let filters = gen.generate_single_wildcard();
+        assert_eq!(filters, vec!["lone/#", "plant/#", "site/#"]);
+        assert!(lines.iter().all(|topic| filters.iter().any(|filter| topic_matches(filter, topic))));
+        assert_eq!(gen.into_single_wildcard().iter().collect::<Vec<_>>(), filters);
+
+        let gen = TopicGenerator::new("test".to_string(), 1, 2, 2);
+        let filters = gen.generate_single_wildcard();
+        assert_eq!(gen.into_single_wildcard().iter().collect::<Vec<_>>(), filters);
+    }
+
+    #[test]
+    fn test_num_topics_cap() {
+        let config = Config { topics_per_node: 10, max_depth: 3, num_topics: 25, ..Config::default() };
+        let gen = TopicGenerator::from_config(&config, 2).unwrap();
+        let topics = gen.generate_all();
+        assert_eq!(topics.len(), 25);
+        // Breadth first: the root and its 10 children come first
+        assert_eq!(topics[0], "test00002");
+        assert_eq!(topics[11], "test00002/01/01");
+        assert_eq!(gen.generate_leaves_only().len(), 25);
+
+        let config = Config { num_topics: 0, ..config };
+        assert_eq!(TopicGenerator::from_config(&config, 1).unwrap().generate_all().len(), 1111);
+    }
+
+    #[test]
+    fn test_levels_shape() {
+        let config = Config { topic_shape: TopicShape::Levels { fan_out: vec![2, 3] }, num_topics: 0, ..Config::default() };
+        let gen = TopicGenerator::from_config(&config, 1).unwrap();
+        assert_eq!(gen.generate_all().len(), 1 + 2 + 6);
+        assert_eq!(gen.generate_leaves_only().last().unwrap(), "test00001/02/03");
+        assert_eq!(gen.generate_wildcard_subscriptions(), vec!["test00001/01/#", "test00001/02/#"]);
+    }
+
+    #[test]
+    fn test_isa95_shape() {
+        let shape = Isa95Shape { sites: 1, areas: 1, lines: 2, cells: 1, devices: 7, metrics: 13 };
+        let config = Config { topic_shape: TopicShape::Isa95(shape), num_topics: 0, ..Config::default() };
+        let leaves = TopicGenerator::from_config(&config, 1).unwrap().generate_leaves_only();
+        assert_eq!(leaves.len(), 2 * 7 * 13);
+        assert_eq!(leaves[0], "test00001/site01/area01/line01/cell01/robot01/temperature");
+        assert!(leaves.contains(&"test00001/site01/area01/line02/cell01/robot02/temperature2".to_string()));
+    }
+
+    #[test]
+    fn test_topic_list_is_lazy() {
+        // 11 million topics, none of them built up front
+        let topics = TopicGenerator::new("test".to_string(), 3, 10, 7).into_topics(false);
+        assert_eq!(topics.len(), 11_111_111);
+        assert_eq!(topics.get(0), "test00003");
+        assert_eq!(topics.get(10), "test00003/10");
+        assert_eq!(topics.get(11), "test00003/01/01");
+        assert_eq!(topics.get(topics.len() - 1), "test00003/10/10/10/10/10/10/10");
+
+        let leaves = TopicGenerator::new("test".to_string(), 3, 10, 7).into_topics(true);
+        assert_eq!(leaves.len(), 10_000_000);
+        assert_eq!(leaves.get(1_234_567), "test00003/02/03/04/05/06/07/08");
+
+        let wildcards = TopicGenerator::new("test".to_string(), 3, 10, 7).into_wildcard_subscriptions();
+        assert_eq!(wildcards.len(), 1_000_000);
+        assert_eq!(wildcards.get(0), "test00003/01/01/01/01/01/01/#");
+    }
+
+    #[test]
+    fn test_random_topics_by_index() {
+        let shape = RandomShape { depth: 3, max_fan_out: 6, skew: 1.5, seed: 3 };
+        let config = Config { topic_shape: TopicShape::Random(shape), num_topics: 0, ..Config::default() };
+        let topics: Vec<String> = TopicGenerator::from_config(&config, 1).unwrap().into_topics(false).iter().collect();
+        // Breadth first, every parent comes before its children and child numbers count up from 01
+        for (index, topic) in topics.iter().enumerate().skip(1) {
+            let (parent, child) = topic.rsplit_once('/').unwrap();
+            assert!(topics[..index].contains(&parent.to_string()), "{}", topic);
+            if child != "01" {
+                let previous = format!("{}/{:02}", parent, child.parse::<usize>().unwrap() - 1);
+                assert_eq!(topics[index - 1], previous);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_shape() {
+        let shape = RandomShape { depth: 3, max_fan_out: 10, skew: 2.0, seed: 7 };
+        let config = Config { topic_shape: TopicShape::Random(shape), num_topics: 0, ..Config::default() };
+        let topics = TopicGenerator::from_config(&config, 1).unwrap().generate_all();
+        // The same seed gives the same tree, another producer gets another one
+        assert_eq!(topics, TopicGenerator::from_config(&config, 1).unwrap().generate_all());
+        assert_ne!(topics.len(), TopicGenerator::from_config(&config, 2).unwrap().generate_all().len());
+        assert!(topics.len() > 3 && topics.len() < 1 + 10 + 100 + 1000);
+    }
+
+    #[test]
+    fn test_file_shape() {
+        let path = std::env::temp_dir().join(format!("mqtt-test-topics-{}.txt", std::process::id()));
+        fs::write(&path, "a/1\na/2\n\nb/1\nb/2\nc\n").unwrap();
+        let config = Config {
+            topic_shape: TopicShape::File { path: path.display().to_string() },
+            num_producers: 2,
+            num_topics: 0,
+            ..Config::default()
+        };
+        let first = TopicGenerator::from_config(&config, 1).unwrap();
+        assert!(!first.is_tree());
+        assert_eq!(first.generate_all(), vec!["a/1", "b/1", "c"]);
+        assert_eq!(TopicGenerator::from_config(&config, 2).unwrap().generate_leaves_only(), vec!["a/2", "b/2"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_topic_shape() {
+        assert_eq!(TopicShape::parse("uniform"), Ok(TopicShape::Uniform));
+        assert_eq!(TopicShape::parse("levels:4,50,10"), Ok(TopicShape::Levels { fan_out: vec![4, 50, 10] }));
+        assert_eq!(TopicShape::parse("isa95"), Ok(TopicShape::Isa95(Isa95Shape::default())));
+        assert!(TopicShape::parse("isa95:1,2,3").is_err());
+        assert_eq!(
+            TopicShape::parse("random:42"),
+            Ok(TopicShape::Random(RandomShape { seed: 42, ..RandomShape::default() }))
+        );
+        assert_eq!(TopicShape::parse("file:topics.txt"), Ok(TopicShape::File { path: "topics.txt".to_string() }));
+        assert!(TopicShape::parse("file:").is_err());
+        assert!(TopicShape::parse("star").is_err());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("a/+/b/#", "a/x/b/c/d"));
+        assert!(topic_matches("a/+/b/#", "a/x/b"));
+        assert!(!topic_matches("a/+/b/#", "a/x/c/d"));
+        assert!(topic_matches("a/+", "a/x"));
+        assert!(!topic_matches("a/+", "a/x/y"));
+        assert!(!topic_matches("a/+", "a"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+    }
 }
@@ -1,3 +1,4 @@
+use crate::benchmark::{DeliveryReport, SharedReport};
 use crate::config::Config;
 use crate::metrics::GlobalMetrics;
 use crossterm::event::{self, Event, KeyCode};
@@ -49,11 +50,13 @@ impl LogBuffer {
     }
 }
 
+#[allow(dead_code)]
 pub enum UIState {
     ConfigInput,
     Running,
 }
 
+#[allow(dead_code)]
 pub struct UIContext {
     pub state: UIState,
     pub config: Config,
@@ -62,6 +65,7 @@ pub struct UIContext {
     pub in_edit_mode: bool,
 }
 
+#[allow(dead_code)]
 impl UIContext {
     pub fn new() -> Self {
         UIContext {
@@ -118,7 +122,7 @@ impl UIContext {
             }
             5 => {
                 if let Ok(n) = self.input_buffer.parse::<usize>() {
-                    self.config.max_depth = n.max(1).min(10);
+                    self.config.max_depth = n.clamp(1, 10);
                 }
             }
             6 => {
@@ -128,7 +132,7 @@ impl UIContext {
             }
             7 => {
                 if let Ok(q) = self.input_buffer.parse::<i32>() {
-                    self.config.qos = q.max(0).min(2);
+                    self.config.qos = q.clamp(0, 2);
                 }
             }
             8 => {
@@ -138,7 +142,7 @@ impl UIContext {
             9 => self.config.topic_prefix = self.input_buffer.clone(),
             10 => {
                 if let Ok(p) = self.input_buffer.parse::<u8>() {
-                    self.config.subscribe_percentage = p.max(0).min(100);
+                    self.config.subscribe_percentage = p.min(100);
                 }
             }
             11 => {
@@ -156,6 +160,7 @@ impl UIContext {
     }
 }
 
+#[allow(dead_code)]
 pub fn draw_config_screen(f: &mut Frame, ui: &UIContext) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -252,10 +257,15 @@ pub fn draw_config_screen(f: &mut Frame, ui: &UIContext) {
     f.render_widget(footer, chunks[2]);
 }
 
+/// Metrics of publishers and subscribers running in one process, one line per
+/// client number with both sides
 #[allow(dead_code)]
 pub fn draw_metrics_screen(
     f: &mut Frame,
-    metrics: &GlobalMetrics,
+    publishers: &GlobalMetrics,
+    subscribers: &GlobalMetrics,
+    delivery: &DeliveryReport,
+    shared: Option<&SharedReport>,
     uptime: Duration,
     log_buffer: &LogBuffer,
 ) {
@@ -264,14 +274,11 @@ pub fn draw_metrics_screen(
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
-        .constraints([Constraint::Length(8), Constraint::Min(5), Constraint::Length(6)])
+        .constraints([Constraint::Length(13), Constraint::Min(5), Constraint::Length(6)])
         .split(total_area);
 
     // Global metrics
-    let total_vps = metrics.get_total_vps();
-    let total_received_vps = metrics.get_total_received_vps();
-    let total_published = metrics.get_total_published();
-    let total_received = metrics.get_total_received();
+    let latency = subscribers.get_latency();
     let uptime_secs = uptime.as_secs();
     let uptime_str = format!(
         "{}:{:02}:{:02}",
@@ -285,10 +292,19 @@ pub fn draw_metrics_screen(
          ═════════════════════════════════════════════════════════════\n\
          Total Published: {} | Total Received: {}\n\
          Pub v/s: {:.2}  |  Recv v/s: {:.2}\n\
-         Uptime: {}  |  Active Clients: {}\n\
+         {}\n\
+         Latency: {}\n\
+         Lost: {}  |  Duplicates: {}  |  Out of order: {}\n\
+         {}\n\
+         Uptime: {}  |  Connected: {}/{} publishers, {}/{} subscribers\n\
          ═════════════════════════════════════════════════════════════\n\
          Press Q to STOP the test",
-        total_published, total_received, total_vps, total_received_vps, uptime_str, metrics.clients.len(),
+        delivery.published, delivery.received, publishers.get_total_vps(), subscribers.get_total_received_vps(),
+        delivery.summary_line(), latency.format_ms(),
+        subscribers.get_total_lost(), subscribers.get_total_duplicates(), subscribers.get_total_out_of_order(),
+        shared.map_or("Subscriptions: one topic tree per subscriber".to_string(), |shared| shared.summary_line()),
+        uptime_str, publishers.get_connected_count(), publishers.clients.len(),
+        subscribers.get_connected_count(), subscribers.clients.len(),
     );
 
     let global_widget = Paragraph::new(global_info)
@@ -296,18 +312,32 @@ pub fn draw_metrics_screen(
         .style(Style::default().fg(Color::Green).add_modifier(Modifier::BOLD));
     f.render_widget(global_widget, chunks[0]);
 
-    // Per-client metrics
-    let per_client_metrics: Vec<String> = metrics
-        .clients
-        .iter()
-        .map(|c| {
+    // Per-client metrics, one side has fewer clients with fan-out and fan-in
+    let per_client_metrics: Vec<String> = (0..publishers.clients.len().max(subscribers.clients.len()))
+        .map(|index| {
+            let publishing = match publishers.clients.get(index) {
+                Some(p) => format!("Pub={:8} (Pub v/s={:7.2})", p.get_total_published(), p.calculate_vps()),
+                None => format!("{:28}", ""),
+            };
+            let Some(s) = subscribers.clients.get(index) else {
+                return format!("Client {:3}: {}", index + 1, publishing);
+            };
+            let latency = s.get_latency();
+            let member = match shared.and_then(|shared| shared.member(s.id + 1)) {
+                Some((group, member)) => format!(" | {} share={:.1}%", group.name, member.share_pct),
+                None => String::new(),
+            };
             format!(
-                "Client {:3}: Pub={:8} (Pub v/s={:7.2}) | Rec={:8} (Recv v/s={:7.2})",
-                c.id + 1,
-                c.get_total_published(),
-                c.calculate_vps(),
-                c.get_total_received(),
-                c.calculate_received_vps()
+                "Client {:3}: {} | Rec={:8} (Recv v/s={:7.2}) | p50={:7.2}ms p99={:7.2}ms | Lost={} Dup={}{}",
+                index + 1,
+                publishing,
+                s.get_total_received(),
+                s.calculate_received_vps(),
+                latency.p50 as f64 / 1000.0,
+                latency.p99 as f64 / 1000.0,
+                s.get_lost(),
+                s.get_duplicates(),
+                member
             )
         })
         .collect();
@@ -335,6 +365,7 @@ pub fn draw_metrics_screen(
     f.render_widget(log_list, chunks[2]);
 }
 
+#[allow(dead_code)]
 pub async fn handle_ui_input(ui: &mut UIContext) -> Option<bool> {
     if event::poll(Duration::from_millis(50)).ok()? {
         if let Event::Key(key) = event::read().ok()? {
@@ -356,16 +387,8 @@ pub async fn handle_ui_input(ui: &mut UIContext) -> Option<bool> {
                         ui.input_buffer.push(c);
                     }
                 }
-                KeyCode::Up => {
-                    if !ui.in_edit_mode {
-                        ui.prev_field();
-                    }
-                }
-                KeyCode::Down => {
-                    if !ui.in_edit_mode {
-                        ui.next_field();
-                    }
-                }
+                KeyCode::Up if !ui.in_edit_mode => ui.prev_field(),
+                KeyCode::Down if !ui.in_edit_mode => ui.next_field(),
                 KeyCode::Enter => {
                     if matches!(ui.state, UIState::ConfigInput) {
                         if ui.in_edit_mode {
@@ -391,10 +414,8 @@ pub async fn handle_ui_input(ui: &mut UIContext) -> Option<bool> {
                         }
                     }
                 }
-                KeyCode::Backspace => {
-                    if matches!(ui.state, UIState::ConfigInput) && ui.in_edit_mode {
-                        ui.input_buffer.pop();
-                    }
+                KeyCode::Backspace if matches!(ui.state, UIState::ConfigInput) && ui.in_edit_mode => {
+                    ui.input_buffer.pop();
                 }
                 _ => {}
             }